@@ -47,27 +47,27 @@ pub fn encode(src: &[u8]) -> Vec<u8> {
 
         dst.push(ALPHABET[albet_i_0 >> 3] as u8);
         dst.push(ALPHABET[(albet_i_0 & 0x7) << 2 | albet_i_1 >> 6] as u8);
-        dst.push(match b'\0' != cpy[cpy_i_1] && cpy_i_1 != src_len {
+        dst.push(match cpy_i_1 < src_len {
             true => ALPHABET[(albet_i_1 & 0x3e) >> 1] as u8,
             _ => b'=',
         });
-        dst.push(match b'\0' != cpy[cpy_i_1] && cpy_i_1 != src_len {
+        dst.push(match cpy_i_1 < src_len {
             true => ALPHABET[(albet_i_1 & 0x1) << 4 | (albet_i_2 & 0xf0) >> 4] as u8,
             _ => b'=',
         });
-        dst.push(match b'\0' != cpy[cpy_i_2] && cpy_i_2 != src_len + 1 {
+        dst.push(match cpy_i_2 < src_len {
             true => ALPHABET[(albet_i_2 & 0xf) << 1 | (albet_i_3 & 0x80) >> 7] as u8,
             _ => b'=',
         });
-        dst.push(match b'\0' != cpy[cpy_i_3] && cpy_i_3 != src_len + 2 {
+        dst.push(match cpy_i_3 < src_len {
             true => ALPHABET[(albet_i_3 & 0x7c) >> 2] as u8,
             _ => b'=',
         });
-        dst.push(match b'\0' != cpy[cpy_i_3] && cpy_i_3 != src_len + 2 {
+        dst.push(match cpy_i_3 < src_len {
             true => ALPHABET[(albet_i_3 & 0x3) << 3 | (albet_i_4 & 0xe0) >> 5] as u8,
             _ => b'=',
         });
-        dst.push(match b'\0' != cpy[cpy_i_4] && cpy_i_4 != src_len + 3 {
+        dst.push(match cpy_i_4 < src_len {
             true => ALPHABET[albet_i_4 & 0x1f] as u8,
             _ => b'=',
         });
@@ -24,6 +24,15 @@ fn test_base32_encode() {
 
     let ret5 = base32::encode("ABcd".as_bytes());
     assert_eq!(String::from_utf8_lossy(&ret5), "IFBGGZA=");
+
+    // Zero bytes are data, not padding
+    let ret6 = base32::encode(&[0x41, 0x00, 0x00]);
+    assert_eq!(String::from_utf8_lossy(&ret6), "IEAAA===");
+    assert_eq!(base32::decode(&ret6), [0x41, 0x00, 0x00]);
+
+    let ret7 = base32::encode(&[0x00, 0x00, 0x00, 0x00, 0x00, 0x00]);
+    assert_eq!(String::from_utf8_lossy(&ret7), "AAAAAAAAAA======");
+    assert_eq!(base32::decode(&ret7), [0x00; 6]);
 }
 
 #[test]
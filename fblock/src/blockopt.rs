@@ -1,81 +1,121 @@
-use crate::hash;
-use crate::splinfo::{SplitInfo, SPLIT_INFO_JSON_PATH};
+use crate::codec::Codec;
+use crate::splinfo::block_file_path;
 
 use std::{
-    fs::{File, OpenOptions},
-    io::{Read, Result, Seek, SeekFrom, Write},
-    path::Path,
+    fs::OpenOptions,
+    io::{Error, ErrorKind, Result, Write},
+    sync::atomic::{AtomicUsize, Ordering},
 };
 
-#[inline]
-pub(crate) fn write_block(
-    file_path: &Path,
-    part_bytes: &Vec<u8>,
-    split_info: &mut SplitInfo,
-) -> Result<()> {
-    let block_sha512sum = hash::compute(&*part_bytes);
-    #[cfg(debug_assertions)]
-    dbg!(&block_sha512sum);
-    let block_file_path = file_path.parent().unwrap().join(block_sha512sum);
-    let mut block_file = OpenOptions::new()
-        .create(true)
-        .truncate(true)
-        .read(true)
-        .write(true)
-        .open(&block_file_path)?;
-    block_file.write_all(&*part_bytes)?;
-    block_file.flush()?;
-    let mut block_file_path_string = (&block_file_path.to_string_lossy()).to_string();
-    #[cfg(debug_assertions)]
-    dbg!(&block_file_path_string);
-    let split_info_dir_path_string =
-        (*SPLIT_INFO_JSON_PATH).parent().unwrap().to_string_lossy().to_string();
-    #[cfg(windows)]
-    let split_info_dir_path_string = split_info_dir_path_string.replace("\\", "/");
-    #[cfg(debug_assertions)]
-    dbg!(&split_info_dir_path_string);
-    #[cfg(windows)]
-    #[allow(unused_mut)]
-    let mut block_file_path_string = block_file_path_string.replace("\\", "/");
-    block_file_path_string =
-        block_file_path_string.replace(&format!("{}{}", split_info_dir_path_string, "/"), "");
+/// Numbers the temporary files of encoded blocks, two workers may store the same block
+static TMP_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+/// Names the parts of a file in order, with numbers wide enough for them to sort by name
+pub(crate) struct Sequence {
+    file_name: String,
+    width: usize,
+}
+
+impl Sequence {
+    /// For at most `max_count` parts
+    pub(crate) fn new(file_name: &str, max_count: u64) -> Self {
+        Sequence { file_name: file_name.to_string(), width: max_count.to_string().len().max(3) }
+    }
+
+    pub(crate) fn part_name(&self, index: usize) -> String {
+        format!("{}.part{:0width$}", self.file_name, index + 1, width = self.width)
+    }
+}
+
+/// Name `part_bytes` after their hash and store them under that name, or under `block_path`,
+/// unless `stored` is already that path or the store already holds that block.
+/// Returns the path and the name.
+pub(crate) fn store_block(
+    codec: &Codec,
+    part_bytes: &[u8],
+    block_path: Option<String>,
+    stored: Option<&str>,
+) -> Result<(String, String)> {
+    let block_name = codec.block_name(part_bytes);
     #[cfg(debug_assertions)]
-    dbg!(&block_file_path_string);
-    split_info.block_paths.push(Box::leak(block_file_path_string.into_boxed_str()));
-    Ok(())
+    dbg!(&block_name);
+    let sequential = block_path.is_some();
+    let block_path = block_path.unwrap_or_else(|| block_name.clone());
+    if stored == Some(block_path.as_str()) {
+        return Ok((block_path, block_name));
+    }
+    let block_file_path = block_file_path(&block_path);
+    // Blocks are named after their content, one already in the store needs no writing
+    let stored = match block_file_path.metadata() {
+        Ok(_) if sequential => false,
+        Ok(metadata) => !codec.is_identity() || metadata.len() == part_bytes.len() as u64,
+        Err(_) => false,
+    };
+    if stored {
+        return Ok((block_path, block_name));
+    }
+    if codec.is_identity() && !sequential {
+        let mut block_file = OpenOptions::new()
+            .create(true)
+            .truncate(true)
+            .read(true)
+            .write(true)
+            .open(&block_file_path)?;
+        block_file.write_all(part_bytes)?;
+        block_file.flush()?;
+    } else {
+        // Encoded blocks have no telling length, and parts in sequence no telling name,
+        // so they only ever appear whole
+        let tmp_path = block_file_path.with_file_name(format!(
+            "{}.{}.tmp",
+            block_path,
+            TMP_COUNTER.fetch_add(1, Ordering::Relaxed)
+        ));
+        let mut tmp_file =
+            OpenOptions::new().create(true).truncate(true).write(true).open(&tmp_path)?;
+        tmp_file.write_all(&codec.encode(part_bytes)?)?;
+        tmp_file.flush()?;
+        std::fs::rename(&tmp_path, &block_file_path)?;
+    }
+    Ok((block_path, block_name))
 }
 
-pub(crate) fn block_stream(
-    file_path: &Path,
-    seek: &mut u64,
-    block_size: &u64,
-    split_info: &mut SplitInfo,
-) -> Result<()> {
-    let mut file = File::open(&file_path)?;
-    let mut part_bytes = vec![0u8; *block_size as usize];
-    file.seek(SeekFrom::Start(*seek))?;
-    file.read_exact(&mut part_bytes)?;
-    write_block(file_path, &part_bytes, split_info)?;
-    *seek += block_size;
-    Ok(())
+/// The plaintext of a stored block, whose content has to give it `block_name` and be `length`
+/// bytes long
+pub(crate) fn read_block(
+    codec: &Codec,
+    block_path: &str,
+    block_name: &str,
+    length: u64,
+) -> Result<Vec<u8>> {
+    let part_bytes = codec.decode(&std::fs::read(block_file_path(block_path))?, length)?;
+    if codec.block_name(&part_bytes) != block_name {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            format!("Block {} does not match its name", block_path),
+        ));
+    }
+    Ok(part_bytes)
 }
 
-pub(crate) fn end_block_stream(
-    file_path: &Path,
-    seek: &mut u64,
-    split_info: &mut SplitInfo,
-) -> Result<()> {
-    let mut file = File::open(&file_path)?;
-    let mut part_bytes = Vec::<u8>::new();
-    file.seek(SeekFrom::Start(*seek))?;
-    file.read_to_end(&mut part_bytes)?;
-    write_block(file_path, &part_bytes, split_info)?;
-    *seek += part_bytes.len() as u64;
-    Ok(())
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum BlockState {
+    Intact,
+    Missing,
+    Corrupt,
 }
 
-// pub(crate) fn blocks_to_file() -> Result<()> {
-//     let Commands::Split { file_path, block_size, back_from } = &*ARGS_CMD;
-//     let sijp = split_info_json_path_from(PathBuf::from(&back_from));
-//     Ok(())
-// }
+/// Blocks are named after the hash of their content, so recompute it and compare
+pub(crate) fn check_block(
+    codec: &Codec,
+    block_path: &str,
+    block_name: &str,
+    length: u64,
+) -> Result<BlockState> {
+    match read_block(codec, block_path, block_name, length) {
+        Ok(_) => Ok(BlockState::Intact),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(BlockState::Missing),
+        Err(e) if e.kind() == ErrorKind::InvalidData => Ok(BlockState::Corrupt),
+        Err(e) => Err(e),
+    }
+}
@@ -0,0 +1,182 @@
+use std::io::{Error, ErrorKind, Result};
+
+use clap::ArgEnum;
+use serde::{Deserialize, Serialize};
+
+#[derive(ArgEnum, Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[derive(Default)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum Chunking {
+    /// Blocks of exactly `--block-size` bytes
+    #[default]
+    Fixed,
+    /// Content-defined blocks averaging `--block-size` bytes (FastCDC)
+    Cdc,
+    /// `--parts` blocks, the first ones a byte larger when the file size does not divide
+    #[clap(skip)]
+    Parts,
+}
+
+/// Random values for the Gear rolling hash, generated with SplitMix64
+/// so that every build cuts the same content at the same places
+const GEAR: [u64; 256] = {
+    let mut gear = [0u64; 256];
+    let mut state = 0x2545_f491_4f6c_dd1du64;
+    let mut i = 0;
+    while i < 256 {
+        state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        gear[i] = z ^ (z >> 31);
+        i += 1;
+    }
+    gear
+};
+
+/// A mask of the `bits` highest bits, those the Gear hash mixes the most bytes into
+const fn high_bits_mask(bits: u32) -> u64 {
+    if bits == 0 {
+        0
+    } else {
+        !0u64 << (64 - bits)
+    }
+}
+
+/// FastCDC with normalized chunking: cut points are harder to find before
+/// the average size and easier after it, so chunk sizes cluster around it
+#[derive(Debug, Clone)]
+pub(crate) struct Chunker {
+    pub(crate) min_size: usize,
+    pub(crate) avg_size: usize,
+    pub(crate) max_size: usize,
+    mask_s: u64,
+    mask_l: u64,
+}
+
+impl Chunker {
+    pub(crate) fn new(min_size: u64, avg_size: u64, max_size: u64) -> Result<Self> {
+        if min_size == 0 || min_size > avg_size || avg_size > max_size {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!(
+                    "Expected 0 < min ({}) <= average ({}) <= max ({}) block size",
+                    min_size, avg_size, max_size
+                ),
+            ));
+        }
+        let bits = 63 - avg_size.leading_zeros();
+        Ok(Chunker {
+            min_size: min_size as usize,
+            avg_size: avg_size as usize,
+            max_size: max_size as usize,
+            mask_s: high_bits_mask((bits + 1).min(64)),
+            mask_l: high_bits_mask(bits.saturating_sub(1)),
+        })
+    }
+
+    /// Min and max default to a quarter and four times the average
+    pub(crate) fn with_avg_size(
+        avg_size: u64,
+        min_size: Option<u64>,
+        max_size: Option<u64>,
+    ) -> Result<Self> {
+        Self::new(
+            min_size.unwrap_or((avg_size / 4).max(1)),
+            avg_size,
+            max_size.unwrap_or(avg_size.saturating_mul(4)),
+        )
+    }
+
+    /// Length of the chunk starting at `bytes[0]`, `bytes` should hold `max_size`
+    /// bytes unless it is the end of the file
+    pub(crate) fn cut(&self, bytes: &[u8]) -> usize {
+        let mut len = bytes.len();
+        if len <= self.min_size {
+            return len;
+        }
+        if len > self.max_size {
+            len = self.max_size;
+        }
+        let normal_size = self.avg_size.min(len);
+
+        let mut hash = 0u64;
+        let mut i = self.min_size;
+        while i < normal_size {
+            hash = (hash << 1).wrapping_add(GEAR[bytes[i] as usize]);
+            if hash & self.mask_s == 0 {
+                return i + 1;
+            }
+            i += 1;
+        }
+        while i < len {
+            hash = (hash << 1).wrapping_add(GEAR[bytes[i] as usize]);
+            if hash & self.mask_l == 0 {
+                return i + 1;
+            }
+            i += 1;
+        }
+        len
+    }
+}
+
+#[cfg(test)]
+fn test_bytes(len: usize) -> Vec<u8> {
+    let mut state = 1u32;
+    (0..len)
+        .map(|_| {
+            state ^= state << 13;
+            state ^= state >> 17;
+            state ^= state << 5;
+            state as u8
+        })
+        .collect()
+}
+
+#[cfg(test)]
+fn chunk_lens(chunker: &Chunker, bytes: &[u8]) -> Vec<usize> {
+    let mut lens = Vec::<usize>::new();
+    let mut seek = 0;
+    while seek < bytes.len() {
+        let end = (seek + chunker.max_size).min(bytes.len());
+        let len = chunker.cut(&bytes[seek..end]);
+        lens.push(len);
+        seek += len;
+    }
+    lens
+}
+
+#[test]
+fn test_cut_respects_bounds() {
+    let chunker = Chunker::with_avg_size(4096, None, None).unwrap();
+    let bytes = test_bytes(1 << 20);
+    let lens = chunk_lens(&chunker, &bytes);
+    assert_eq!(lens.iter().sum::<usize>(), bytes.len());
+    for len in &lens[..lens.len() - 1] {
+        assert!(*len >= 1024 && *len <= 16384);
+    }
+    let avg = bytes.len() / lens.len();
+    assert!(avg > 2048 && avg < 8192, "average chunk size {}", avg);
+}
+
+#[test]
+fn test_cut_survives_insertion() {
+    let chunker = Chunker::with_avg_size(4096, None, None).unwrap();
+    let bytes = test_bytes(1 << 18);
+    let mut edited = bytes[..100].to_vec();
+    edited.push(0x42);
+    edited.extend_from_slice(&bytes[100..]);
+
+    let lens = chunk_lens(&chunker, &bytes);
+    let edited_lens = chunk_lens(&chunker, &edited);
+    // Only the first chunk changes, every later boundary is found again
+    assert_eq!(edited_lens[0], lens[0] + 1);
+    assert_eq!(edited_lens[1..], lens[1..]);
+}
+
+#[test]
+fn test_chunker_rejects_invalid_sizes() {
+    assert!(Chunker::new(0, 4096, 16384).is_err());
+    assert!(Chunker::new(8192, 4096, 16384).is_err());
+    assert!(Chunker::new(1024, 4096, 2048).is_err());
+}
@@ -1,3 +1,9 @@
+use crate::chunker::Chunking;
+use crate::codec::{Cipher, Compression, Naming};
+use crate::hash::HashAlgorithm;
+
+use std::io::{Error, ErrorKind, Result};
+
 use clap::{Parser, Subcommand};
 use lazy_static::lazy_static;
 
@@ -24,17 +30,198 @@ pub(crate) struct Args {
 #[derive(Subcommand, Debug)]
 #[derive(Clone)]
 pub(crate) enum Commands {
-    /// Divide the file into equal chunks
+    /// Divide the file into equal chunks, or snapshot every file of a directory tree
     Split {
-        /// The path of the file to be split
+        /// The path of the file to be split, or of the directory whose files are split and kept
         #[clap(short, long)]
         file_path: String,
 
-        /// The size of each block after splitting
-        #[clap(long, default_value_t = 3145728)]
+        /// JSON configuration file path for block description information, or its folder,
+        /// where blocks are written too, next to the file by default
+        #[clap(long)]
+        to: Option<String>,
+
+        /// The size of each block after splitting, the average one with content-defined chunking,
+        /// in bytes or like 3MiB or 700M
+        #[clap(long, default_value = "3MiB", parse(try_from_str = parse_size))]
         block_size: u64,
-        // /// JSON configuration file path for block description information
-        // #[clap(long)]
-        // back_from: String,
+
+        /// Split into exactly this many parts of nearly the same size instead
+        #[clap(long, conflicts_with_all = &["block-size", "max-part-size", "chunking"])]
+        parts: Option<u64>,
+
+        /// Use the largest blocks whose stored parts, compressed or encrypted,
+        /// are never larger than this, like 25MB
+        #[clap(long, conflicts_with_all = &["block-size", "chunking"], parse(try_from_str = parse_size))]
+        max_part_size: Option<u64>,
+
+        /// How block boundaries are chosen
+        #[clap(long, arg_enum, default_value = "fixed")]
+        chunking: Chunking,
+
+        /// The smallest content-defined block, a quarter of the block size by default
+        #[clap(long, parse(try_from_str = parse_size))]
+        min_block_size: Option<u64>,
+
+        /// The largest content-defined block, four times the block size by default
+        #[clap(long, parse(try_from_str = parse_size))]
+        max_block_size: Option<u64>,
+
+        /// How blocks are named, sequential parts are plain and can be joined with cat
+        #[clap(long, arg_enum, default_value = "content-hash")]
+        naming: Naming,
+
+        /// How blocks are named and the file is checked on restore. Blocks used to be named
+        /// after their CityHash128 in base64, which no choice reproduces, so new splits do not
+        /// share blocks with those older ones even in the same store
+        #[clap(long, arg_enum, default_value = "blake3")]
+        hash: HashAlgorithm,
+
+        /// How blocks are compressed before being written
+        #[clap(long, arg_enum, default_value = "none")]
+        compression: Compression,
+
+        /// Encrypt blocks with a key derived from a passphrase, read from FBLOCK_PASSPHRASE
+        /// or prompted for
+        #[clap(long, arg_enum)]
+        encrypt: Option<Cipher>,
+
+        /// How many blocks are hashed and written at once, the number of CPUs by default
+        #[clap(long)]
+        threads: Option<usize>,
+
+        /// Continue an interrupted split of the same file instead of starting over, trees
+        /// only ever split the files that changed since their last snapshot
+        #[clap(long)]
+        resume: bool,
+    },
+    /// Rebuild split files from their blocks
+    Restore {
+        /// JSON configuration file path for block description information, or its folder
+        #[clap(long, default_value = ".")]
+        from: String,
+
+        /// Only restore the file or tree with this name
+        #[clap(long)]
+        name: Option<String>,
+
+        /// Restore this snapshot of trees instead of their latest one
+        #[clap(long)]
+        snapshot: Option<u64>,
+    },
+    /// Forget a split file, or every snapshot of a tree, and delete the blocks nothing else uses
+    Rm {
+        /// The name of the split file or tree
+        file_name: String,
+
+        /// JSON configuration file path for block description information, or its folder
+        #[clap(long, default_value = ".")]
+        from: String,
+    },
+    /// Delete the blocks no split file uses anymore, unless a split is running, but for
+    /// encrypted ones, which cannot be told from stray files
+    Gc {
+        /// JSON configuration file path for block description information, or its folder
+        #[clap(long, default_value = ".")]
+        from: String,
     },
+    /// Show how much the block store saves through deduplication
+    Stats {
+        /// JSON configuration file path for block description information, or its folder
+        #[clap(long, default_value = ".")]
+        from: String,
+    },
+    /// Check that the blocks of split files exist and are intact
+    Verify {
+        /// JSON configuration file path for block description information, or its folder
+        #[clap(long, default_value = ".")]
+        from: String,
+
+        /// Only verify the file or tree with this name
+        #[clap(long)]
+        name: Option<String>,
+    },
+    /// Upload the blocks an HTTP block server is missing and every sequential part, then the
+    /// split info
+    Push {
+        /// The block server URL, like http://host/blocks
+        #[clap(long)]
+        to: String,
+
+        /// JSON configuration file path for block description information, or its folder
+        #[clap(long, default_value = ".")]
+        from: String,
+
+        /// Only push the file with this name
+        #[clap(long)]
+        name: Option<String>,
+    },
+    /// Download split files from an HTTP block server and restore them
+    Pull {
+        /// The block server URL, like http://host/blocks
+        #[clap(long)]
+        from: String,
+
+        /// The folder to download blocks into and restore files in
+        #[clap(long, default_value = ".")]
+        to: String,
+
+        /// Only pull the file with this name
+        #[clap(long)]
+        name: Option<String>,
+    },
+    /// Show split files as the read-only files of a folder, without restoring them, on Linux
+    Mount {
+        /// JSON configuration file path for block description information, or its folder
+        manifest: String,
+
+        /// The folder they appear in, until it is unmounted
+        mountpoint: String,
+    },
+    /// Serve a folder over HTTP with GET, HEAD and PUT, as a block server for push and pull
+    Serve {
+        /// The folder to serve
+        #[clap(long, default_value = ".")]
+        dir: String,
+
+        #[clap(long, default_value = "127.0.0.1:9998")]
+        addr: String,
+    },
+}
+
+/// Bytes, with an optional unit: K, M, G and T are powers of 1000, KiB, MiB, GiB and TiB
+/// powers of 1024, and the B is optional
+pub(crate) fn parse_size(size: &str) -> Result<u64> {
+    let invalid = || Error::new(ErrorKind::InvalidInput, format!("Invalid size {}", size));
+    let digits = size.find(|c: char| !c.is_ascii_digit()).unwrap_or(size.len());
+    let (number, unit) = size.split_at(digits);
+    let number = number.parse::<u64>().map_err(|_| invalid())?;
+    let unit = unit.trim_start().to_ascii_lowercase();
+    let unit = unit.strip_suffix('b').unwrap_or(&unit);
+    let (prefix, base) = match unit.strip_suffix('i') {
+        Some(prefix) if !prefix.is_empty() => (prefix, 1024u64),
+        _ => (unit, 1000u64),
+    };
+    let exponent = match prefix {
+        "" => 0,
+        "k" => 1,
+        "m" => 2,
+        "g" => 3,
+        "t" => 4,
+        _ => return Err(invalid()),
+    };
+    number.checked_mul(base.pow(exponent)).ok_or_else(invalid)
+}
+
+#[test]
+fn test_parse_size() {
+    assert_eq!(parse_size("3145728").unwrap(), 3145728);
+    assert_eq!(parse_size("3MiB").unwrap(), 3 * 1024 * 1024);
+    assert_eq!(parse_size("700M").unwrap(), 700_000_000);
+    assert_eq!(parse_size("25MB").unwrap(), 25_000_000);
+    assert_eq!(parse_size("4 kib").unwrap(), 4096);
+    assert_eq!(parse_size("12B").unwrap(), 12);
+    for size in ["", "MiB", "3iB", "3X", "1.5G", "-1", "99999999999T"] {
+        assert!(parse_size(size).is_err(), "{}", size);
+    }
 }
@@ -0,0 +1,414 @@
+use crate::hash::{to_hex, HashAlgorithm};
+use crate::splinfo::SplitInfo;
+
+use std::{
+    collections::HashMap,
+    io::{Error, ErrorKind, Read, Result, Write},
+};
+
+use aes_gcm::Aes256Gcm;
+use chacha20poly1305::aead::{Aead, NewAead};
+use chacha20poly1305::ChaCha20Poly1305;
+use clap::ArgEnum;
+use flate2::{read::DeflateDecoder, write::DeflateEncoder};
+use hmac::Hmac;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+/// Read by scripts instead of prompting for the passphrase
+const PASSPHRASE_ENV: &str = "FBLOCK_PASSPHRASE";
+
+const SALT_LEN: usize = 16;
+const NONCE_LEN: usize = 12;
+/// What the AEAD adds to the ciphertext
+const TAG_LEN: usize = 16;
+const KEY_LEN: usize = 32;
+/// Hex digits of the key id, the last part of encrypted block names
+const KEY_ID_LEN: usize = 16;
+
+/// PBKDF2-HMAC-SHA256 iteration count for newly encrypted splits
+const DEFAULT_PBKDF2_ROUNDS: u32 = 310_000;
+
+#[derive(ArgEnum, Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[derive(Default)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum Compression {
+    #[default]
+    None,
+    Zstd,
+    Deflate,
+}
+
+#[derive(ArgEnum, Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum Cipher {
+    /// AES-256-GCM
+    #[serde(alias = "aesGcm")]
+    AesGcm,
+    /// ChaCha20-Poly1305
+    #[serde(alias = "chacha20Poly1305")]
+    Chacha20Poly1305,
+}
+
+#[derive(ArgEnum, Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[derive(Default)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum Naming {
+    /// Named after the hash of their content, so identical blocks are stored once
+    #[default]
+    ContentHash,
+    /// `<file>.part001`, `<file>.part002` and so on, in file order
+    Sequential,
+}
+
+/// Everything but the passphrase needed to derive the key of an encrypted split
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub(crate) struct Encryption {
+    pub(crate) cipher: Cipher,
+    /// PBKDF2-HMAC-SHA256 salt, in hex
+    pub(crate) salt: String,
+    pub(crate) rounds: u32,
+    /// Tells a wrong passphrase from corrupt blocks, and keeps apart blocks
+    /// of the same content encrypted with different keys
+    #[serde(alias = "keyId")]
+    pub(crate) key_id: String,
+}
+
+/// Names blocks after the hash of their plaintext and turns it into what is stored
+///
+/// Stored blocks are the plaintext compressed, then sealed as `nonce | ciphertext`.
+/// The name is the hash followed by `.zst` or `.deflate` when compressed and by
+/// the key id when encrypted, so that differently stored copies never collide.
+pub(crate) struct Codec {
+    pub(crate) hash: HashAlgorithm,
+    compression: Compression,
+    cipher: Option<(Cipher, [u8; KEY_LEN])>,
+    suffix: String,
+}
+
+impl Codec {
+    pub(crate) fn new(
+        hash: HashAlgorithm,
+        compression: Compression,
+        cipher: Option<(Cipher, [u8; KEY_LEN])>,
+    ) -> Self {
+        let mut suffix = match compression {
+            Compression::None => String::new(),
+            Compression::Zstd => String::from(".zst"),
+            Compression::Deflate => String::from(".deflate"),
+        };
+        if let Some((_, key)) = &cipher {
+            suffix.push('.');
+            suffix.push_str(&key_id(key));
+        }
+        Codec { hash, compression, cipher, suffix }
+    }
+
+    pub(crate) fn block_name(&self, part_bytes: &[u8]) -> String {
+        self.hash.compute(part_bytes) + &self.suffix
+    }
+
+    /// Whether stored blocks are the plaintext itself
+    pub(crate) fn is_identity(&self) -> bool {
+        self.compression == Compression::None && self.cipher.is_none()
+    }
+
+    pub(crate) fn encode(&self, part_bytes: &[u8]) -> Result<Vec<u8>> {
+        let compressed = match self.compression {
+            Compression::None => part_bytes.to_vec(),
+            Compression::Zstd => zstd::encode_all(part_bytes, 0)?,
+            Compression::Deflate => {
+                let mut encoder = DeflateEncoder::new(Vec::<u8>::new(), Default::default());
+                encoder.write_all(part_bytes)?;
+                encoder.finish()?
+            }
+        };
+        let (cipher, key) = match &self.cipher {
+            Some(cipher) => cipher,
+            None => return Ok(compressed),
+        };
+        let mut nonce = [0u8; NONCE_LEN];
+        fill_random(&mut nonce)?;
+        let ciphertext = match cipher {
+            Cipher::AesGcm => {
+                Aes256Gcm::new(&(*key).into()).encrypt(&nonce.into(), compressed.as_slice())
+            }
+            Cipher::Chacha20Poly1305 => {
+                ChaCha20Poly1305::new(&(*key).into()).encrypt(&nonce.into(), compressed.as_slice())
+            }
+        }
+        .map_err(|_| Error::other("Failed to encrypt block"))?;
+        let mut sealed = nonce.to_vec();
+        sealed.extend_from_slice(&ciphertext);
+        Ok(sealed)
+    }
+
+    /// The plaintext of a block, which is refused once it grows past `max_len`, so that a
+    /// small block cannot expand without bound
+    pub(crate) fn decode(&self, stored_bytes: &[u8], max_len: u64) -> Result<Vec<u8>> {
+        let compressed = match &self.cipher {
+            Some((cipher, key)) => {
+                if stored_bytes.len() < NONCE_LEN {
+                    return Err(Error::new(ErrorKind::InvalidData, "Truncated block"));
+                }
+                let (nonce, ciphertext) = stored_bytes.split_at(NONCE_LEN);
+                let mut nonce_bytes = [0u8; NONCE_LEN];
+                nonce_bytes.copy_from_slice(nonce);
+                match cipher {
+                    Cipher::AesGcm => {
+                        Aes256Gcm::new(&(*key).into()).decrypt(&nonce_bytes.into(), ciphertext)
+                    }
+                    Cipher::Chacha20Poly1305 => ChaCha20Poly1305::new(&(*key).into())
+                        .decrypt(&nonce_bytes.into(), ciphertext),
+                }
+                .map_err(|_| Error::new(ErrorKind::InvalidData, "Block failed authentication"))?
+            }
+            None => stored_bytes.to_vec(),
+        };
+        let part_bytes = match self.compression {
+            Compression::None => compressed,
+            Compression::Zstd => {
+                let decoder = zstd::stream::read::Decoder::new(compressed.as_slice())
+                    .map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+                read_at_most(decoder, max_len)?
+            }
+            Compression::Deflate => {
+                read_at_most(DeflateDecoder::new(compressed.as_slice()), max_len)?
+            }
+        };
+        if part_bytes.len() as u64 > max_len {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("Block decodes to more than {} bytes", max_len),
+            ));
+        }
+        Ok(part_bytes)
+    }
+}
+
+/// One byte more than `max_len` when there is more, to tell
+fn read_at_most<R: Read>(decoder: R, max_len: u64) -> Result<Vec<u8>> {
+    let mut part_bytes = Vec::<u8>::new();
+    decoder
+        .take(max_len.saturating_add(1))
+        .read_to_end(&mut part_bytes)
+        .map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+    Ok(part_bytes)
+}
+
+/// The codecs of many split files, which ask for the passphrase and derive each key only once
+#[derive(Default)]
+pub(crate) struct Codecs {
+    passphrase: Option<String>,
+    /// By salt and rounds, many files of a tree share one
+    keys: HashMap<(String, u32), [u8; KEY_LEN]>,
+}
+
+impl Codecs {
+    /// The codec `split_info` was written with
+    pub(crate) fn of(&mut self, split_info: &SplitInfo) -> Result<Codec> {
+        let cipher = match &split_info.encryption {
+            Some(encryption) => Some((encryption.cipher, self.key(encryption)?)),
+            None => None,
+        };
+        Ok(Codec::new(split_info.hash, split_info.compression, cipher))
+    }
+
+    pub(crate) fn key(&mut self, encryption: &Encryption) -> Result<[u8; KEY_LEN]> {
+        let salt_rounds = (encryption.salt.clone(), encryption.rounds);
+        if let Some(key) = self.keys.get(&salt_rounds) {
+            if key_id(key) == encryption.key_id {
+                return Ok(*key);
+            }
+        }
+        if self.passphrase.is_none() {
+            self.passphrase = Some(read_passphrase(false)?);
+        }
+        let key = encryption.key(self.passphrase.as_deref().unwrap())?;
+        self.keys.insert(salt_rounds, key);
+        Ok(key)
+    }
+}
+
+impl Encryption {
+    /// Pick a fresh salt for a new split and derive its key
+    pub(crate) fn create(cipher: Cipher, passphrase: &str) -> Result<(Self, [u8; KEY_LEN])> {
+        let mut salt = [0u8; SALT_LEN];
+        fill_random(&mut salt)?;
+        let key = derive_key(passphrase, &salt, DEFAULT_PBKDF2_ROUNDS);
+        let encryption = Encryption {
+            cipher,
+            salt: to_hex(&salt),
+            rounds: DEFAULT_PBKDF2_ROUNDS,
+            key_id: key_id(&key),
+        };
+        Ok((encryption, key))
+    }
+
+    pub(crate) fn key(&self, passphrase: &str) -> Result<[u8; KEY_LEN]> {
+        let salt = from_hex(&self.salt)
+            .ok_or_else(|| Error::new(ErrorKind::InvalidData, "Invalid encryption salt"))?;
+        let key = derive_key(passphrase, &salt, self.rounds);
+        if key_id(&key) != self.key_id {
+            return Err(Error::new(ErrorKind::InvalidInput, "Wrong passphrase"));
+        }
+        Ok(key)
+    }
+}
+
+/// The largest block that is never stored in more than `stored_size` bytes, even when
+/// it does not compress at all
+pub(crate) fn max_block_size_within(
+    stored_size: u64,
+    compression: Compression,
+    encrypted: bool,
+) -> u64 {
+    let sealed_size = if encrypted {
+        stored_size.saturating_sub((NONCE_LEN + TAG_LEN) as u64)
+    } else {
+        stored_size
+    };
+    let compressed_bound = |size: u64| match compression {
+        Compression::None => size,
+        Compression::Zstd => zstd::zstd_safe::compress_bound(size as usize) as u64,
+        // Stored deflate blocks cost 5 bytes for each 64KiB, counted for every 16KiB to be safe
+        Compression::Deflate => size + 5 * (size / 16383 + 1) + 6,
+    };
+    let (mut low, mut high) = (0, sealed_size);
+    while low < high {
+        let mid = high - (high - low) / 2;
+        if compressed_bound(mid) <= sealed_size {
+            low = mid;
+        } else {
+            high = mid - 1;
+        }
+    }
+    low
+}
+
+/// Split a block name into its hash, its compression and its key id
+pub(crate) fn parse_block_name(name: &str) -> Option<(&str, Compression, Option<&str>)> {
+    let mut parts = name.split('.');
+    let stem = parts.next()?;
+    let (mut compression, mut key_id) = (Compression::None, None);
+    let mut next = parts.next();
+    match next {
+        Some("zst") => compression = Compression::Zstd,
+        Some("deflate") => compression = Compression::Deflate,
+        _ => {}
+    }
+    if compression != Compression::None {
+        next = parts.next();
+    }
+    if let Some(id) = next {
+        if id.len() != KEY_ID_LEN || !id.bytes().all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f')) {
+            return None;
+        }
+        key_id = Some(id);
+    }
+    match parts.next() {
+        Some(_) => None,
+        None => Some((stem, compression, key_id)),
+    }
+}
+
+pub(crate) fn read_passphrase(confirm: bool) -> Result<String> {
+    if let Ok(passphrase) = std::env::var(PASSPHRASE_ENV) {
+        return Ok(passphrase);
+    }
+    let passphrase = rpassword::prompt_password("Passphrase: ")?;
+    if confirm && passphrase != rpassword::prompt_password("Confirm passphrase: ")? {
+        return Err(Error::new(ErrorKind::InvalidInput, "Passphrases do not match"));
+    }
+    Ok(passphrase)
+}
+
+fn derive_key(passphrase: &str, salt: &[u8], rounds: u32) -> [u8; KEY_LEN] {
+    let mut key = [0u8; KEY_LEN];
+    pbkdf2::pbkdf2::<Hmac<Sha256>>(passphrase.as_bytes(), salt, rounds, &mut key);
+    key
+}
+
+fn key_id(key: &[u8; KEY_LEN]) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(b"fblock key id");
+    hasher.update(key);
+    to_hex(&hasher.finalize()[..KEY_ID_LEN / 2])
+}
+
+fn fill_random(dest: &mut [u8]) -> Result<()> {
+    getrandom::getrandom(dest).map_err(Error::other)
+}
+
+fn from_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..hex.len()).step_by(2).map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok()).collect()
+}
+
+#[test]
+fn test_codec_round_trip() {
+    let part_bytes = b"fblock fblock fblock fblock fblock fblock fblock".repeat(100);
+    for compression in [Compression::None, Compression::Zstd, Compression::Deflate] {
+        for cipher in [None, Some(Cipher::AesGcm), Some(Cipher::Chacha20Poly1305)] {
+            let codec =
+                Codec::new(HashAlgorithm::Sha256, compression, cipher.map(|c| (c, [7; 32])));
+            let stored_bytes = codec.encode(&part_bytes).unwrap();
+            let len = part_bytes.len() as u64;
+            assert_eq!(codec.decode(&stored_bytes, len).unwrap(), part_bytes);
+            assert!(codec.decode(&stored_bytes, len - 1).is_err());
+            if compression != Compression::None {
+                assert!(stored_bytes.len() < part_bytes.len());
+            }
+            let name = codec.block_name(&part_bytes);
+            let (stem, parsed_compression, key_id) = parse_block_name(&name).unwrap();
+            assert_eq!(stem, HashAlgorithm::Sha256.compute(&part_bytes));
+            assert_eq!(parsed_compression, compression);
+            assert_eq!(key_id.is_some(), cipher.is_some());
+        }
+    }
+}
+
+#[test]
+fn test_wrong_key_fails() {
+    let codec =
+        Codec::new(HashAlgorithm::Blake3, Compression::Zstd, Some((Cipher::AesGcm, [1; 32])));
+    let stored_bytes = codec.encode(b"secret").unwrap();
+    let other =
+        Codec::new(HashAlgorithm::Blake3, Compression::Zstd, Some((Cipher::AesGcm, [2; 32])));
+    assert!(other.decode(&stored_bytes, 6).is_err());
+    assert_ne!(codec.block_name(b"secret"), other.block_name(b"secret"));
+}
+
+#[test]
+fn test_max_block_size_within() {
+    let random = {
+        let mut random = vec![0u8; 300_000];
+        fill_random(&mut random).unwrap();
+        random
+    };
+    for compression in [Compression::None, Compression::Zstd, Compression::Deflate] {
+        for cipher in [None, Some(Cipher::AesGcm)] {
+            let max_size = max_block_size_within(200_000, compression, cipher.is_some());
+            assert!(max_size > 190_000);
+            let codec =
+                Codec::new(HashAlgorithm::Blake3, compression, cipher.map(|c| (c, [3; 32])));
+            let stored_bytes = codec.encode(&random[..max_size as usize]).unwrap();
+            assert!(stored_bytes.len() <= 200_000, "{:?} {:?}", compression, cipher);
+        }
+    }
+    assert_eq!(max_block_size_within(20, Compression::None, true), 0);
+}
+
+#[test]
+fn test_decode_stops_at_max_len() {
+    let part_bytes = vec![0u8; 64 * 1024 * 1024];
+    for compression in [Compression::Zstd, Compression::Deflate] {
+        let codec = Codec::new(HashAlgorithm::Blake3, compression, None);
+        let stored_bytes = codec.encode(&part_bytes).unwrap();
+        assert!(stored_bytes.len() < 1024 * 1024);
+        let e = codec.decode(&stored_bytes, 4096).unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::InvalidData);
+    }
+}
@@ -1,12 +1,138 @@
 use cityhash_sys::city_hash_128;
+use clap::ArgEnum;
 use rb64::encode;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 
-pub(crate) fn compute(bytes: &[u8]) -> String {
-    let city_hash_u128 = city_hash_128(bytes);
-    let hash_be_bytes = city_hash_u128.to_be_bytes();
-    let hash_le_bytes = city_hash_u128.to_le_bytes();
-    let mut hash_vec = hash_be_bytes.to_vec();
-    hash_vec.extend_from_slice(&hash_le_bytes);
-    let rb64_bytes = encode(&hash_vec, false, false, false);
-    String::from_utf8_lossy(&rb64_bytes).to_string()
+#[derive(ArgEnum, Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[derive(Default)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum HashAlgorithm {
+    /// CityHash128, fast but not collision-resistant, and only for files that fit in as many
+    /// blocks as there are threads, since it hashes a whole file in memory
+    City128,
+    /// SHA-256
+    Sha256,
+    /// BLAKE3
+    Blake3,
+    /// CityHash128 in standard base64, how blocks were named before `--hash`
+    #[default]
+    #[clap(skip)]
+    #[serde(alias = "legacyCity128")]
+    LegacyCity128,
+}
+
+impl HashAlgorithm {
+    /// The name of the block holding `bytes`, lowercase hex so that it is safe on any filesystem
+    pub(crate) fn compute(self, bytes: &[u8]) -> String {
+        match self {
+            HashAlgorithm::City128 => to_hex(&city_hash_128(bytes).to_be_bytes()),
+            HashAlgorithm::Sha256 => to_hex(&Sha256::digest(bytes)),
+            HashAlgorithm::Blake3 => to_hex(blake3::hash(bytes).as_bytes()),
+            HashAlgorithm::LegacyCity128 => {
+                let city_hash_u128 = city_hash_128(bytes);
+                let hash_be_bytes = city_hash_u128.to_be_bytes();
+                let hash_le_bytes = city_hash_u128.to_le_bytes();
+                let mut hash_vec = hash_be_bytes.to_vec();
+                hash_vec.extend_from_slice(&hash_le_bytes);
+                let rb64_bytes = encode(&hash_vec, false, false, false);
+                String::from_utf8_lossy(&rb64_bytes).to_string()
+            }
+        }
+    }
+
+    /// The algorithms that could have named a block `name`
+    pub(crate) fn candidates(name: &str) -> Vec<HashAlgorithm> {
+        let is_hex = name.bytes().all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f'));
+        match name.len() {
+            32 if is_hex => vec![HashAlgorithm::City128],
+            64 if is_hex => vec![HashAlgorithm::Sha256, HashAlgorithm::Blake3],
+            44 if name.bytes().all(|b| b.is_ascii_alphanumeric() || b"+/=".contains(&b)) => {
+                vec![HashAlgorithm::LegacyCity128]
+            }
+            _ => vec![],
+        }
+    }
+
+    /// Whether `hasher` hashes a file as it streams by, rather than keeping all of it
+    pub(crate) fn streams(self) -> bool {
+        matches!(self, HashAlgorithm::Sha256 | HashAlgorithm::Blake3)
+    }
+
+    pub(crate) fn hasher(self) -> Hasher {
+        match self {
+            HashAlgorithm::Sha256 => Hasher::Sha256(Sha256::new()),
+            HashAlgorithm::Blake3 => Hasher::Blake3(Box::new(blake3::Hasher::new())),
+            HashAlgorithm::City128 | HashAlgorithm::LegacyCity128 => {
+                Hasher::Buffered(self, Vec::<u8>::new())
+            }
+        }
+    }
+}
+
+/// Hashes a whole file as it streams by
+pub(crate) enum Hasher {
+    Sha256(Sha256),
+    Blake3(Box<blake3::Hasher>),
+    /// CityHash has no streaming form, so the content is kept until `finish`, which is why
+    /// splits only use it for small files, and restoring them holds them in memory
+    Buffered(HashAlgorithm, Vec<u8>),
+}
+
+impl Hasher {
+    pub(crate) fn update(&mut self, bytes: &[u8]) {
+        match self {
+            Hasher::Sha256(hasher) => hasher.update(bytes),
+            Hasher::Blake3(hasher) => {
+                hasher.update(bytes);
+            }
+            Hasher::Buffered(_, buffer) => buffer.extend_from_slice(bytes),
+        }
+    }
+
+    /// The same as `HashAlgorithm::compute` over everything passed to `update`
+    pub(crate) fn finish(self) -> String {
+        match self {
+            Hasher::Sha256(hasher) => to_hex(&hasher.finalize()),
+            Hasher::Blake3(hasher) => to_hex(hasher.finalize().as_bytes()),
+            Hasher::Buffered(hash, buffer) => hash.compute(&buffer),
+        }
+    }
+}
+
+pub(crate) fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+#[test]
+fn test_compute() {
+    assert_eq!(
+        HashAlgorithm::Sha256.compute(b"abc"),
+        "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+    );
+    assert_eq!(
+        HashAlgorithm::Blake3.compute(b""),
+        "af1349b9f5f9a1a6a0404dea36dcc9499bcb25c9adc112b7cc9a93cae41f3262"
+    );
+}
+
+#[test]
+fn test_candidates() {
+    let name = HashAlgorithm::Blake3.compute(b"abc");
+    assert_eq!(HashAlgorithm::candidates(&name), [HashAlgorithm::Sha256, HashAlgorithm::Blake3]);
+    assert_eq!(HashAlgorithm::candidates(&name[..32]), [HashAlgorithm::City128]);
+    assert!(HashAlgorithm::candidates(".split_info.json").is_empty());
+    assert!(HashAlgorithm::candidates(&name.to_uppercase()).is_empty());
+}
+
+#[test]
+fn test_hasher_matches_compute() {
+    let bytes: Vec<u8> = (0..100_000u32).map(|i| (i * 7 % 251) as u8).collect();
+    for hash in [HashAlgorithm::Sha256, HashAlgorithm::Blake3] {
+        let mut hasher = hash.hasher();
+        for part_bytes in bytes.chunks(4096) {
+            hasher.update(part_bytes);
+        }
+        assert_eq!(hasher.finish(), hash.compute(&bytes));
+    }
 }
@@ -1,57 +1,100 @@
 mod blockopt;
+mod chunker;
 mod cli;
+mod codec;
 mod hash;
+#[cfg(target_os = "linux")]
+mod mount;
+mod pipeline;
+mod remote;
+mod restore;
+mod serve;
 mod splinfo;
+mod split;
+mod store;
+mod verify;
 
-use crate::blockopt::*;
+use crate::chunker::{Chunker, Chunking};
 use crate::cli::{Commands, ARGS_CMD};
-use crate::splinfo::{SplitInfo, SPLIT_INFOS, SPLIT_INFO_JSON_PATH};
+use crate::codec::max_block_size_within;
+use crate::split::SplitOptions;
 
 use std::{
-    fs::OpenOptions,
-    io::{Result, Write},
+    io::{Error, ErrorKind, Result},
     path::Path,
 };
 
 fn main() -> Result<()> {
-    let Commands::Split { file_path, block_size, .. } = &*ARGS_CMD;
-    let file_path = Path::new(&file_path);
-    let file_sha512sum = hash::compute(&std::fs::read(&file_path)?);
-    #[cfg(debug_assertions)]
-    dbg!(&file_sha512sum);
-    let metadata = file_path.metadata()?;
-    #[cfg(debug_assertions)]
-    dbg!(&metadata.len());
-    let block_size = block_size;
-    let parts = metadata.len() / block_size;
-    let mut seek = 0;
-
-    let mut split_infos = SPLIT_INFOS.clone();
-    #[cfg(debug_assertions)]
-    dbg!(&split_infos);
-    let mut split_info = SplitInfo::default();
-    split_info.file_name =
-        Box::leak(file_path.file_name().unwrap().to_string_lossy().to_string().into_boxed_str());
-    split_info.file_sha512sum = Box::leak(file_sha512sum.into_boxed_str());
-    for _ in 0..parts {
-        block_stream(&file_path, &mut seek, &block_size, &mut split_info)?;
+    match &*ARGS_CMD {
+        Commands::Split {
+            file_path,
+            block_size,
+            parts,
+            max_part_size,
+            chunking,
+            min_block_size,
+            max_block_size,
+            hash,
+            compression,
+            encrypt,
+            naming,
+            threads,
+            resume,
+            ..
+        } => {
+            let block_size = match max_part_size {
+                Some(max_part_size) => {
+                    match max_block_size_within(*max_part_size, *compression, encrypt.is_some()) {
+                        0 => {
+                            return Err(Error::new(
+                                ErrorKind::InvalidInput,
+                                format!("Parts of {} bytes cannot hold any data", max_part_size),
+                            ))
+                        }
+                        block_size => block_size,
+                    }
+                }
+                None => *block_size,
+            };
+            let chunker = match chunking {
+                Chunking::Cdc => {
+                    Some(Chunker::with_avg_size(block_size, *min_block_size, *max_block_size)?)
+                }
+                Chunking::Fixed | Chunking::Parts => None,
+            };
+            let threads = threads.unwrap_or_else(|| {
+                std::thread::available_parallelism().map_or(1, |threads| threads.get())
+            });
+            let options = SplitOptions {
+                block_size,
+                parts: *parts,
+                naming: *naming,
+                hash: *hash,
+                compression: *compression,
+                cipher: *encrypt,
+                threads,
+                resume: *resume,
+            };
+            let file_path = Path::new(&file_path);
+            if file_path.is_dir() {
+                split::split_tree(file_path, chunker.as_ref(), &options)
+            } else {
+                split::split(file_path, chunker.as_ref(), &options)
+            }
+        }
+        Commands::Restore { name, snapshot, .. } => restore::restore(name.as_deref(), *snapshot),
+        Commands::Rm { file_name, .. } => store::rm(file_name),
+        Commands::Gc { .. } => store::gc(),
+        Commands::Stats { .. } => store::stats(),
+        Commands::Verify { name, .. } => verify::verify(name.as_deref()),
+        Commands::Push { to, name, .. } => remote::push(to, name.as_deref()),
+        Commands::Pull { from, name, .. } => remote::pull(from, name.as_deref()),
+        #[cfg(target_os = "linux")]
+        Commands::Mount { mountpoint, .. } => mount::mount(Path::new(mountpoint)),
+        #[cfg(not(target_os = "linux"))]
+        Commands::Mount { .. } => {
+            Err(Error::new(ErrorKind::Unsupported, "Mounting is only supported on Linux"))
+        }
+        Commands::Serve { dir, addr } => serve::serve(Path::new(dir), addr),
     }
-    end_block_stream(&file_path, &mut seek, &mut split_info)?;
-    #[cfg(debug_assertions)]
-    dbg!(parts);
-    #[cfg(debug_assertions)]
-    dbg!(seek);
-    split_infos.push(split_info);
-    split_infos.dedup_by(|a, b| a.file_sha512sum == b.file_sha512sum);
-    let mut split_info_json_file = OpenOptions::new()
-        .create(true)
-        .truncate(true)
-        .read(true)
-        .write(true)
-        .open(&*SPLIT_INFO_JSON_PATH)?;
-    split_info_json_file
-        .write_all(serde_json::to_string_pretty(&split_infos).unwrap().as_bytes())?;
-    split_info_json_file.flush()?;
-    std::fs::remove_file(file_path)?;
-    Ok(())
 }
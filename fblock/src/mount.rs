@@ -0,0 +1,284 @@
+use crate::blockopt::read_block;
+use crate::codec::{Codec, Codecs};
+use crate::splinfo::{block_file_path, Manifest, SplitInfo, SPLIT_INFO_JSON_PATH};
+
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    ffi::OsStr,
+    fs::{File, Metadata},
+    io::Result,
+    os::unix::fs::{FileExt, MetadataExt},
+    path::Path,
+    rc::Rc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use fuser::{
+    FileAttr, FileType, Filesystem, MountOption, ReplyAttr, ReplyData, ReplyDirectory, ReplyEntry,
+    Request, FUSE_ROOT_ID,
+};
+use libc::{EIO, ENOENT, ENOTDIR};
+
+/// Nothing changes while mounted, the kernel may keep what it was told
+const TTL: Duration = Duration::from_secs(3600);
+
+/// Decoded blocks kept for the reads that come in pieces smaller than a block
+const CACHED_BLOCKS: usize = 8;
+
+struct MountedFile {
+    name: String,
+    split_info: SplitInfo,
+    codec: Codec,
+}
+
+/// The split files of a manifest as read-only files of a single folder,
+/// file `index` being inode `FUSE_ROOT_ID + 1 + index`
+struct SplitFs {
+    files: Vec<MountedFile>,
+    uid: u32,
+    gid: u32,
+    /// When the manifest was written, files that did not record their mtime have it too
+    mtime: SystemTime,
+    /// Most recently used last, by inode and block index
+    cache: VecDeque<(u64, usize, Rc<Vec<u8>>)>,
+    /// The blocks already found to match their name, by inode and block index
+    verified: HashSet<(u64, usize)>,
+}
+
+impl SplitFs {
+    /// The split files of `manifest`, which was written with `metadata`
+    fn new(manifest: &Manifest, metadata: &Metadata) -> Result<Self> {
+        // Older splits of a file that was split again are there too, as `name~1` and so on
+        let mut seen = HashMap::<&str, usize>::new();
+        let mut names = Vec::<String>::new();
+        for split_info in manifest.files.iter().filter(|split_info| !split_info.pending).rev() {
+            let older = seen.entry(&split_info.file_name).or_insert(0);
+            names.push(match *older {
+                0 => split_info.file_name.clone(),
+                older => format!("{}~{}", split_info.file_name, older),
+            });
+            *older += 1;
+        }
+        let mut codecs = Codecs::default();
+        let mut files = Vec::<MountedFile>::new();
+        for split_info in manifest.files.iter().filter(|split_info| !split_info.pending) {
+            files.push(MountedFile {
+                name: names.pop().unwrap(),
+                split_info: split_info.clone(),
+                codec: codecs.of(split_info)?,
+            });
+        }
+        Ok(SplitFs {
+            files,
+            uid: metadata.uid(),
+            gid: metadata.gid(),
+            mtime: metadata.modified()?,
+            cache: VecDeque::new(),
+            verified: HashSet::new(),
+        })
+    }
+
+    fn file(&self, ino: u64) -> Option<&MountedFile> {
+        ino.checked_sub(FUSE_ROOT_ID + 1).and_then(|index| self.files.get(index as usize))
+    }
+
+    fn attr(&self, ino: u64) -> Option<FileAttr> {
+        let (kind, perm, size, mtime) = if ino == FUSE_ROOT_ID {
+            (FileType::Directory, 0o555, 0, self.mtime)
+        } else {
+            let split_info = &self.file(ino)?.split_info;
+            let mtime = split_info
+                .mtime
+                .map_or(self.mtime, |mtime| UNIX_EPOCH + Duration::from_nanos(mtime));
+            (FileType::RegularFile, 0o444, split_info.file_size, mtime)
+        };
+        Some(FileAttr {
+            ino,
+            size,
+            blocks: size.div_ceil(512),
+            atime: mtime,
+            mtime,
+            ctime: mtime,
+            crtime: mtime,
+            kind,
+            perm,
+            nlink: if kind == FileType::Directory { 2 } else { 1 },
+            uid: self.uid,
+            gid: self.gid,
+            rdev: 0,
+            blksize: 4096,
+            flags: 0,
+        })
+    }
+
+    /// The plaintext of block `index` of the file, decoded once for several reads
+    fn block(&mut self, ino: u64, index: usize) -> Result<Rc<Vec<u8>>> {
+        if let Some(pos) = self.cache.iter().position(|(i, j, _)| *i == ino && *j == index) {
+            let cached = self.cache.remove(pos).unwrap();
+            let part_bytes = Rc::clone(&cached.2);
+            self.cache.push_back(cached);
+            return Ok(part_bytes);
+        }
+        let file = self.file(ino).unwrap();
+        let (block_path, block_name, length) = file.split_info.blocks().nth(index).unwrap();
+        let part_bytes = Rc::new(read_block(&file.codec, block_path, block_name, length)?);
+        self.verified.insert((ino, index));
+        if self.cache.len() == CACHED_BLOCKS {
+            self.cache.pop_front();
+        }
+        self.cache.push_back((ino, index, Rc::clone(&part_bytes)));
+        Ok(part_bytes)
+    }
+
+    /// Plain blocks are read in place once they were found intact, encoded ones have to be
+    /// decoded whole, and blocks that do not match their name are errors
+    fn read_at(&mut self, ino: u64, offset: u64, size: u64) -> Result<Vec<u8>> {
+        let file = self.file(ino).unwrap();
+        let end = offset.saturating_add(size).min(file.split_info.file_size);
+        let chunks = file.split_info.chunks.clone();
+        let is_identity = file.codec.is_identity();
+        let mut data = Vec::<u8>::with_capacity(end.saturating_sub(offset) as usize);
+        let mut pos = offset;
+        let mut index = chunks.partition_point(|chunk| chunk.offset + chunk.length <= offset);
+        while pos < end && index < chunks.len() {
+            let chunk = chunks[index];
+            let (from, to) = (pos - chunk.offset, (end - chunk.offset).min(chunk.length));
+            if is_identity && self.verified.contains(&(ino, index)) {
+                let block_path = &self.file(ino).unwrap().split_info.block_paths[index];
+                let mut part_bytes = vec![0u8; (to - from) as usize];
+                File::open(block_file_path(block_path))?.read_exact_at(&mut part_bytes, from)?;
+                data.extend_from_slice(&part_bytes);
+            } else {
+                data.extend_from_slice(&self.block(ino, index)?[from as usize..to as usize]);
+            }
+            pos = chunk.offset + to;
+            index += 1;
+        }
+        Ok(data)
+    }
+}
+
+impl Filesystem for SplitFs {
+    fn lookup(&mut self, _req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEntry) {
+        if parent != FUSE_ROOT_ID {
+            return reply.error(ENOENT);
+        }
+        let ino = self.files.iter().position(|file| OsStr::new(&file.name) == name);
+        match ino.and_then(|index| self.attr(FUSE_ROOT_ID + 1 + index as u64)) {
+            Some(attr) => reply.entry(&TTL, &attr, 0),
+            None => reply.error(ENOENT),
+        }
+    }
+
+    fn getattr(&mut self, _req: &Request<'_>, ino: u64, reply: ReplyAttr) {
+        match self.attr(ino) {
+            Some(attr) => reply.attr(&TTL, &attr),
+            None => reply.error(ENOENT),
+        }
+    }
+
+    fn read(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        _fh: u64,
+        offset: i64,
+        size: u32,
+        _flags: i32,
+        _lock_owner: Option<u64>,
+        reply: ReplyData,
+    ) {
+        if self.file(ino).is_none() {
+            return reply.error(ENOENT);
+        }
+        match self.read_at(ino, offset.max(0) as u64, size as u64) {
+            Ok(data) => reply.data(&data),
+            Err(e) => {
+                eprintln!("Reading {}: {}", self.file(ino).unwrap().name, e);
+                reply.error(EIO)
+            }
+        }
+    }
+
+    fn readdir(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        _fh: u64,
+        offset: i64,
+        mut reply: ReplyDirectory,
+    ) {
+        if ino != FUSE_ROOT_ID {
+            return reply.error(ENOTDIR);
+        }
+        let entries =
+            [(FUSE_ROOT_ID, FileType::Directory, "."), (FUSE_ROOT_ID, FileType::Directory, "..")]
+                .into_iter()
+                .chain(self.files.iter().enumerate().map(|(index, file)| {
+                    (FUSE_ROOT_ID + 1 + index as u64, FileType::RegularFile, file.name.as_str())
+                }));
+        for (i, (ino, kind, name)) in entries.enumerate().skip(offset as usize) {
+            // The offset is that of the next entry
+            if reply.add(ino, i as i64 + 1, kind, name) {
+                break;
+            }
+        }
+        reply.ok()
+    }
+}
+
+/// Serve the split files of the manifest under `mountpoint` until it is unmounted
+pub(crate) fn mount(mountpoint: &Path) -> Result<()> {
+    let manifest = Manifest::load_existing()?;
+    let split_fs = SplitFs::new(&manifest, &(*SPLIT_INFO_JSON_PATH).metadata()?)?;
+    println!("Mounting {} files on {}", split_fs.files.len(), mountpoint.display());
+    let options = [
+        MountOption::RO,
+        MountOption::FSName(String::from("fblock")),
+        MountOption::DefaultPermissions,
+    ];
+    fuser::mount2(split_fs, mountpoint, &options)
+}
+
+#[test]
+fn test_read_at() {
+    use crate::codec::Compression;
+    use crate::splinfo::test_store;
+    use crate::split::{split, SplitOptions};
+    use std::{fs, io::ErrorKind};
+
+    let (_store, store_dir) = test_store();
+    let bytes: Vec<u8> = (0..4500u32).map(|i| (i * 7 % 251) as u8).collect();
+    fs::write(store_dir.join("plain.bin"), &bytes).unwrap();
+    fs::write(store_dir.join("packed.bin"), &bytes).unwrap();
+    let mut options = SplitOptions::with_block_size(1000);
+    split(&store_dir.join("plain.bin"), None, &options).unwrap();
+    options.compression = Compression::Zstd;
+    split(&store_dir.join("packed.bin"), None, &options).unwrap();
+
+    let manifest = Manifest::load_existing().unwrap();
+    let mut split_fs =
+        SplitFs::new(&manifest, &(*SPLIT_INFO_JSON_PATH).metadata().unwrap()).unwrap();
+    for ino in [FUSE_ROOT_ID + 1, FUSE_ROOT_ID + 2] {
+        for (offset, size) in [(0, 10), (995, 10), (1000, 1000), (1999, 2002), (4490, 100)] {
+            let end = (offset + size).min(bytes.len());
+            let data = split_fs.read_at(ino, offset as u64, size as u64).unwrap();
+            assert_eq!(data, bytes[offset..end], "{} at {}", ino, offset);
+        }
+        assert!(split_fs.read_at(ino, 5000, 10).unwrap().is_empty());
+    }
+
+    // A block that does not match its name is never served, even in place
+    let mut split_fs =
+        SplitFs::new(&manifest, &(*SPLIT_INFO_JSON_PATH).metadata().unwrap()).unwrap();
+    for split_info in &manifest.files {
+        let block_path = store_dir.join(&split_info.block_paths[1]);
+        let mut block_bytes = fs::read(&block_path).unwrap();
+        block_bytes[0] ^= 1;
+        fs::write(&block_path, block_bytes).unwrap();
+    }
+    for ino in [FUSE_ROOT_ID + 1, FUSE_ROOT_ID + 2] {
+        assert_eq!(split_fs.read_at(ino, 1500, 10).unwrap_err().kind(), ErrorKind::InvalidData);
+        assert_eq!(split_fs.read_at(ino, 2500, 10).unwrap(), bytes[2500..2510]);
+    }
+}
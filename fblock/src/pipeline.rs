@@ -0,0 +1,304 @@
+use crate::blockopt::{store_block, Sequence};
+use crate::chunker::Chunker;
+use crate::codec::Codec;
+use crate::splinfo::Chunk;
+
+use std::{
+    collections::BTreeMap,
+    fs::File,
+    io::{Error, ErrorKind, Read, Result},
+    path::Path,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc::{self, SyncSender},
+        Mutex,
+    },
+    thread,
+};
+
+/// Where `split_blocks` cuts the file
+#[derive(Clone, Copy)]
+pub(crate) enum Cuts<'a> {
+    /// Every so many bytes
+    Fixed(u64),
+    /// Into `count` parts of `size` bytes in all
+    Parts { size: u64, count: u64 },
+    /// Where the content says
+    Cdc(&'a Chunker),
+}
+
+impl Cuts<'_> {
+    /// The most bytes a block can hold
+    fn max_block_len(self) -> u64 {
+        match self {
+            Cuts::Fixed(block_size) => block_size,
+            Cuts::Parts { size, count } => size.div_ceil(count),
+            Cuts::Cdc(chunker) => chunker.max_size as u64,
+        }
+    }
+}
+
+/// A block read from the source file, numbered in file order
+struct Task {
+    index: usize,
+    offset: u64,
+    part_bytes: Vec<u8>,
+}
+
+/// Read `file_path` once while `threads` workers hash and store its blocks, so that
+/// memory use stays around `threads` blocks whatever the size of the file.
+/// `on_block` sees every stored block in file order with its path and the name its content
+/// gives it, `stored` holds the paths of blocks already known to be intact, and the whole-file
+/// hash is returned. Blocks are stored under their name, or named in `sequence`.
+/// A hash that cannot stream would hold the whole file, so files larger than `threads` blocks
+/// are refused with it.
+pub(crate) fn split_blocks<F>(
+    file_path: &Path,
+    cuts: Cuts,
+    codec: &Codec,
+    sequence: Option<&Sequence>,
+    threads: usize,
+    stored: &[String],
+    mut on_block: F,
+) -> Result<String>
+where
+    F: FnMut(Chunk, String, String) -> Result<()>,
+{
+    if !codec.hash.streams() {
+        let max_len = cuts.max_block_len().saturating_mul(threads as u64);
+        if file_path.metadata()?.len() > max_len {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!(
+                    "{:?} hashes a whole file in memory, {} is larger than the {} bytes of {} \
+                     blocks, split it with --hash sha256 or blake3",
+                    codec.hash,
+                    file_path.display(),
+                    max_len,
+                    threads
+                ),
+            ));
+        }
+    }
+    let failed = &AtomicBool::new(false);
+    let (task_sender, task_receiver) = mpsc::sync_channel::<Task>(threads);
+    let task_receiver = &Mutex::new(task_receiver);
+    let (block_sender, block_receiver) =
+        mpsc::channel::<Result<(usize, Chunk, (String, String))>>();
+
+    thread::scope(|scope| {
+        let reader = scope.spawn(move || {
+            let file_hash = read_blocks(file_path, cuts, codec, failed, task_sender);
+            if file_hash.is_err() {
+                failed.store(true, Ordering::Relaxed);
+            }
+            file_hash
+        });
+        for _ in 0..threads {
+            let block_sender = block_sender.clone();
+            scope.spawn(move || loop {
+                let task = match task_receiver.lock().unwrap().recv() {
+                    Ok(task) => task,
+                    Err(_) => break,
+                };
+                // Keep draining after a failure so that the reader is never left blocked
+                if failed.load(Ordering::Relaxed) {
+                    continue;
+                }
+                let chunk = Chunk { offset: task.offset, length: task.part_bytes.len() as u64 };
+                let block = store_block(
+                    codec,
+                    &task.part_bytes,
+                    sequence.map(|sequence| sequence.part_name(task.index)),
+                    stored.get(task.index).map(String::as_str),
+                )
+                .map(|block| (task.index, chunk, block));
+                if block.is_err() {
+                    failed.store(true, Ordering::Relaxed);
+                }
+                let _ = block_sender.send(block);
+            });
+        }
+        drop(block_sender);
+
+        // Workers finish out of order, blocks wait here until those before them are done
+        let mut done = BTreeMap::<usize, (Chunk, (String, String))>::new();
+        let mut next_index = 0;
+        let mut error = None;
+        for block in block_receiver {
+            match block {
+                Ok((index, chunk, block)) if error.is_none() => {
+                    done.insert(index, (chunk, block));
+                    while let Some((chunk, (block_path, block_name))) = done.remove(&next_index) {
+                        next_index += 1;
+                        if let Err(e) = on_block(chunk, block_path, block_name) {
+                            failed.store(true, Ordering::Relaxed);
+                            error = Some(e);
+                            break;
+                        }
+                    }
+                }
+                Ok(_) => {}
+                Err(e) => {
+                    error.get_or_insert(e);
+                }
+            }
+        }
+        let file_hash = reader.join().unwrap();
+        match error {
+            Some(e) => Err(e),
+            None => file_hash,
+        }
+    })
+}
+
+/// Cut the file into blocks for the workers, hashing all of it on the way
+fn read_blocks(
+    file_path: &Path,
+    cuts: Cuts,
+    codec: &Codec,
+    failed: &AtomicBool,
+    tasks: SyncSender<Task>,
+) -> Result<String> {
+    let mut file = File::open(file_path)?;
+    let mut hasher = codec.hash.hasher();
+    let mut offset = 0;
+    // What the last content-defined chunk left of the bytes read
+    let mut buffer = Vec::<u8>::new();
+    for index in 0.. {
+        if failed.load(Ordering::Relaxed) {
+            break;
+        }
+        let part_bytes = match cuts {
+            Cuts::Cdc(chunker) => {
+                let wanted = (chunker.max_size - buffer.len()) as u64;
+                file.by_ref().take(wanted).read_to_end(&mut buffer)?;
+                if buffer.is_empty() {
+                    break;
+                }
+                let rest = buffer.split_off(chunker.cut(&buffer));
+                std::mem::replace(&mut buffer, rest)
+            }
+            Cuts::Fixed(block_size) => read_part(&mut file, block_size)?,
+            Cuts::Parts { size, count } => {
+                // The first parts take the bytes left over by the division
+                let part = index as u64;
+                if part == count {
+                    break;
+                }
+                read_part(&mut file, size / count + u64::from(part < size % count))?
+            }
+        };
+        hasher.update(&part_bytes);
+        let length = part_bytes.len() as u64;
+        if tasks.send(Task { index, offset, part_bytes }).is_err() {
+            break;
+        }
+        offset += length;
+        // Fixed blocks end with a short one, even an empty one
+        if matches!(cuts, Cuts::Fixed(block_size) if length < block_size) {
+            break;
+        }
+    }
+    Ok(hasher.finish())
+}
+
+fn read_part(file: &mut File, part_size: u64) -> Result<Vec<u8>> {
+    let mut part_bytes = Vec::<u8>::with_capacity(part_size as usize);
+    file.by_ref().take(part_size).read_to_end(&mut part_bytes)?;
+    Ok(part_bytes)
+}
+
+#[test]
+fn test_buffered_hash_stays_within_threads_blocks() {
+    use crate::hash::HashAlgorithm;
+    use crate::splinfo::test_store;
+
+    let (_store, store_dir) = test_store();
+    let file_path = store_dir.join("image.bin");
+    std::fs::write(&file_path, vec![7u8; 4096]).unwrap();
+    let codec = Codec::new(HashAlgorithm::City128, Default::default(), None);
+    let e = split_blocks(&file_path, Cuts::Fixed(1024), &codec, None, 2, &[], |_, _, _| Ok(()))
+        .unwrap_err();
+    assert_eq!(e.kind(), ErrorKind::InvalidInput);
+    assert_eq!(std::fs::read_dir(&store_dir).unwrap().count(), 1);
+}
+
+#[test]
+fn test_split_blocks_keeps_file_order() {
+    use crate::hash::HashAlgorithm;
+    use crate::splinfo::test_store;
+
+    let (_store, store_dir) = test_store();
+    let file_path = store_dir.join("image.bin");
+    let bytes: Vec<u8> = (0..100_000u32).map(|i| (i * 7 % 251) as u8).collect();
+    std::fs::write(&file_path, &bytes).unwrap();
+    let codec = Codec::new(HashAlgorithm::Blake3, Default::default(), None);
+    let mut blocks = Vec::<(Chunk, String)>::new();
+    let file_hash = split_blocks(
+        &file_path,
+        Cuts::Fixed(1000),
+        &codec,
+        None,
+        4,
+        &[],
+        |chunk, block_path, _| {
+            blocks.push((chunk, block_path));
+            Ok(())
+        },
+    )
+    .unwrap();
+    assert_eq!(file_hash, HashAlgorithm::Blake3.compute(&bytes));
+    // And the empty block that ends a file of whole blocks
+    assert_eq!(blocks.len(), 101);
+    for (index, (chunk, block_path)) in blocks.iter().enumerate() {
+        let part_bytes =
+            &bytes[(index * 1000).min(bytes.len())..((index + 1) * 1000).min(bytes.len())];
+        assert_eq!(chunk.offset, index as u64 * 1000);
+        assert_eq!(chunk.length, part_bytes.len() as u64);
+        assert_eq!(*block_path, HashAlgorithm::Blake3.compute(part_bytes));
+    }
+}
+
+#[test]
+fn test_split_blocks_stops_at_the_first_failure() {
+    use crate::hash::HashAlgorithm;
+    use crate::splinfo::test_store;
+
+    let (_store, store_dir) = test_store();
+    let file_path = store_dir.join("image.bin");
+    std::fs::write(&file_path, vec![1u8; 200 * 100]).unwrap();
+    let codec = Codec::new(HashAlgorithm::Blake3, Default::default(), None);
+    let sequence = Sequence::new("image.bin", 200);
+    // A folder where the tenth part goes cannot be replaced by it
+    std::fs::create_dir_all(store_dir.join(sequence.part_name(9)).join("x")).unwrap();
+    let mut offsets = Vec::<u64>::new();
+    let result = split_blocks(
+        &file_path,
+        Cuts::Fixed(100),
+        &codec,
+        Some(&sequence),
+        4,
+        &[],
+        |chunk, _, _| {
+            offsets.push(chunk.offset);
+            Ok(())
+        },
+    );
+    assert!(result.is_err());
+    assert!(offsets.len() <= 9);
+    assert!(offsets.iter().enumerate().all(|(index, offset)| *offset == index as u64 * 100));
+
+    // Or when what is done with the blocks fails
+    let mut seen = 0;
+    let e = split_blocks(&file_path, Cuts::Fixed(100), &codec, None, 4, &[], |_, _, _| {
+        seen += 1;
+        match seen {
+            5 => Err(Error::other("journal full")),
+            _ => Ok(()),
+        }
+    })
+    .unwrap_err();
+    assert_eq!(e.to_string(), "journal full");
+    assert_eq!(seen, 5);
+}
@@ -0,0 +1,272 @@
+use crate::codec::{Codecs, Naming};
+use crate::restore::restore_file;
+use crate::splinfo::{
+    block_file_path, is_plain_file_name, Manifest, SplitInfo, SPLIT_INFO_JSON_NAME,
+};
+
+use std::{
+    collections::HashSet,
+    fs,
+    io::{Error, ErrorKind, Result},
+};
+
+use hyper::{client::HttpConnector, Body, Client, Method, Request, StatusCode, Uri};
+use hyper_rustls::{HttpsConnector, HttpsConnectorBuilder};
+
+/// A block server, every block is a file under `base` named after it
+struct Remote {
+    client: Client<HttpsConnector<HttpConnector>>,
+    base: String,
+}
+
+impl Remote {
+    fn new(base: &str) -> Self {
+        let connector =
+            HttpsConnectorBuilder::new().with_native_roots().https_or_http().enable_http1().build();
+        Remote {
+            client: Client::builder().build(connector),
+            base: base.trim_end_matches('/').into(),
+        }
+    }
+
+    fn uri(&self, name: &str) -> Result<Uri> {
+        format!("{}/{}", self.base, name)
+            .parse::<Uri>()
+            .map_err(|e| Error::new(ErrorKind::InvalidInput, e))
+    }
+
+    async fn send(&self, method: Method, name: &str, body: Body) -> Result<hyper::Response<Body>> {
+        let request = Request::builder()
+            .method(method)
+            .uri(self.uri(name)?)
+            .body(body)
+            .map_err(|e| Error::new(ErrorKind::InvalidInput, e))?;
+        self.client.request(request).await.map_err(Error::other)
+    }
+
+    async fn exists(&self, name: &str) -> Result<bool> {
+        let response = self.send(Method::HEAD, name, Body::empty()).await?;
+        match response.status() {
+            StatusCode::NOT_FOUND => Ok(false),
+            status if status.is_success() => Ok(true),
+            status => Err(status_error(&Method::HEAD, name, status)),
+        }
+    }
+
+    /// The content of `name`, `None` if the server does not have it
+    async fn get(&self, name: &str) -> Result<Option<Vec<u8>>> {
+        let response = self.send(Method::GET, name, Body::empty()).await?;
+        match response.status() {
+            StatusCode::NOT_FOUND => Ok(None),
+            status if status.is_success() => {
+                let bytes =
+                    hyper::body::to_bytes(response.into_body()).await.map_err(Error::other)?;
+                Ok(Some(bytes.to_vec()))
+            }
+            status => Err(status_error(&Method::GET, name, status)),
+        }
+    }
+
+    async fn put(&self, name: &str, bytes: Vec<u8>) -> Result<()> {
+        let response = self.send(Method::PUT, name, Body::from(bytes)).await?;
+        match response.status() {
+            status if status.is_success() => Ok(()),
+            status => Err(status_error(&Method::PUT, name, status)),
+        }
+    }
+
+    /// The manifest the server has, an empty one if it has no split info JSON yet
+    async fn manifest(&self) -> Result<Manifest> {
+        match self.get(SPLIT_INFO_JSON_NAME).await? {
+            Some(bytes) => {
+                let json =
+                    String::from_utf8(bytes).map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+                Manifest::parse(&json, None).map_err(|e| {
+                    Error::new(
+                        e.kind(),
+                        format!("{} on {}: {}", SPLIT_INFO_JSON_NAME, self.base, e),
+                    )
+                })
+            }
+            None => Ok(Manifest::default()),
+        }
+    }
+}
+
+fn status_error(method: &Method, name: &str, status: StatusCode) -> Error {
+    Error::other(format!("{} {} failed: {}", method, name, status))
+}
+
+fn runtime() -> Result<tokio::runtime::Runtime> {
+    tokio::runtime::Builder::new_current_thread().enable_all().build()
+}
+
+/// Replace the split infos of the same files in `split_infos` by `merged`
+fn merge(split_infos: &mut Vec<SplitInfo>, merged: &[&SplitInfo]) {
+    split_infos.retain(|split_info| {
+        !merged.iter().any(|other| {
+            other.file_name == split_info.file_name && other.file_hash == split_info.file_hash
+        })
+    });
+    split_infos.extend(merged.iter().map(|split_info| (*split_info).clone()));
+}
+
+/// The first name of a file or block that `pull` would refuse to write
+fn unplain_name<'a>(split_infos: &[&'a SplitInfo]) -> Option<&'a str> {
+    split_infos
+        .iter()
+        .flat_map(|split_info| split_info.block_paths.iter().chain([&split_info.file_name]))
+        .map(String::as_str)
+        .find(|name| !is_plain_file_name(name))
+}
+
+pub(crate) fn push(to: &str, name: Option<&str>) -> Result<()> {
+    let manifest = Manifest::load_existing()?;
+    let (split_infos, _) = manifest.named(name)?;
+    if let Some(split_info) = split_infos.iter().find(|split_info| split_info.pending) {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            format!("{} was not completely split", split_info.file_name),
+        ));
+    }
+    // Like old blocks named in standard base64, which may contain `/`
+    if let Some(name) = unplain_name(&split_infos) {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            format!("{} is not a plain file name, it could never be pulled", name),
+        ));
+    }
+    runtime()?.block_on(async {
+        let remote = Remote::new(to);
+        let (mut sent, mut present) = (0, 0);
+        let mut seen = HashSet::<&str>::new();
+        let block_paths = split_infos.iter().flat_map(|split_info| {
+            split_info.block_paths.iter().map(move |block_path| (block_path, split_info.naming))
+        });
+        for (block_path, naming) in block_paths {
+            if !seen.insert(block_path.as_str()) {
+                continue;
+            }
+            // Only a name derived from the content says the server has the same bytes, a
+            // sequential part may be left from an earlier split of another version
+            if naming == Naming::ContentHash && remote.exists(block_path).await? {
+                present += 1;
+                continue;
+            }
+            remote.put(block_path, fs::read(block_file_path(block_path))?).await?;
+            sent += 1;
+        }
+        // Only once all blocks are there, so the server never refers to blocks it lacks
+        let mut remote_manifest = remote.manifest().await?;
+        merge(&mut remote_manifest.files, &split_infos);
+        remote.put(SPLIT_INFO_JSON_NAME, remote_manifest.to_json().into_bytes()).await?;
+        println!(
+            "Pushed {} files, sent {} blocks, {} already on the server",
+            split_infos.len(),
+            sent,
+            present
+        );
+        Ok(())
+    })
+}
+
+pub(crate) fn pull(from: &str, name: Option<&str>) -> Result<()> {
+    runtime()?.block_on(async {
+        let remote = Remote::new(from);
+        let remote_manifest = remote.manifest().await?;
+        let split_infos: Vec<_> = remote_manifest
+            .files
+            .iter()
+            .filter(|split_info| !split_info.pending)
+            .filter(|split_info| name.is_none_or(|name| split_info.file_name == name))
+            .collect();
+        if split_infos.is_empty() {
+            return Err(Error::new(
+                ErrorKind::NotFound,
+                format!("No split info for {} on {}", name.unwrap_or("any file"), from),
+            ));
+        }
+        // Names from the server end up in local paths, they must stay in the folder
+        if let Some(name) = unplain_name(&split_infos) {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("Refusing to write {} outside of the folder", name),
+            ));
+        }
+
+        let mut received = 0;
+        let mut seen = HashSet::<&str>::new();
+        for block_path in split_infos.iter().flat_map(|split_info| &split_info.block_paths) {
+            let block_file_path = block_file_path(block_path);
+            if !seen.insert(block_path.as_str()) || block_file_path.exists() {
+                continue;
+            }
+            let bytes = remote.get(block_path).await?.ok_or_else(|| {
+                Error::new(ErrorKind::NotFound, format!("{} is missing on {}", block_path, from))
+            })?;
+            let tmp_path = block_file_path.with_file_name(format!("{}.tmp", block_path));
+            fs::write(&tmp_path, bytes)?;
+            fs::rename(&tmp_path, &block_file_path)?;
+            received += 1;
+        }
+        println!("Received {} blocks", received);
+
+        let mut local_manifest = Manifest::load()?;
+        merge(&mut local_manifest.files, &split_infos);
+        local_manifest.write()?;
+        let mut codecs = Codecs::default();
+        for split_info in split_infos {
+            restore_file(split_info, &codecs.of(split_info)?)?;
+        }
+        Ok(())
+    })
+}
+
+#[test]
+fn test_push_and_pull() {
+    use crate::serve::serve_on;
+    use crate::splinfo::test_store;
+    use crate::split::{split, SplitOptions};
+
+    let (_store, store_dir) = test_store();
+    let server_dir = store_dir.with_file_name(format!("fblock-test-{}-server", std::process::id()));
+    if server_dir.exists() {
+        fs::remove_dir_all(&server_dir).unwrap();
+    }
+    fs::create_dir(&server_dir).unwrap();
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("http://{}", listener.local_addr().unwrap());
+    let served_dir = server_dir.clone();
+    std::thread::spawn(move || serve_on(&served_dir, listener));
+
+    let bytes: Vec<u8> = (0..300_000u32).map(|i| (i * 7 % 251) as u8).collect();
+    fs::write(store_dir.join("image.bin"), &bytes).unwrap();
+    split(&store_dir.join("image.bin"), None, &SplitOptions::with_block_size(64 * 1024)).unwrap();
+    push(&url, None).unwrap();
+    let manifest = Manifest::load_existing().unwrap();
+    let block_paths = manifest.files[0].block_paths.clone();
+    assert_eq!(block_paths.len(), 5);
+    let empty_store = || {
+        fs::remove_dir_all(&store_dir).unwrap();
+        fs::create_dir(&store_dir).unwrap();
+    };
+
+    empty_store();
+    pull(&url, None).unwrap();
+    assert_eq!(fs::read(store_dir.join("image.bin")).unwrap(), bytes);
+
+    empty_store();
+    fs::write(server_dir.join(&block_paths[2]), b"tampered").unwrap();
+    assert_eq!(pull(&url, None).unwrap_err().kind(), ErrorKind::InvalidData);
+    assert!(!store_dir.join("image.bin").exists());
+
+    empty_store();
+    fs::remove_file(server_dir.join(&block_paths[3])).unwrap();
+    assert_eq!(pull(&url, None).unwrap_err().kind(), ErrorKind::NotFound);
+    assert!(!store_dir.join("image.bin").exists());
+
+    let mut legacy = manifest;
+    legacy.files[0].block_paths[0] = String::from("AbC/dEf+gHi=");
+    legacy.write().unwrap();
+    assert_eq!(push(&url, None).unwrap_err().kind(), ErrorKind::InvalidInput);
+}
@@ -0,0 +1,287 @@
+use crate::blockopt::read_block;
+use crate::codec::{Codec, Codecs};
+use crate::splinfo::{
+    is_plain_file_name, EntryKind, Manifest, SplitInfo, TreeInfo, SPLIT_INFO_JSON_PATH,
+};
+
+use std::{
+    collections::BTreeMap,
+    fs::{self, File, OpenOptions},
+    io::{Error, ErrorKind, Result, Write},
+    path::Path,
+    time::{Duration, UNIX_EPOCH},
+};
+
+pub(crate) fn restore(name: Option<&str>, snapshot: Option<u64>) -> Result<()> {
+    let manifest = Manifest::load_existing()?;
+    let (split_infos, trees) = manifest.named(name)?;
+    let mut codecs = Codecs::default();
+    for split_info in split_infos {
+        restore_file(split_info, &codecs.of(split_info)?)?;
+    }
+    // The latest snapshot of each tree, or the one asked for
+    let mut latest = BTreeMap::<&str, &TreeInfo>::new();
+    for tree in trees.into_iter().filter(|tree| snapshot.is_none_or(|s| s == tree.snapshot)) {
+        let latest_tree = latest.entry(tree.root.as_str()).or_insert(tree);
+        if tree.snapshot > latest_tree.snapshot {
+            *latest_tree = tree;
+        }
+    }
+    if let (Some(snapshot), true) = (snapshot, latest.is_empty()) {
+        return Err(Error::new(ErrorKind::NotFound, format!("No snapshot {}", snapshot)));
+    }
+    for tree in latest.values() {
+        restore_tree(tree, &mut codecs)?;
+    }
+    Ok(())
+}
+
+/// Concatenate the blocks into a temporary file, and only give it
+/// the original name once its hash matches `file_hash`
+pub(crate) fn restore_file(split_info: &SplitInfo, codec: &Codec) -> Result<()> {
+    if split_info.pending {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            format!(
+                "{} was not completely split, split it with --resume first",
+                split_info.file_name
+            ),
+        ));
+    }
+    if !is_plain_file_name(&split_info.file_name) {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            format!("Refusing to write {} outside of the folder", split_info.file_name),
+        ));
+    }
+    let split_info_dir_path = (*SPLIT_INFO_JSON_PATH).parent().unwrap();
+    let file_path = split_info_dir_path.join(&split_info.file_name);
+    if file_path.exists() {
+        return Err(Error::new(
+            ErrorKind::AlreadyExists,
+            format!("{} already exists", file_path.display()),
+        ));
+    }
+    let restoring_file_path =
+        split_info_dir_path.join(format!("{}.restoring", split_info.file_name));
+    write_file(split_info, codec, &restoring_file_path)?;
+    std::fs::rename(&restoring_file_path, &file_path)?;
+    println!("Restored {}", file_path.display());
+    Ok(())
+}
+
+/// Concatenate the blocks into `file_path`, which is deleted again unless they all are intact
+/// and its hash matches
+fn write_file(split_info: &SplitInfo, codec: &Codec, file_path: &Path) -> Result<()> {
+    let written = write_blocks(split_info, codec, file_path);
+    if written.is_err() {
+        let _ = fs::remove_file(file_path);
+    }
+    written
+}
+
+fn write_blocks(split_info: &SplitInfo, codec: &Codec, file_path: &Path) -> Result<()> {
+    let mut restoring_file =
+        OpenOptions::new().create(true).truncate(true).read(true).write(true).open(file_path)?;
+    let mut hasher = codec.hash.hasher();
+    for (block_path, block_name, length) in split_info.blocks() {
+        let part_bytes = read_block(codec, block_path, block_name, length)?;
+        hasher.update(&part_bytes);
+        restoring_file.write_all(&part_bytes)?;
+    }
+    restoring_file.flush()?;
+    restoring_file.sync_all()?;
+    drop(restoring_file);
+
+    let file_hash = hasher.finish();
+    #[cfg(debug_assertions)]
+    dbg!(&file_hash);
+    if file_hash != split_info.file_hash {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            format!("Restored {} does not match its hash", split_info.file_name),
+        ));
+    }
+    Ok(())
+}
+
+/// Rebuild a snapshot in a temporary folder, and only give it the name of the tree once complete
+fn restore_tree(tree: &TreeInfo, codecs: &mut Codecs) -> Result<()> {
+    if tree
+        .entries
+        .iter()
+        .any(|entry| matches!(&entry.kind, EntryKind::File(split_info) if split_info.pending))
+    {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            format!("{} snapshot {} is incomplete", tree.root, tree.snapshot),
+        ));
+    }
+    let split_info_dir_path = (*SPLIT_INFO_JSON_PATH).parent().unwrap();
+    let tree_path = split_info_dir_path.join(&tree.root);
+    if tree_path.symlink_metadata().is_ok() {
+        return Err(Error::new(
+            ErrorKind::AlreadyExists,
+            format!("{} already exists", tree_path.display()),
+        ));
+    }
+    let restoring_path = split_info_dir_path.join(format!("{}.restoring", tree.root));
+    // Left over by an interrupted restore
+    if restoring_path.exists() {
+        fs::remove_dir_all(&restoring_path)?;
+    }
+    fs::create_dir(&restoring_path)?;
+    for entry in &tree.entries {
+        let path = restoring_path.join(&entry.path);
+        match &entry.kind {
+            EntryKind::Dir => fs::create_dir(&path)?,
+            EntryKind::File(split_info) => {
+                write_file(split_info, &codecs.of(split_info)?, &path)?;
+                set_mtime(&File::options().write(true).open(&path)?, split_info.mtime)?;
+            }
+            EntryKind::Symlink(target) => symlink(target, &path)?,
+        }
+    }
+    // Contents before their folder, a read-only one would not let them be changed, and
+    // folders only once nothing is added to them anymore
+    for entry in tree.entries.iter().rev() {
+        let path = restoring_path.join(&entry.path);
+        match entry.kind {
+            EntryKind::Dir => {
+                set_dir_mtime(&path, entry.mtime)?;
+                set_mode(&path, entry.mode)?;
+            }
+            EntryKind::File(_) => set_mode(&path, entry.mode)?,
+            EntryKind::Symlink(_) => {}
+        }
+    }
+    set_dir_mtime(&restoring_path, tree.mtime)?;
+    set_mode(&restoring_path, tree.mode)?;
+    fs::rename(&restoring_path, &tree_path)?;
+    println!("Restored {} snapshot {}", tree_path.display(), tree.snapshot);
+    Ok(())
+}
+
+/// In nanoseconds since the Unix epoch, if known
+fn set_mtime(file: &File, mtime: Option<u64>) -> Result<()> {
+    match mtime {
+        Some(mtime) => file.set_modified(UNIX_EPOCH + Duration::from_nanos(mtime)),
+        None => Ok(()),
+    }
+}
+
+#[cfg(unix)]
+fn set_dir_mtime(path: &Path, mtime: Option<u64>) -> Result<()> {
+    set_mtime(&File::open(path)?, mtime)
+}
+
+/// Folders do not open like files there
+#[cfg(not(unix))]
+fn set_dir_mtime(_path: &Path, _mtime: Option<u64>) -> Result<()> {
+    Ok(())
+}
+
+#[cfg(unix)]
+fn set_mode(path: &Path, mode: u32) -> Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    fs::set_permissions(path, fs::Permissions::from_mode(mode))
+}
+
+#[cfg(not(unix))]
+fn set_mode(path: &Path, mode: u32) -> Result<()> {
+    let mut permissions = fs::metadata(path)?.permissions();
+    permissions.set_readonly(mode & 0o222 == 0);
+    fs::set_permissions(path, permissions)
+}
+
+#[cfg(unix)]
+fn symlink(target: &str, path: &Path) -> Result<()> {
+    std::os::unix::fs::symlink(target, path)
+}
+
+#[cfg(not(unix))]
+fn symlink(_target: &str, path: &Path) -> Result<()> {
+    Err(Error::new(
+        ErrorKind::Unsupported,
+        format!("Cannot restore {} as a symlink on this platform", path.display()),
+    ))
+}
+
+#[test]
+fn test_restore() {
+    use crate::splinfo::test_store;
+    use crate::split::{split, SplitOptions};
+
+    let (_store, store_dir) = test_store();
+    let file_path = store_dir.join("image.bin");
+    let bytes: Vec<u8> = (0..100_000u32).map(|i| (i * 7 % 251) as u8).collect();
+    fs::write(&file_path, &bytes).unwrap();
+    split(&file_path, None, &SplitOptions::with_block_size(16 * 1024)).unwrap();
+    assert!(!file_path.exists());
+    restore(None, None).unwrap();
+    assert_eq!(fs::read(&file_path).unwrap(), bytes);
+    assert_eq!(restore(None, None).unwrap_err().kind(), ErrorKind::AlreadyExists);
+    assert_eq!(fs::read(&file_path).unwrap(), bytes);
+
+    let manifest = Manifest::load_existing().unwrap();
+    let split_info = &manifest.files[0];
+    let restoring_path = store_dir.join("image.bin.restoring");
+    fs::remove_file(&file_path).unwrap();
+    let corrupt_path = store_dir.join(&split_info.block_paths[3]);
+    let block_bytes = fs::read(&corrupt_path).unwrap();
+    fs::write(&corrupt_path, &block_bytes[1..]).unwrap();
+    assert_eq!(restore(None, None).unwrap_err().kind(), ErrorKind::InvalidData);
+    assert!(!file_path.exists() && !restoring_path.exists());
+    fs::remove_file(&corrupt_path).unwrap();
+    assert_eq!(restore(None, None).unwrap_err().kind(), ErrorKind::NotFound);
+    assert!(!file_path.exists() && !restoring_path.exists());
+
+    let escaping = SplitInfo { file_name: String::from("../image.bin"), ..split_info.clone() };
+    let codec = Codecs::default().of(&escaping).unwrap();
+    assert_eq!(restore_file(&escaping, &codec).unwrap_err().kind(), ErrorKind::InvalidData);
+}
+
+#[cfg(unix)]
+#[test]
+fn test_restore_tree() {
+    use crate::splinfo::test_store;
+    use crate::split::{split_tree, SplitOptions};
+    use std::os::unix::fs::PermissionsExt;
+
+    let (_store, store_dir) = test_store();
+    let tree_path = store_dir.join("project");
+    fs::create_dir_all(tree_path.join("src")).unwrap();
+    fs::write(tree_path.join("src/main.rs"), b"fn main() {}\n").unwrap();
+    fs::write(tree_path.join("run.sh"), vec![b'#'; 2500]).unwrap();
+    std::os::unix::fs::symlink("src/main.rs", tree_path.join("main.rs")).unwrap();
+    set_mode(&tree_path.join("run.sh"), 0o751).unwrap();
+    set_mode(&tree_path.join("src/main.rs"), 0o640).unwrap();
+    set_mode(&tree_path.join("src"), 0o750).unwrap();
+    set_mode(&tree_path, 0o700).unwrap();
+    let past = UNIX_EPOCH + Duration::from_secs(1_000_000_000);
+    for path in ["run.sh", "src", ""] {
+        File::open(tree_path.join(path)).unwrap().set_modified(past).unwrap();
+    }
+    split_tree(&tree_path, None, &SplitOptions::with_block_size(1000)).unwrap();
+    let original_path = store_dir.join("project.orig");
+    fs::rename(&tree_path, &original_path).unwrap();
+
+    restore(Some("project"), None).unwrap();
+    for path in ["", "src", "src/main.rs", "run.sh"] {
+        let (original, restored) = (
+            original_path.join(path).metadata().unwrap(),
+            tree_path.join(path).metadata().unwrap(),
+        );
+        assert_eq!(restored.permissions().mode(), original.permissions().mode(), "{}", path);
+        assert_eq!(restored.modified().unwrap(), original.modified().unwrap(), "{}", path);
+        if original.is_file() {
+            assert_eq!(
+                fs::read(tree_path.join(path)).unwrap(),
+                fs::read(original_path.join(path)).unwrap()
+            );
+        }
+    }
+    assert_eq!(tree_path.join("run.sh").metadata().unwrap().modified().unwrap(), past);
+    assert_eq!(fs::read_link(tree_path.join("main.rs")).unwrap(), Path::new("src/main.rs"));
+    assert_eq!(restore(Some("project"), None).unwrap_err().kind(), ErrorKind::AlreadyExists);
+}
@@ -0,0 +1,143 @@
+use std::{
+    convert::Infallible,
+    fs,
+    io::{Error, ErrorKind, Result},
+    net::{SocketAddr, TcpListener},
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use hyper::{
+    header::{ALLOW, CONTENT_LENGTH},
+    service::{make_service_fn, service_fn},
+    Body, Method, Request, Response, Server, StatusCode,
+};
+
+/// Numbers the temporary files of uploads
+static PUT_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+/// Serve the files under `dir` like rfserv does, and store whatever is PUT there,
+/// which is all `push` and `pull` need from a block server
+pub(crate) fn serve(dir: &Path, addr: &str) -> Result<()> {
+    let addr = addr.parse::<SocketAddr>().map_err(|e| Error::new(ErrorKind::InvalidInput, e))?;
+    serve_on(dir, TcpListener::bind(addr)?)
+}
+
+/// Like `serve`, on a socket already bound
+pub(crate) fn serve_on(dir: &Path, listener: TcpListener) -> Result<()> {
+    let addr = listener.local_addr()?;
+    let dir: &'static Path = Box::leak(dir.to_path_buf().into_boxed_path());
+    let runtime = tokio::runtime::Builder::new_current_thread().enable_all().build()?;
+    runtime.block_on(async {
+        let make_service = make_service_fn(move |_| async move {
+            Ok::<_, Infallible>(service_fn(move |request| handle(dir, request)))
+        });
+        let server = Server::from_tcp(listener).map_err(Error::other)?.serve(make_service);
+        println!("Serving {} on http://{}", dir.display(), addr);
+        server.await.map_err(Error::other)
+    })
+}
+
+async fn handle(
+    dir: &Path,
+    request: Request<Body>,
+) -> std::result::Result<Response<Body>, Infallible> {
+    let method = request.method().clone();
+    let uri_path = request.uri().path().to_string();
+    let response = match local_path(dir, &uri_path) {
+        Some(path) => match method {
+            Method::GET | Method::HEAD => read(&path, method == Method::HEAD),
+            Method::PUT => match hyper::body::to_bytes(request.into_body()).await {
+                Ok(bytes) => write(&path, &bytes),
+                Err(_) => status(StatusCode::BAD_REQUEST),
+            },
+            _ => {
+                let mut response = status(StatusCode::METHOD_NOT_ALLOWED);
+                response.headers_mut().insert(ALLOW, "GET, HEAD, PUT".parse().unwrap());
+                response
+            }
+        },
+        None => status(StatusCode::BAD_REQUEST),
+    };
+    println!("{} {} {}", method, uri_path, response.status().as_u16());
+    Ok(response)
+}
+
+/// Where `uri_path` points under `dir`, if it stays there
+fn local_path(dir: &Path, uri_path: &str) -> Option<PathBuf> {
+    let mut path = dir.to_path_buf();
+    for segment in uri_path.split('/').filter(|segment| !segment.is_empty()) {
+        if segment == "." || segment == ".." || segment.contains('\\') {
+            return None;
+        }
+        path.push(segment);
+    }
+    Some(path)
+}
+
+fn status(status: StatusCode) -> Response<Body> {
+    let mut response = Response::new(Body::empty());
+    *response.status_mut() = status;
+    response
+}
+
+fn read(path: &Path, head: bool) -> Response<Body> {
+    let len = match fs::metadata(path) {
+        Ok(metadata) if metadata.is_file() => metadata.len(),
+        _ => return status(StatusCode::NOT_FOUND),
+    };
+    let body = if head {
+        Body::empty()
+    } else {
+        match fs::read(path) {
+            Ok(bytes) => Body::from(bytes),
+            Err(_) => return status(StatusCode::INTERNAL_SERVER_ERROR),
+        }
+    };
+    let mut response = Response::new(body);
+    response.headers_mut().insert(CONTENT_LENGTH, len.into());
+    response
+}
+
+/// Replace the file through a temporary one, so readers never see half of it
+fn write(path: &Path, bytes: &[u8]) -> Response<Body> {
+    let existed = path.is_file();
+    // Two uploads of the same block may arrive at once, each writes a file of its own
+    let tmp_path = path.with_file_name(format!(
+        "{}.{}.{}.put",
+        path.file_name().map_or_else(String::new, |name| name.to_string_lossy().to_string()),
+        std::process::id(),
+        PUT_COUNTER.fetch_add(1, Ordering::Relaxed)
+    ));
+    let written = path.parent().map_or(Ok(()), fs::create_dir_all).and_then(|_| {
+        fs::write(&tmp_path, bytes)?;
+        fs::rename(&tmp_path, path)
+    });
+    match written {
+        Ok(_) if existed => status(StatusCode::NO_CONTENT),
+        Ok(_) => status(StatusCode::CREATED),
+        Err(_) => {
+            let _ = fs::remove_file(&tmp_path);
+            status(StatusCode::INTERNAL_SERVER_ERROR)
+        }
+    }
+}
+
+#[test]
+fn test_concurrent_writes() {
+    let dir = std::env::temp_dir().join(format!("fblock-test-{}-put", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("block");
+    let contents: Vec<Vec<u8>> = (0..8u8).map(|i| vec![i; 256 * 1024]).collect();
+    std::thread::scope(|scope| {
+        for bytes in &contents {
+            let path = &path;
+            scope.spawn(move || assert!(write(path, bytes).status().is_success()));
+        }
+    });
+    assert!(contents.contains(&fs::read(&path).unwrap()));
+    assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+    assert_eq!(write(&dir.join("block/under/a/file"), b"x").status(), 500);
+    assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+    fs::remove_dir_all(&dir).unwrap();
+}
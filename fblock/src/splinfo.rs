@@ -1,50 +1,472 @@
+use crate::chunker::Chunking;
+#[cfg(not(test))]
 use crate::cli::{Commands, ARGS_CMD};
+use crate::codec::{Compression, Encryption, Naming};
+use crate::hash::HashAlgorithm;
 
-use std::fs::File;
-use std::io::{Read, Result};
-use std::path::PathBuf;
+use std::collections::HashSet;
+use std::fs::{File, OpenOptions};
+use std::io::{Error, ErrorKind, Read, Result, Write};
+use std::path::{Path, PathBuf};
 
 use lazy_static::lazy_static;
 use serde::{Deserialize, Serialize};
+use serde_json::Value;
 
+#[cfg(not(test))]
 lazy_static! {
     pub(crate) static ref SPLIT_INFO_JSON_PATH: PathBuf = get_split_info_json_path();
-    pub(crate) static ref SPLIT_INFO_JSON: &'static str =
-        Box::leak(read_split_info_json(&*SPLIT_INFO_JSON_PATH).unwrap().into_boxed_str());
-    pub(crate) static ref SPLIT_INFOS: Vec<SplitInfo<'static>> = {
-        if let Ok(ret) = serde_json::from_str::<Vec<SplitInfo<'static>>>(*SPLIT_INFO_JSON) {
-            ret
-        } else {
-            Vec::<SplitInfo<'static>>::new()
-        }
-    };
 }
 
+// Tests have no arguments to find it from, they take turns with a store of their own
+#[cfg(test)]
+lazy_static! {
+    pub(crate) static ref SPLIT_INFO_JSON_PATH: PathBuf = std::env::temp_dir()
+        .join(format!("fblock-test-{}", std::process::id()))
+        .join(SPLIT_INFO_JSON_NAME);
+    static ref TEST_STORE: std::sync::Mutex<()> = std::sync::Mutex::new(());
+}
+
+/// What the split info JSON is called in the folder of the blocks
+pub(crate) const SPLIT_INFO_JSON_NAME: &str = ".split_info.json";
+
+/// The manifest layout written by this version, older ones are migrated when read.
+/// Version 1 is the bare camelCase array of split infos, which had no version field,
+/// version 2 had no trees.
+pub(crate) const MANIFEST_VERSION: u64 = 3;
+
+/// Where a block's content sits in the original file
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub(crate) struct Chunk {
+    pub(crate) offset: u64,
+    pub(crate) length: u64,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub(crate) struct SplitInfo {
+    pub(crate) file_name: String,
+    /// Hash of the whole file, empty while it is pending
+    pub(crate) file_hash: String,
+    pub(crate) file_size: u64,
+    /// When the file was last modified before being split, in nanoseconds since
+    /// the Unix epoch, unknown for files split before the manifest had a version
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) mtime: Option<u64>,
+    /// Names the blocks and hashes the whole file
+    pub(crate) hash: HashAlgorithm,
+    /// The block size, or the average one for content-defined chunking
+    pub(crate) block_size: u64,
+    pub(crate) chunking: Chunking,
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub(crate) min_block_size: u64,
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub(crate) max_block_size: u64,
+    #[serde(default)]
+    pub(crate) compression: Compression,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) encryption: Option<Encryption>,
+    pub(crate) block_paths: Vec<String>,
+    /// One for each of `block_paths`
+    pub(crate) chunks: Vec<Chunk>,
+    #[serde(default, skip_serializing_if = "is_default")]
+    pub(crate) naming: Naming,
+    /// The names the content of the blocks gives them, one for each of `block_paths`
+    /// when they are named in sequence
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub(crate) block_names: Vec<String>,
+    /// Set while the file is being split, every written block is already in `block_paths`
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub(crate) pending: bool,
+}
+
+/// A snapshot of a directory tree, whose files are split into the same block store
 #[derive(Serialize, Deserialize, Clone, Debug)]
+pub(crate) struct TreeInfo {
+    /// The name of the directory
+    pub(crate) root: String,
+    /// When the snapshot was taken, in seconds since the Unix epoch, unique per root
+    pub(crate) snapshot: u64,
+    /// Permissions of the directory itself
+    pub(crate) mode: u32,
+    /// When the directory itself was last modified, in nanoseconds since the Unix epoch
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) mtime: Option<u64>,
+    /// Parents always come before what they contain
+    pub(crate) entries: Vec<TreeEntry>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub(crate) struct TreeEntry {
+    /// Relative to the root, components separated by `/`
+    pub(crate) path: String,
+    /// Unix permission bits, ignored for symlinks
+    pub(crate) mode: u32,
+    /// When a folder was last modified, files have it in their split info
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) mtime: Option<u64>,
+    pub(crate) kind: EntryKind,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum EntryKind {
+    Dir,
+    File(Box<SplitInfo>),
+    Symlink(String),
+}
+
+impl TreeInfo {
+    pub(crate) fn split_infos(&self) -> impl Iterator<Item = &SplitInfo> {
+        self.entries.iter().filter_map(|entry| match &entry.kind {
+            EntryKind::File(split_info) => Some(split_info.as_ref()),
+            _ => None,
+        })
+    }
+
+    /// Paths end up joined to the restore folder, so they must stay under the root,
+    /// and only ever go through folders restored before them, never through symlinks
+    fn check(&self) -> Result<()> {
+        let corrupt = |reason: String| {
+            Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("Corrupt tree {}: {}", self.root, reason),
+            ))
+        };
+        if !is_plain_file_name(&self.root) {
+            return corrupt(String::from("not a directory name"));
+        }
+        let mut dirs = HashSet::<&str>::new();
+        let mut paths = HashSet::<&str>::new();
+        for entry in &self.entries {
+            if !entry.path.split('/').all(is_plain_file_name) {
+                return corrupt(format!("{} leaves the tree", entry.path));
+            }
+            if let Some((parent, _)) = entry.path.rsplit_once('/') {
+                if !dirs.contains(parent) {
+                    return corrupt(format!("{} comes before its folder", entry.path));
+                }
+            }
+            if !paths.insert(&entry.path) {
+                return corrupt(format!("{} appears twice", entry.path));
+            }
+            match &entry.kind {
+                EntryKind::Dir => {
+                    dirs.insert(&entry.path);
+                }
+                EntryKind::File(split_info) => split_info.check()?,
+                EntryKind::Symlink(_) => {}
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A split info as version 1 manifests hold it, fields were added over time
+#[derive(Deserialize)]
 #[serde(rename_all = "camelCase")]
-pub(crate) struct SplitInfo<'a> {
-    pub(crate) file_name: &'a str,
-    pub(crate) file_sha512sum: &'a str,
-    pub(crate) block_paths: Vec<&'a str>,
+struct SplitInfoV1 {
+    file_name: String,
+    /// Never was a SHA-512 sum
+    file_sha512sum: String,
+    #[serde(default)]
+    hash: HashAlgorithm,
+    #[serde(default)]
+    block_size: u64,
+    #[serde(default)]
+    chunking: Chunking,
+    #[serde(default)]
+    min_block_size: u64,
+    #[serde(default)]
+    max_block_size: u64,
+    #[serde(default)]
+    compression: Compression,
+    #[serde(default)]
+    encryption: Option<Encryption>,
+    block_paths: Vec<String>,
+    #[serde(default)]
+    chunks: Vec<Chunk>,
+    #[serde(default)]
+    pending: bool,
 }
 
-impl<'a> SplitInfo<'a> {
-    pub(crate) fn default() -> Self {
-        SplitInfo { file_name: "", file_sha512sum: "", block_paths: Vec::<&'static str>::new() }
+impl SplitInfoV1 {
+    /// The first manifests did not record where blocks sit, but their blocks were stored
+    /// as they are, so their lengths tell
+    fn migrate(self, block_dir: Option<&Path>) -> Result<SplitInfo> {
+        let mut chunks = self.chunks;
+        if chunks.len() != self.block_paths.len() {
+            let block_dir = block_dir.ok_or_else(|| {
+                Error::new(
+                    ErrorKind::InvalidData,
+                    format!("{} has to be migrated where its blocks are", self.file_name),
+                )
+            })?;
+            chunks.clear();
+            let mut offset = 0;
+            for block_path in &self.block_paths {
+                let length = block_dir.join(block_path).metadata().map_err(|e| {
+                    Error::new(
+                        e.kind(),
+                        format!("Cannot migrate {}, block {}: {}", self.file_name, block_path, e),
+                    )
+                })?;
+                chunks.push(Chunk { offset, length: length.len() });
+                offset += length.len();
+            }
+        }
+        Ok(SplitInfo {
+            file_name: self.file_name,
+            file_hash: if self.pending { String::new() } else { self.file_sha512sum },
+            file_size: chunks.iter().map(|chunk| chunk.length).sum(),
+            mtime: None,
+            hash: self.hash,
+            block_size: self.block_size,
+            chunking: self.chunking,
+            min_block_size: self.min_block_size,
+            max_block_size: self.max_block_size,
+            compression: self.compression,
+            encryption: self.encryption,
+            block_paths: self.block_paths,
+            chunks,
+            naming: Naming::ContentHash,
+            block_names: Vec::<String>::new(),
+            pending: self.pending,
+        })
+    }
+}
+
+/// The split info JSON
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub(crate) struct Manifest {
+    /// The layout the manifest was read from, it is always written as `MANIFEST_VERSION`
+    pub(crate) version: u64,
+    pub(crate) files: Vec<SplitInfo>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub(crate) trees: Vec<TreeInfo>,
+}
+
+impl Default for Manifest {
+    fn default() -> Self {
+        Manifest {
+            version: MANIFEST_VERSION,
+            files: Vec::<SplitInfo>::new(),
+            trees: Vec::<TreeInfo>::new(),
+        }
     }
 }
 
+impl Manifest {
+    /// The manifest at `SPLIT_INFO_JSON_PATH`, an empty one if there is none yet
+    pub(crate) fn load() -> Result<Self> {
+        match read_split_info_json(&SPLIT_INFO_JSON_PATH) {
+            Ok(json) => Self::parse(&json, SPLIT_INFO_JSON_PATH.parent()).map_err(|e| {
+                Error::new(e.kind(), format!("{}: {}", SPLIT_INFO_JSON_PATH.display(), e))
+            }),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(Manifest::default()),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Like `load`, but there has to be a manifest
+    pub(crate) fn load_existing() -> Result<Self> {
+        if !(*SPLIT_INFO_JSON_PATH).exists() {
+            return Err(Error::new(
+                ErrorKind::NotFound,
+                format!("{} does not exist", SPLIT_INFO_JSON_PATH.display()),
+            ));
+        }
+        Self::load()
+    }
+
+    /// Read a manifest of any version, `block_dir` is where its blocks are if they are local.
+    /// Anything that does not parse is an error, never an empty manifest.
+    pub(crate) fn parse(json: &str, block_dir: Option<&Path>) -> Result<Self> {
+        // Older versions created the file empty before the first split
+        if json.trim().is_empty() {
+            return Ok(Manifest::default());
+        }
+        let value = serde_json::from_str::<Value>(json).map_err(invalid_data)?;
+        let manifest = if value.is_array() {
+            let files = serde_json::from_value::<Vec<SplitInfoV1>>(value)
+                .map_err(invalid_data)?
+                .into_iter()
+                .map(|split_info| split_info.migrate(block_dir))
+                .collect::<Result<Vec<_>>>()?;
+            Manifest { version: 1, files, trees: Vec::<TreeInfo>::new() }
+        } else {
+            match value.get("version").and_then(Value::as_u64) {
+                Some(2..=MANIFEST_VERSION) => {
+                    serde_json::from_value::<Manifest>(value).map_err(invalid_data)?
+                }
+                Some(version) => {
+                    return Err(Error::new(
+                        ErrorKind::InvalidData,
+                        format!(
+                            "Unsupported manifest version {}, expected up to {}",
+                            version, MANIFEST_VERSION
+                        ),
+                    ))
+                }
+                None => return Err(Error::new(ErrorKind::InvalidData, "No manifest version")),
+            }
+        };
+        for split_info in &manifest.files {
+            split_info.check()?;
+        }
+        for tree in &manifest.trees {
+            tree.check()?;
+        }
+        Ok(manifest)
+    }
+
+    pub(crate) fn to_json(&self) -> String {
+        let manifest = Manifest { version: MANIFEST_VERSION, ..self.clone() };
+        serde_json::to_string_pretty(&manifest).unwrap()
+    }
+
+    /// Every split file, including those of the trees, which share blocks with the others
+    pub(crate) fn split_infos(&self) -> impl Iterator<Item = &SplitInfo> {
+        self.files.iter().chain(self.trees.iter().flat_map(TreeInfo::split_infos))
+    }
+
+    /// Replace the split info JSON as a whole, so an interruption never leaves it half written
+    pub(crate) fn write(&self) -> Result<()> {
+        let tmp_path = (*SPLIT_INFO_JSON_PATH).with_extension("json.tmp");
+        let mut split_info_json_file = OpenOptions::new()
+            .create(true)
+            .truncate(true)
+            .read(true)
+            .write(true)
+            .open(&tmp_path)?;
+        split_info_json_file.write_all(self.to_json().as_bytes())?;
+        split_info_json_file.flush()?;
+        split_info_json_file.sync_all()?;
+        std::fs::rename(&tmp_path, &*SPLIT_INFO_JSON_PATH)?;
+        Ok(())
+    }
+
+    /// The split infos of the file named `name` and the snapshots of the tree named `name`,
+    /// or all of them, failing if there are none
+    pub(crate) fn named(&self, name: Option<&str>) -> Result<(Vec<&SplitInfo>, Vec<&TreeInfo>)> {
+        let split_infos: Vec<_> = self
+            .files
+            .iter()
+            .filter(|split_info| name.is_none_or(|name| split_info.file_name == name))
+            .collect();
+        let trees: Vec<_> =
+            self.trees.iter().filter(|tree| name.is_none_or(|name| tree.root == name)).collect();
+        if let (Some(name), true) = (name, split_infos.is_empty() && trees.is_empty()) {
+            return Err(Error::new(ErrorKind::NotFound, format!("No split info for {}", name)));
+        }
+        Ok((split_infos, trees))
+    }
+}
+
+impl SplitInfo {
+    /// The path of each block with the name its content has to give it and its length
+    pub(crate) fn blocks(&self) -> impl Iterator<Item = (&str, &str, u64)> {
+        self.block_paths.iter().enumerate().map(|(index, block_path)| {
+            (
+                block_path.as_str(),
+                self.block_names.get(index).unwrap_or(block_path).as_str(),
+                self.chunks.get(index).map_or(0, |chunk| chunk.length),
+            )
+        })
+    }
+
+    /// Blocks have to cover the whole file, one after the other, and names joined to the
+    /// folder of the manifest have to stay in it, though old blocks may be in subfolders
+    fn check(&self) -> Result<()> {
+        let corrupt = |reason: &str| {
+            Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("Corrupt split info for {}: {}", self.file_name, reason),
+            ))
+        };
+        if !is_plain_file_name(&self.file_name) {
+            return corrupt("not a file name");
+        }
+        if !self.block_paths.iter().all(|block_path| block_path.split('/').all(is_plain_file_name))
+        {
+            return corrupt("blocks leave the folder");
+        }
+        if self.chunks.len() != self.block_paths.len() {
+            return corrupt("there are not as many chunks as blocks");
+        }
+        let names = match self.naming {
+            Naming::ContentHash => 0,
+            Naming::Sequential => self.block_paths.len(),
+        };
+        if self.block_names.len() != names {
+            return corrupt("block names do not go with the naming");
+        }
+        let mut offset = 0;
+        for chunk in &self.chunks {
+            if chunk.offset != offset {
+                return corrupt("chunks are not contiguous");
+            }
+            offset += chunk.length;
+        }
+        if !self.pending && offset != self.file_size {
+            return corrupt("chunks do not add up to the file size");
+        }
+        Ok(())
+    }
+}
+
+fn invalid_data(e: serde_json::Error) -> Error {
+    Error::new(ErrorKind::InvalidData, e)
+}
+
+/// Whether `name` is a single path component, so joining it to a folder stays in that folder
+pub(crate) fn is_plain_file_name(name: &str) -> bool {
+    !name.is_empty() && name != "." && name != ".." && !name.contains(['/', '\\'])
+}
+
+fn is_default<T: Default + PartialEq>(value: &T) -> bool {
+    *value == T::default()
+}
+
+fn is_zero(n: &u64) -> bool {
+    *n == 0
+}
+
+#[cfg(not(test))]
 fn get_split_info_json_path() -> PathBuf {
-    let Commands::Split { file_path, .. } = &*ARGS_CMD;
-    split_info_json_path_from(PathBuf::from(file_path).parent().unwrap().to_path_buf())
+    match &*ARGS_CMD {
+        Commands::Split { file_path, to: None, .. } => {
+            let parent = PathBuf::from(file_path).parent().unwrap().to_path_buf();
+            split_info_json_path_from(if parent.as_os_str().is_empty() {
+                PathBuf::from(".")
+            } else {
+                parent
+            })
+        }
+        Commands::Split { to: Some(from), .. }
+        | Commands::Restore { from, .. }
+        | Commands::Rm { from, .. }
+        | Commands::Gc { from }
+        | Commands::Stats { from }
+        | Commands::Verify { from, .. }
+        | Commands::Push { from, .. }
+        | Commands::Pull { to: from, .. }
+        | Commands::Mount { manifest: from, .. }
+        | Commands::Serve { dir: from, .. } => split_info_json_path_from(PathBuf::from(from)),
+    }
 }
 
+#[cfg(not(test))]
 pub(crate) fn split_info_json_path_from(path: PathBuf) -> PathBuf {
-    let sijp = if path.is_dir() { path.join(".split_info.json") } else { path };
-    if !sijp.exists() {
-        File::create(&sijp).unwrap();
+    if path.is_dir() {
+        path.join(SPLIT_INFO_JSON_NAME)
+    } else {
+        path
     }
-    sijp
+}
+
+/// Where a block recorded in the split info JSON is stored
+pub(crate) fn block_file_path(block_path: &str) -> PathBuf {
+    (*SPLIT_INFO_JSON_PATH).parent().unwrap().join(block_path)
 }
 
 pub(crate) fn read_split_info_json(path: &PathBuf) -> Result<String> {
@@ -54,6 +476,19 @@ pub(crate) fn read_split_info_json(path: &PathBuf) -> Result<String> {
     Ok(split_info_json)
 }
 
+/// The folder of `SPLIT_INFO_JSON_PATH`, emptied, and the calling test's alone until the guard
+/// is dropped
+#[cfg(test)]
+pub(crate) fn test_store() -> (std::sync::MutexGuard<'static, ()>, PathBuf) {
+    let guard = TEST_STORE.lock().unwrap_or_else(std::sync::PoisonError::into_inner);
+    let store_dir = (*SPLIT_INFO_JSON_PATH).parent().unwrap().to_path_buf();
+    if store_dir.exists() {
+        std::fs::remove_dir_all(&store_dir).unwrap();
+    }
+    std::fs::create_dir_all(&store_dir).unwrap();
+    (guard, store_dir)
+}
+
 // pub(crate) fn read_split_info(path: &PathBuf) -> Result<Vec<SplitInfo<'_>>> {
 //     let mut ret = Vec::<SplitInfo<'_>>::new();
 //     if let Ok(serde_ret) = serde_json::from_str::<Vec<SplitInfo<'_>>>(&read_split_info_json(path)?)
@@ -64,3 +499,50 @@ pub(crate) fn read_split_info_json(path: &PathBuf) -> Result<String> {
 //     }
 //     Ok(ret)
 // }
+
+#[test]
+fn test_migrate_v1() {
+    let json = r#"[{
+        "fileName": "a.bin",
+        "fileSha512sum": "AAAA",
+        "blockSize": 4,
+        "blockPaths": ["x", "y"],
+        "chunks": [{"offset": 0, "length": 4}, {"offset": 4, "length": 2}]
+    }]"#;
+    let manifest = Manifest::parse(json, None).unwrap();
+    assert_eq!(manifest.version, 1);
+    let split_info = &manifest.files[0];
+    assert_eq!(split_info.file_hash, "AAAA");
+    assert_eq!(split_info.file_size, 6);
+    assert_eq!(split_info.hash, HashAlgorithm::LegacyCity128);
+
+    // Blocks named in standard base64 could end up in subfolders
+    let json = json.replace(r#""x""#, r#""AB/c+D=""#);
+    assert_eq!(Manifest::parse(&json, None).unwrap().files[0].block_paths[0], "AB/c+D=");
+
+    let migrated = Manifest::parse(&manifest.to_json(), None).unwrap();
+    assert_eq!(migrated.version, MANIFEST_VERSION);
+    assert_eq!(migrated.files[0].block_paths, ["x", "y"]);
+}
+
+#[test]
+fn test_corrupt_manifests_are_errors() {
+    assert!(Manifest::parse(" \n", None).unwrap().files.is_empty());
+    for json in [
+        "[{\"fileName\": ",
+        "{\"files\": []}",
+        "{\"version\": 99, \"files\": []}",
+        r#"{"version": 3, "files": [], "trees": [{"root": "a", "snapshot": 1, "mode": 493,
+            "entries": [{"path": "b/../../c", "mode": 420, "kind": "dir"}]}]}"#,
+        r#"[{"fileName": "a.bin", "fileSha512sum": "AAAA", "blockPaths": ["x"]}]"#,
+        r#"[{"fileName": "../a.bin", "fileSha512sum": "AAAA", "blockPaths": [],
+            "chunks": []}]"#,
+        r#"[{"fileName": "a.bin", "fileSha512sum": "AAAA", "blockPaths": ["/etc/passwd"],
+            "chunks": [{"offset": 0, "length": 4}]}]"#,
+        r#"[{"fileName": "a.bin", "fileSha512sum": "AAAA", "blockPaths": ["AB/../../x"],
+            "chunks": [{"offset": 0, "length": 4}]}]"#,
+    ] {
+        let e = Manifest::parse(json, None).unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::InvalidData, "{}", json);
+    }
+}
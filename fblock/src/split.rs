@@ -0,0 +1,580 @@
+use crate::blockopt::*;
+use crate::chunker::{Chunker, Chunking};
+use crate::codec::{read_passphrase, Cipher, Codec, Codecs, Compression, Encryption, Naming};
+use crate::hash::HashAlgorithm;
+use crate::pipeline::{split_blocks, Cuts};
+use crate::splinfo::{
+    block_file_path, EntryKind, Manifest, SplitInfo, TreeEntry, TreeInfo, SPLIT_INFO_JSON_PATH,
+};
+use crate::store::lock_store;
+
+use std::{
+    collections::HashMap,
+    fs::{self, Metadata},
+    io::{Error, ErrorKind, Result},
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// How `split` names, encodes and writes blocks
+pub(crate) struct SplitOptions {
+    pub(crate) block_size: u64,
+    /// Cut into this many parts instead of blocks of `block_size`
+    pub(crate) parts: Option<u64>,
+    pub(crate) naming: Naming,
+    pub(crate) hash: HashAlgorithm,
+    pub(crate) compression: Compression,
+    pub(crate) cipher: Option<Cipher>,
+    pub(crate) threads: usize,
+    pub(crate) resume: bool,
+}
+
+/// Reject what would never finish, like empty blocks or no worker
+fn check_options(options: &SplitOptions) -> Result<()> {
+    if options.threads == 0 {
+        return Err(Error::new(ErrorKind::InvalidInput, "Expected at least one thread"));
+    }
+    if options.block_size == 0 {
+        return Err(empty_blocks());
+    }
+    Ok(())
+}
+
+fn empty_blocks() -> Error {
+    Error::new(ErrorKind::InvalidInput, "Blocks must hold at least one byte")
+}
+
+/// Record the split info JSON with `split_info` as it currently is
+fn journal(manifest: &Manifest, split_info: &SplitInfo) -> Result<()> {
+    let mut journaled = manifest.clone();
+    journaled.files.push(split_info.clone());
+    journaled.write()
+}
+
+/// Split into `block_size` blocks, into content-defined chunks when `chunker` is given,
+/// or into `parts`
+pub(crate) fn split(
+    file_path: &Path,
+    chunker: Option<&Chunker>,
+    options: &SplitOptions,
+) -> Result<()> {
+    check_options(options)?;
+    let _lock = lock_store(false)?;
+    let file_name = file_path.file_name().unwrap().to_string_lossy().to_string();
+    let metadata = file_path.metadata()?;
+    let mtime = mtime_of(&metadata)?;
+    let (cuts, block_size) = match (chunker, options.parts) {
+        (Some(chunker), _) => (Cuts::Cdc(chunker), options.block_size),
+        (None, Some(count)) => {
+            if count == 0 || count > metadata.len() {
+                return Err(Error::new(
+                    ErrorKind::InvalidInput,
+                    format!("{} bytes cannot be cut into {} parts", metadata.len(), count),
+                ));
+            }
+            (Cuts::Parts { size: metadata.len(), count }, metadata.len().div_ceil(count))
+        }
+        (None, None) => (Cuts::Fixed(options.block_size), options.block_size),
+    };
+    let (chunking, min_block_size, max_block_size) = chunking_of(cuts);
+
+    let mut manifest = Manifest::load()?;
+    #[cfg(debug_assertions)]
+    dbg!(&manifest);
+    let sequence = match options.naming {
+        Naming::ContentHash => None,
+        Naming::Sequential => Some(sequence_of(&manifest, &file_name, cuts, &metadata, options)?),
+    };
+    let pending_pos = manifest.files.iter().position(|split_info| {
+        split_info.pending
+            && split_info.file_name == file_name
+            && split_info.naming == options.naming
+            && split_info.hash == options.hash
+            && split_info.compression == options.compression
+            && split_info.encryption.as_ref().map(|encryption| encryption.cipher) == options.cipher
+            && split_info.block_size == block_size
+            && split_info.chunking == chunking
+            && split_info.min_block_size == min_block_size
+            && split_info.max_block_size == max_block_size
+    });
+    let (mut split_info, codec, intact) = match pending_pos {
+        Some(pos) if options.resume => {
+            let mut split_info = manifest.files.remove(pos);
+            // The key has to be the one derived from the salt the first blocks were written with
+            let codec = Codecs::default().of(&split_info)?;
+            // Blocks are recorded only once written, but the disk may not agree after a crash
+            let mut intact = Vec::<String>::new();
+            let mut broken = false;
+            for (block_path, block_name, length) in split_info.blocks() {
+                match check_block(&codec, block_path, block_name, length)? {
+                    BlockState::Intact if !broken => intact.push(block_path.to_string()),
+                    BlockState::Intact | BlockState::Missing => broken = true,
+                    // Its length may pass for that of the block, it has to be written again
+                    BlockState::Corrupt => {
+                        fs::remove_file(block_file_path(block_path))?;
+                        broken = true;
+                    }
+                }
+            }
+            println!("Resuming {} with {} blocks already stored", file_name, intact.len());
+            // The file is read again from the start, blocks that did not change are not rewritten
+            split_info.block_paths.clear();
+            split_info.block_names.clear();
+            split_info.chunks.clear();
+            (split_info, codec, intact)
+        }
+        _ => {
+            let mut split_info = SplitInfo {
+                file_name,
+                hash: options.hash,
+                compression: options.compression,
+                block_size,
+                chunking,
+                min_block_size,
+                max_block_size,
+                naming: options.naming,
+                ..Default::default()
+            };
+            let cipher = match options.cipher {
+                Some(cipher) => {
+                    let (encryption, key) = Encryption::create(cipher, &read_passphrase(true)?)?;
+                    split_info.encryption = Some(encryption);
+                    Some((cipher, key))
+                }
+                None => None,
+            };
+            let codec = Codec::new(options.hash, options.compression, cipher);
+            (split_info, codec, Vec::<String>::new())
+        }
+    };
+    // This split supersedes any interrupted one of the same file
+    manifest.files.retain(|other| !other.pending || other.file_name != split_info.file_name);
+    split_info.file_size = metadata.len();
+    split_info.mtime = mtime;
+    split_info.pending = true;
+
+    let file_hash = split_blocks(
+        file_path,
+        cuts,
+        &codec,
+        sequence.as_ref(),
+        options.threads,
+        &intact,
+        |chunk, block_path, block_name| {
+            split_info.block_paths.push(block_path);
+            if sequence.is_some() {
+                split_info.block_names.push(block_name);
+            }
+            split_info.chunks.push(chunk);
+            journal(&manifest, &split_info)
+        },
+    )?;
+    #[cfg(debug_assertions)]
+    dbg!(&file_hash);
+    check_split_size(&split_info)?;
+    split_info.file_hash = file_hash;
+    manifest.files.retain(|other| {
+        other.file_name != split_info.file_name || other.file_hash != split_info.file_hash
+    });
+    split_info.pending = false;
+    journal(&manifest, &split_info)?;
+    std::fs::remove_file(file_path)?;
+    Ok(())
+}
+
+/// Snapshot every file under `dir_path` into the block store, keeping them, and only split
+/// those whose size or mtime changed since the latest snapshot of the tree
+pub(crate) fn split_tree(
+    dir_path: &Path,
+    chunker: Option<&Chunker>,
+    options: &SplitOptions,
+) -> Result<()> {
+    check_options(options)?;
+    let _lock = lock_store(false)?;
+    if options.resume {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            "Only single files are resumed, trees skip the files already in their last snapshot",
+        ));
+    }
+    if options.parts.is_some() || options.naming != Naming::ContentHash {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            "Files of a tree are only split into blocks named after their content",
+        ));
+    }
+    let dir_path = fs::canonicalize(dir_path)?;
+    let root = dir_path
+        .file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .ok_or_else(|| Error::new(ErrorKind::InvalidInput, "The tree needs a name"))?;
+    // Where blocks go, which are not part of the snapshot
+    let store_dir = fs::canonicalize((*SPLIT_INFO_JSON_PATH).parent().unwrap())?;
+    if store_dir == dir_path {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            format!("Blocks cannot be stored in {} itself, choose another folder with --to", root),
+        ));
+    }
+    let cuts = match chunker {
+        Some(chunker) => Cuts::Cdc(chunker),
+        None => Cuts::Fixed(options.block_size),
+    };
+    let (chunking, min_block_size, max_block_size) = chunking_of(cuts);
+
+    let mut manifest = Manifest::load()?;
+    let previous =
+        manifest.trees.iter().filter(|tree| tree.root == root).max_by_key(|tree| tree.snapshot);
+    // The key of the previous snapshot keeps the blocks of unchanged files valid
+    let encryption = match options.cipher {
+        Some(cipher) => match previous.and_then(|tree| {
+            tree.split_infos()
+                .find_map(|split_info| split_info.encryption.clone())
+                .filter(|encryption| encryption.cipher == cipher)
+        }) {
+            Some(encryption) => {
+                let key = Codecs::default().key(&encryption)?;
+                Some((encryption, key))
+            }
+            None => Some(Encryption::create(cipher, &read_passphrase(true)?)?),
+        },
+        None => None,
+    };
+    let codec = Codec::new(
+        options.hash,
+        options.compression,
+        encryption.as_ref().map(|(encryption, key)| (encryption.cipher, *key)),
+    );
+    let template = SplitInfo {
+        hash: options.hash,
+        compression: options.compression,
+        block_size: options.block_size,
+        chunking,
+        min_block_size,
+        max_block_size,
+        encryption: encryption.map(|(encryption, _)| encryption),
+        ..Default::default()
+    };
+    let mut unchanged = HashMap::<&str, &SplitInfo>::new();
+    for entry in previous.iter().flat_map(|tree| &tree.entries) {
+        match &entry.kind {
+            EntryKind::File(split_info)
+                if split_info.hash == template.hash
+                    && split_info.compression == template.compression
+                    && split_info.encryption == template.encryption
+                    && split_info.block_size == template.block_size
+                    && split_info.chunking == template.chunking
+                    && split_info.min_block_size == template.min_block_size
+                    && split_info.max_block_size == template.max_block_size =>
+            {
+                unchanged.insert(&entry.path, split_info);
+            }
+            _ => {}
+        }
+    }
+
+    let mut walked = Vec::<(String, PathBuf, Metadata)>::new();
+    walk(&dir_path, "", &store_dir, &mut walked)?;
+    let (mut split, mut kept) = (0, 0);
+    let mut entries = Vec::<TreeEntry>::new();
+    for (path, file_path, metadata) in walked {
+        let kind = if metadata.is_dir() {
+            EntryKind::Dir
+        } else if metadata.file_type().is_symlink() {
+            let target = fs::read_link(&file_path)?;
+            EntryKind::Symlink(target.to_str().map(String::from).ok_or_else(|| {
+                Error::new(
+                    ErrorKind::InvalidData,
+                    format!("{} is not valid UTF-8", target.display()),
+                )
+            })?)
+        } else if metadata.is_file() {
+            let mtime = mtime_of(&metadata)?;
+            match unchanged.get(path.as_str()) {
+                Some(split_info)
+                    if split_info.file_size == metadata.len() && split_info.mtime == mtime =>
+                {
+                    kept += 1;
+                    EntryKind::File(Box::new((*split_info).clone()))
+                }
+                _ => {
+                    let split_info = SplitInfo {
+                        file_name: file_path.file_name().unwrap().to_string_lossy().to_string(),
+                        file_size: metadata.len(),
+                        mtime,
+                        ..template.clone()
+                    };
+                    split += 1;
+                    EntryKind::File(Box::new(split_tree_file(
+                        &file_path,
+                        cuts,
+                        &codec,
+                        options.threads,
+                        split_info,
+                    )?))
+                }
+            }
+        } else {
+            println!("Skipping {}, it is neither a file, a folder nor a symlink", path);
+            continue;
+        };
+        let mtime = match kind {
+            EntryKind::Dir => mtime_of(&metadata)?,
+            _ => None,
+        };
+        entries.push(TreeEntry { path, mode: mode_of(&metadata), mtime, kind });
+    }
+
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |duration| duration.as_secs());
+    let snapshot = previous.map_or(now, |tree| now.max(tree.snapshot + 1));
+    let metadata = dir_path.metadata()?;
+    let (mode, mtime) = (mode_of(&metadata), mtime_of(&metadata)?);
+    manifest.trees.push(TreeInfo { root, snapshot, mode, mtime, entries });
+    manifest.write()?;
+    println!(
+        "Snapshot {} of {}: {} files split, {} unchanged",
+        snapshot,
+        dir_path.display(),
+        split,
+        kept
+    );
+    Ok(())
+}
+
+/// Split a file of a tree, which stays where it is
+fn split_tree_file(
+    file_path: &Path,
+    cuts: Cuts,
+    codec: &Codec,
+    threads: usize,
+    mut split_info: SplitInfo,
+) -> Result<SplitInfo> {
+    let file_hash =
+        split_blocks(file_path, cuts, codec, None, threads, &[], |chunk, block_path, _| {
+            split_info.block_paths.push(block_path);
+            split_info.chunks.push(chunk);
+            Ok(())
+        })?;
+    check_split_size(&split_info)?;
+    split_info.file_hash = file_hash;
+    Ok(split_info)
+}
+
+/// Everything under `dir_path` but `store_dir`, by name and folders before what they contain,
+/// with paths relative to the root of the tree
+fn walk(
+    dir_path: &Path,
+    prefix: &str,
+    store_dir: &Path,
+    walked: &mut Vec<(String, PathBuf, Metadata)>,
+) -> Result<()> {
+    let mut children = fs::read_dir(dir_path)?.collect::<Result<Vec<_>>>()?;
+    children.sort_by_key(|child| child.file_name());
+    for child in children {
+        let child_path = child.path();
+        let name = child.file_name().into_string().map_err(|_| {
+            Error::new(
+                ErrorKind::InvalidData,
+                format!("{} is not valid UTF-8", child_path.display()),
+            )
+        })?;
+        let path = if prefix.is_empty() { name } else { format!("{}/{}", prefix, name) };
+        let metadata = child_path.symlink_metadata()?;
+        if metadata.is_dir() {
+            if child_path == store_dir {
+                continue;
+            }
+            walked.push((path.clone(), child_path.clone(), metadata));
+            walk(&child_path, &path, store_dir, walked)?;
+        } else {
+            walked.push((path, child_path, metadata));
+        }
+    }
+    Ok(())
+}
+
+fn chunking_of(cuts: Cuts) -> (Chunking, u64, u64) {
+    match cuts {
+        Cuts::Fixed(_) => (Chunking::Fixed, 0, 0),
+        Cuts::Parts { .. } => (Chunking::Parts, 0, 0),
+        Cuts::Cdc(chunker) => (Chunking::Cdc, chunker.min_size as u64, chunker.max_size as u64),
+    }
+}
+
+/// How the parts of `file_name` are named, they would overwrite those of an earlier split
+/// and are joined with `cat`, so they cannot be compressed or encrypted
+fn sequence_of(
+    manifest: &Manifest,
+    file_name: &str,
+    cuts: Cuts,
+    metadata: &Metadata,
+    options: &SplitOptions,
+) -> Result<Sequence> {
+    if options.compression != Compression::None || options.cipher.is_some() {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            "Parts named in sequence are joined as they are, they cannot be compressed or encrypted",
+        ));
+    }
+    if manifest.files.iter().any(|split_info| {
+        !split_info.pending
+            && split_info.naming == Naming::Sequential
+            && split_info.file_name == file_name
+    }) {
+        return Err(Error::new(
+            ErrorKind::AlreadyExists,
+            format!("{} is already split in sequence, rm it first", file_name),
+        ));
+    }
+    let max_count = match cuts {
+        Cuts::Fixed(block_size) => {
+            metadata.len().checked_div(block_size).ok_or_else(empty_blocks)? + 1
+        }
+        Cuts::Parts { count, .. } => count,
+        Cuts::Cdc(chunker) => metadata.len() / chunker.min_size as u64 + 1,
+    };
+    Ok(Sequence::new(file_name, max_count))
+}
+
+/// In nanoseconds since the Unix epoch
+fn mtime_of(metadata: &Metadata) -> Result<Option<u64>> {
+    Ok(metadata
+        .modified()?
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_nanos() as u64)
+        .ok())
+}
+
+/// Blocks cover what was read, which is not the whole file if it changed meanwhile
+fn check_split_size(split_info: &SplitInfo) -> Result<()> {
+    let split_size = split_info.chunks.last().map_or(0, |chunk| chunk.offset + chunk.length);
+    if split_size != split_info.file_size {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            format!("{} changed while being split", split_info.file_name),
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(unix)]
+fn mode_of(metadata: &Metadata) -> u32 {
+    use std::os::unix::fs::PermissionsExt;
+    metadata.permissions().mode() & 0o7777
+}
+
+#[cfg(not(unix))]
+fn mode_of(metadata: &Metadata) -> u32 {
+    if metadata.permissions().readonly() {
+        0o444
+    } else {
+        0o644
+    }
+}
+
+#[cfg(test)]
+impl SplitOptions {
+    /// Fixed blocks named after their content, on two threads
+    pub(crate) fn with_block_size(block_size: u64) -> Self {
+        SplitOptions {
+            block_size,
+            parts: None,
+            naming: Naming::default(),
+            hash: HashAlgorithm::Blake3,
+            compression: Compression::default(),
+            cipher: None,
+            threads: 2,
+            resume: false,
+        }
+    }
+}
+
+#[test]
+fn test_check_options() {
+    let mut options = SplitOptions::with_block_size(3 * 1024 * 1024);
+    assert!(check_options(&options).is_ok());
+    options.block_size = 0;
+    assert!(check_options(&options).is_err());
+    options.block_size = 1;
+    options.threads = 0;
+    assert!(check_options(&options).is_err());
+}
+
+#[test]
+fn test_resume() {
+    use crate::restore::restore;
+    use crate::splinfo::test_store;
+    use crate::verify::verify;
+
+    let (_store, store_dir) = test_store();
+    let file_path = store_dir.join("image.bin");
+    let bytes: Vec<u8> = (0..10_000u32).map(|i| (i * 7 % 251) as u8).collect();
+    fs::write(&file_path, &bytes).unwrap();
+    let block_names: Vec<_> =
+        bytes.chunks(1000).map(|part_bytes| HashAlgorithm::Blake3.compute(part_bytes)).collect();
+    // The seventh block cannot be written where a folder is
+    fs::create_dir_all(store_dir.join(&block_names[6]).join("x")).unwrap();
+    let mut options = SplitOptions::with_block_size(1000);
+    assert!(split(&file_path, None, &options).is_err());
+    assert!(file_path.exists());
+
+    let manifest = Manifest::load_existing().unwrap();
+    let split_info = &manifest.files[0];
+    assert!(split_info.pending && split_info.file_hash.is_empty());
+    let journaled = split_info.block_paths.len();
+    assert!((1..=6).contains(&journaled));
+    assert_eq!(split_info.block_paths, block_names[..journaled]);
+    assert!(verify(None).is_ok());
+    assert_eq!(restore(None, None).unwrap_err().kind(), ErrorKind::InvalidInput);
+
+    fs::remove_dir_all(store_dir.join(&block_names[6])).unwrap();
+    // A recorded block damaged by the crash, which is as long as it should be
+    fs::write(store_dir.join(&block_names[0]), vec![0u8; 1000]).unwrap();
+    options.resume = true;
+    split(&file_path, None, &options).unwrap();
+    assert!(!file_path.exists());
+    let manifest = Manifest::load_existing().unwrap();
+    assert_eq!(manifest.files.len(), 1);
+    assert!(!manifest.files[0].pending);
+    verify(None).unwrap();
+    restore(None, None).unwrap();
+    assert_eq!(fs::read(&file_path).unwrap(), bytes);
+}
+
+#[test]
+fn test_split_tree_skips_unchanged_files() {
+    use crate::splinfo::test_store;
+
+    let (_store, store_dir) = test_store();
+    let tree_path = store_dir.join("project");
+    fs::create_dir_all(tree_path.join("src")).unwrap();
+    fs::write(tree_path.join("src/keep.txt"), vec![1u8; 3000]).unwrap();
+    fs::write(tree_path.join("change.txt"), vec![2u8; 3000]).unwrap();
+    let options = SplitOptions::with_block_size(1000);
+    split_tree(&tree_path, None, &options).unwrap();
+    let first = Manifest::load_existing().unwrap().trees[0].clone();
+    let split_info_of = |tree: &TreeInfo, path: &str| {
+        tree.entries.iter().find(|entry| entry.path == path).and_then(|entry| match &entry.kind {
+            EntryKind::File(split_info) => Some(split_info.as_ref().clone()),
+            _ => None,
+        })
+    };
+    let kept = split_info_of(&first, "src/keep.txt").unwrap();
+    // Only splitting it again would bring its blocks back
+    for block_path in &kept.block_paths {
+        let _ = fs::remove_file(store_dir.join(block_path));
+    }
+    fs::write(tree_path.join("change.txt"), vec![3u8; 2500]).unwrap();
+    split_tree(&tree_path, None, &options).unwrap();
+
+    let manifest = Manifest::load_existing().unwrap();
+    assert_eq!(manifest.trees.len(), 2);
+    let second = &manifest.trees[1];
+    assert!(second.snapshot > first.snapshot);
+    assert_eq!(split_info_of(second, "src/keep.txt").unwrap().block_paths, kept.block_paths);
+    assert!(!store_dir.join(&kept.block_paths[0]).exists());
+    let changed = split_info_of(second, "change.txt").unwrap();
+    assert_eq!(changed.file_size, 2500);
+    assert!(changed.block_paths.iter().all(|block_path| store_dir.join(block_path).exists()));
+    assert!(tree_path.join("change.txt").exists());
+}
@@ -0,0 +1,207 @@
+use crate::codec::{parse_block_name, Codec};
+use crate::hash::HashAlgorithm;
+use crate::splinfo::{block_file_path, Manifest, SplitInfo, TreeInfo, SPLIT_INFO_JSON_PATH};
+
+use std::{
+    collections::HashMap,
+    fs::{self, File, OpenOptions, TryLockError},
+    io::{Error, ErrorKind, Result},
+};
+
+/// Blocks no split file refers to are checked only up to this decoded length, larger ones are
+/// not taken for ours and kept
+const MAX_UNKNOWN_BLOCK_LEN: u64 = 64 * 1024 * 1024;
+
+/// Held shared by splits and exclusively by what deletes blocks, which would otherwise delete
+/// those a split is writing before they are recorded. The lock goes with the file.
+pub(crate) fn lock_store(exclusive: bool) -> Result<File> {
+    let lock_path = (*SPLIT_INFO_JSON_PATH).with_extension("json.lock");
+    let lock_file = OpenOptions::new().create(true).truncate(false).write(true).open(lock_path)?;
+    if !exclusive {
+        lock_file.lock_shared()?;
+        return Ok(lock_file);
+    }
+    match lock_file.try_lock() {
+        Ok(_) => Ok(lock_file),
+        Err(TryLockError::WouldBlock) => Err(Error::new(
+            ErrorKind::WouldBlock,
+            "A split is in progress, try again once it is done",
+        )),
+        Err(TryLockError::Error(e)) => Err(e),
+    }
+}
+
+/// How many times each block is used across the given split files
+pub(crate) fn refcounts<'a, I>(split_infos: I) -> HashMap<&'a str, usize>
+where
+    I: IntoIterator<Item = &'a SplitInfo>,
+{
+    let mut refcounts = HashMap::<&str, usize>::new();
+    for split_info in split_infos {
+        for block_path in &split_info.block_paths {
+            *refcounts.entry(block_path.as_str()).or_insert(0) += 1;
+        }
+    }
+    refcounts
+}
+
+pub(crate) fn rm(file_name: &str) -> Result<()> {
+    let _lock = lock_store(true)?;
+    let mut manifest = Manifest::load_existing()?;
+    manifest.named(Some(file_name))?;
+    let (removed, kept): (Vec<_>, Vec<_>) =
+        manifest.files.drain(..).partition(|split_info| split_info.file_name == file_name);
+    let (removed_trees, kept_trees): (Vec<_>, Vec<_>) =
+        manifest.trees.drain(..).partition(|tree| tree.root == file_name);
+    manifest.files = kept;
+    manifest.trees = kept_trees;
+    // Forget the file first, an interruption then leaves unused blocks for `gc`
+    manifest.write()?;
+
+    let kept_refcounts = refcounts(manifest.split_infos());
+    let removed_refcounts =
+        refcounts(removed.iter().chain(removed_trees.iter().flat_map(TreeInfo::split_infos)));
+    let mut deleted = 0;
+    for block_path in removed_refcounts.keys() {
+        if kept_refcounts.contains_key(block_path) {
+            continue;
+        }
+        match fs::remove_file(block_file_path(block_path)) {
+            Ok(_) => deleted += 1,
+            Err(e) if e.kind() == ErrorKind::NotFound => {}
+            Err(e) => return Err(e),
+        }
+    }
+    println!("Removed {}, deleted {} unused blocks", file_name, deleted);
+    Ok(())
+}
+
+pub(crate) fn gc() -> Result<()> {
+    let _lock = lock_store(true)?;
+    let manifest = Manifest::load_existing()?;
+    let refcounts = refcounts(manifest.split_infos());
+    let (mut deleted, mut freed, mut encrypted) = (0, 0, 0);
+    for entry in (*SPLIT_INFO_JSON_PATH).parent().unwrap().read_dir()? {
+        let entry = entry?;
+        if !entry.file_type()?.is_file() {
+            continue;
+        }
+        let block_name = entry.file_name().to_string_lossy().to_string();
+        if refcounts.contains_key(block_name.as_str()) {
+            continue;
+        }
+        let (stem, compression, key_id) = match parse_block_name(&block_name) {
+            Some(parsed) => parsed,
+            None => continue,
+        };
+        let candidates = HashAlgorithm::candidates(stem);
+        if candidates.is_empty() {
+            continue;
+        }
+        // Encrypted ones cannot be checked without their key, their name alone is no proof
+        if key_id.is_some() {
+            encrypted += 1;
+            continue;
+        }
+        let stored_bytes = fs::read(entry.path())?;
+        // Only a file named after the hash of its content is one of our blocks
+        if !candidates.iter().any(|hash| {
+            let codec = Codec::new(*hash, compression, None);
+            codec
+                .decode(&stored_bytes, MAX_UNKNOWN_BLOCK_LEN)
+                .is_ok_and(|part_bytes| codec.block_name(&part_bytes) == block_name)
+        }) {
+            continue;
+        }
+        fs::remove_file(entry.path())?;
+        deleted += 1;
+        freed += stored_bytes.len() as u64;
+    }
+    println!("Deleted {} unused blocks, freed {} bytes", deleted, freed);
+    if encrypted > 0 {
+        println!("Kept {} unused encrypted blocks, which cannot be checked to be ours", encrypted);
+    }
+    Ok(())
+}
+
+pub(crate) fn stats() -> Result<()> {
+    let manifest = Manifest::load_existing()?;
+    let split_infos: Vec<_> = manifest.split_infos().collect();
+    let refcounts = refcounts(split_infos.iter().copied());
+    let (mut referenced, mut stored, mut missing) = (0u64, 0u64, 0);
+    for (block_path, refcount) in &refcounts {
+        match block_file_path(block_path).metadata() {
+            Ok(metadata) => {
+                referenced += metadata.len() * *refcount as u64;
+                stored += metadata.len();
+            }
+            Err(e) if e.kind() == ErrorKind::NotFound => missing += 1,
+            Err(e) => return Err(e),
+        }
+    }
+    println!(
+        "{} files, {} blocks, {} unique blocks",
+        split_infos.len(),
+        refcounts.values().sum::<usize>(),
+        refcounts.len()
+    );
+    if !manifest.trees.is_empty() {
+        println!("{} tree snapshots", manifest.trees.len());
+    }
+    println!("{} bytes referenced, {} bytes stored", referenced, stored);
+    if missing > 0 {
+        println!("{} blocks are missing", missing);
+    }
+    if stored > 0 {
+        println!("Deduplication ratio: {:.2}", referenced as f64 / stored as f64);
+    }
+    Ok(())
+}
+
+#[test]
+fn test_shared_blocks_outlive_their_files() {
+    use crate::restore::restore;
+    use crate::splinfo::test_store;
+    use crate::split::{split, SplitOptions};
+
+    let (_store, store_dir) = test_store();
+    let a: Vec<u8> = (0..4096u32).map(|i| (i * 7 % 251) as u8).collect();
+    let mut b = a[..3072].to_vec();
+    b.extend(vec![9u8; 1024]);
+    fs::write(store_dir.join("a.bin"), &a).unwrap();
+    fs::write(store_dir.join("b.bin"), &b).unwrap();
+    let options = SplitOptions::with_block_size(1024);
+    split(&store_dir.join("a.bin"), None, &options).unwrap();
+    split(&store_dir.join("b.bin"), None, &options).unwrap();
+
+    let manifest = Manifest::load_existing().unwrap();
+    let counts = refcounts(manifest.split_infos());
+    let (a_blocks, b_blocks) = (&manifest.files[0].block_paths, &manifest.files[1].block_paths);
+    // Both files end with an empty block
+    assert_eq!(counts.len(), 6);
+    assert!(a_blocks[..3].iter().all(|block_path| counts[block_path.as_str()] == 2));
+    assert_eq!(counts[a_blocks[3].as_str()], 1);
+    stats().unwrap();
+
+    rm("a.bin").unwrap();
+    assert!(!store_dir.join(&a_blocks[3]).exists());
+    assert!(b_blocks.iter().all(|block_path| store_dir.join(block_path).exists()));
+
+    // What is not ours, or cannot be told to be, stays
+    let stray = HashAlgorithm::Blake3.compute(b"stray");
+    fs::write(store_dir.join(&stray), b"stray").unwrap();
+    fs::write(store_dir.join(format!("{}.0123456789abcdef", stray)), b"sealed").unwrap();
+    fs::write(store_dir.join(HashAlgorithm::Blake3.compute(b"x")), b"not x").unwrap();
+    fs::write(store_dir.join("notes.txt"), b"notes").unwrap();
+    let lock = lock_store(false).unwrap();
+    assert_eq!(gc().unwrap_err().kind(), ErrorKind::WouldBlock);
+    drop(lock);
+    gc().unwrap();
+    assert!(!store_dir.join(&stray).exists());
+    assert!(b_blocks.iter().all(|block_path| store_dir.join(block_path).exists()));
+    // With the manifest, its lock and the three files above
+    assert_eq!(fs::read_dir(&store_dir).unwrap().count(), b_blocks.len() + 5);
+
+    restore(Some("b.bin"), None).unwrap();
+    assert_eq!(fs::read(store_dir.join("b.bin")).unwrap(), b);
+}
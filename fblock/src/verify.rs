@@ -0,0 +1,90 @@
+use crate::blockopt::{check_block, BlockState};
+use crate::codec::Codecs;
+use crate::splinfo::{EntryKind, Manifest, SplitInfo};
+
+use std::{
+    collections::HashMap,
+    io::{Error, ErrorKind, Result},
+};
+
+pub(crate) fn verify(name: Option<&str>) -> Result<()> {
+    let (missing, corrupt) = check(name)?;
+    if missing + corrupt > 0 {
+        return Err(Error::new(ErrorKind::InvalidData, "Some blocks are missing or corrupt"));
+    }
+    Ok(())
+}
+
+/// Report every block that is missing or corrupt, and how many are
+fn check(name: Option<&str>) -> Result<(usize, usize)> {
+    let manifest = Manifest::load_existing()?;
+    let (split_infos, trees) = manifest.named(name)?;
+    let mut files: Vec<(String, &SplitInfo)> = split_infos
+        .into_iter()
+        .map(|split_info| (split_info.file_name.clone(), split_info))
+        .collect();
+    for tree in trees {
+        for entry in &tree.entries {
+            if let EntryKind::File(split_info) = &entry.kind {
+                files.push((format!("{}@{}/{}", tree.root, tree.snapshot, entry.path), split_info));
+            }
+        }
+    }
+
+    let (mut blocks, mut missing, mut corrupt) = (0, 0, 0);
+    let mut codecs = Codecs::default();
+    // Snapshots share most of their blocks, each is read once
+    let mut states = HashMap::<&str, BlockState>::new();
+    for (file_name, split_info) in files {
+        let codec = codecs.of(split_info)?;
+        println!("{}{}", file_name, if split_info.pending { " (pending)" } else { "" });
+        for (block_path, block_name, length) in split_info.blocks() {
+            blocks += 1;
+            let state = match states.get(block_path) {
+                Some(state) => *state,
+                None => {
+                    let state = check_block(&codec, block_path, block_name, length)?;
+                    states.insert(block_path, state);
+                    state
+                }
+            };
+            match state {
+                BlockState::Intact => {}
+                BlockState::Missing => {
+                    missing += 1;
+                    println!("  missing: {}", block_path);
+                }
+                BlockState::Corrupt => {
+                    corrupt += 1;
+                    println!("  corrupt: {}", block_path);
+                }
+            }
+        }
+    }
+    println!("{} blocks, {} missing, {} corrupt", blocks, missing, corrupt);
+    Ok((missing, corrupt))
+}
+
+#[test]
+fn test_verify() {
+    use crate::splinfo::test_store;
+    use crate::split::{split, SplitOptions};
+    use std::fs;
+
+    let (_store, store_dir) = test_store();
+    let bytes: Vec<u8> = (0..10_000u32).map(|i| (i * 7 % 251) as u8).collect();
+    fs::write(store_dir.join("a.bin"), &bytes).unwrap();
+    fs::write(store_dir.join("b.bin"), &bytes[..5000]).unwrap();
+    split(&store_dir.join("a.bin"), None, &SplitOptions::with_block_size(1000)).unwrap();
+    split(&store_dir.join("b.bin"), None, &SplitOptions::with_block_size(1000)).unwrap();
+    assert_eq!(check(None).unwrap(), (0, 0));
+    verify(None).unwrap();
+
+    let block_paths = Manifest::load_existing().unwrap().files[0].block_paths.clone();
+    fs::remove_file(store_dir.join(&block_paths[2])).unwrap();
+    fs::write(store_dir.join(&block_paths[7]), vec![0u8; 1000]).unwrap();
+    // b.bin shares the missing block
+    assert_eq!(check(None).unwrap(), (2, 1));
+    assert_eq!(check(Some("a.bin")).unwrap(), (1, 1));
+    assert_eq!(verify(None).unwrap_err().kind(), ErrorKind::InvalidData);
+}
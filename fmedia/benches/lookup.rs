@@ -0,0 +1,60 @@
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use fmedia::{FileExtension, MediaType, MIME_MAPPING};
+
+/// Early, middle and late entries of the mapping, and one that is not there
+const EXTENSIONS: &[&str] = &["3gp", "json", "png", "zmm", "nope"];
+const MEDIA_TYPES: &[&str] = &["video/3gpp", "application/json", "image/jpeg", "x/nope"];
+
+/// The scans the lookups used to be, to compare against
+fn scan_extension(ext: &str) -> Option<&'static str> {
+    MIME_MAPPING.iter().find(|(file_ext, _)| *file_ext == ext).map(|(_, media_ty)| *media_ty)
+}
+
+fn scan_media_type(media_type: &str) -> Vec<&'static str> {
+    MIME_MAPPING
+        .iter()
+        .filter(|(_, media_ty)| *media_ty == media_type)
+        .map(|(file_ext, _)| *file_ext)
+        .collect()
+}
+
+fn by_extension(c: &mut Criterion) {
+    let mut group = c.benchmark_group("media type by extension");
+    group.bench_function("linear scan", |b| {
+        b.iter(|| {
+            for ext in EXTENSIONS {
+                black_box(scan_extension(black_box(ext)));
+            }
+        })
+    });
+    group.bench_function("perfect hash", |b| {
+        b.iter(|| {
+            for ext in EXTENSIONS {
+                black_box(MediaType::from_file_extension(black_box(ext)));
+            }
+        })
+    });
+    group.finish();
+}
+
+fn by_media_type(c: &mut Criterion) {
+    let mut group = c.benchmark_group("extensions by media type");
+    group.bench_function("linear scan", |b| {
+        b.iter(|| {
+            for ty in MEDIA_TYPES {
+                black_box(scan_media_type(black_box(ty)));
+            }
+        })
+    });
+    group.bench_function("perfect hash", |b| {
+        b.iter(|| {
+            for ty in MEDIA_TYPES {
+                black_box(FileExtension::from_media_type(black_box(ty)));
+            }
+        })
+    });
+    group.finish();
+}
+
+criterion_group!(benches, by_extension, by_media_type);
+criterion_main!(benches);
@@ -0,0 +1,55 @@
+use std::env;
+use std::fs::File;
+use std::io::{BufWriter, Result, Write};
+use std::path::Path;
+
+include!("src/data.rs");
+
+#[path = "src/meta.rs"]
+mod meta;
+
+/// Generate perfect-hash maps from [`MIME_MAPPING`] for both lookup directions, the media
+/// types with what [`meta`] knows about them
+fn main() -> Result<()> {
+    println!("cargo:rerun-if-changed=src/data.rs");
+    println!("cargo:rerun-if-changed=src/meta.rs");
+
+    let path = Path::new(&env::var_os("OUT_DIR").unwrap()).join("maps.rs");
+    let mut out = BufWriter::new(File::create(path)?);
+
+    let mut extensions = phf_codegen::Map::new();
+    // Extensions of a media type, in the order of the mapping
+    let mut media_types = Vec::<(&str, Vec<&str>)>::new();
+    for (file_ext, media_ty) in MIME_MAPPING {
+        extensions.entry(*file_ext, &format!("{:?}", media_ty));
+        match media_types.iter_mut().find(|(ty, _)| ty == media_ty) {
+            Some((_, exts)) => exts.push(*file_ext),
+            None => media_types.push((*media_ty, vec![*file_ext])),
+        }
+    }
+    let mut media_type_map = phf_codegen::Map::new();
+    for (media_ty, exts) in &media_types {
+        let info = format!(
+            "crate::MediaTypeInfo {{ extensions: &{:?}, text: {}, charset: {:?}, compressible: {} }}",
+            exts,
+            meta::is_text(media_ty),
+            meta::charset(media_ty),
+            meta::is_compressible(media_ty)
+        );
+        media_type_map.entry(*media_ty, &info);
+    }
+
+    writeln!(
+        out,
+        "/// Media type by file extension\n\
+        pub(crate) static EXTENSIONS: phf::Map<&'static str, &'static str> = {};\n",
+        extensions.build()
+    )?;
+    writeln!(
+        out,
+        "/// File extensions and more by media type\n\
+        pub(crate) static MEDIA_TYPES: phf::Map<&'static str, crate::MediaTypeInfo> = {};",
+        media_type_map.build()
+    )?;
+    Ok(())
+}
@@ -1,21 +1,34 @@
 #![allow(dead_code)]
 
 mod data;
+mod magic;
+mod meta;
+mod mime;
+mod registry;
+mod maps {
+    include!(concat!(env!("OUT_DIR"), "/maps.rs"));
+}
 
-pub use data::MIME_MAPPING;
+pub use data::{COMPOUND_MAPPING, MIME_MAPPING};
+pub use mime::{negotiate, Mime};
+pub use registry::MimeRegistry;
 
-use std::sync::Once;
+use std::io::Read;
+use std::path::Path;
 
 pub struct MediaType;
 
 impl MediaType {
     /// Get the media type from the file suffix
     ///
+    /// The suffix may start with a dot and is not case-sensitive
+    ///
     /// # Examples
     /// ```rust
     /// use fmedia::MediaType;
     ///
     /// assert_eq!("image/png", MediaType::from_file_extension("png").unwrap());
+    /// assert_eq!("image/png", MediaType::from_file_extension(".PNG").unwrap());
     /// assert_eq!(
     ///     "image/jpeg",
     ///     MediaType::from_file_extension("jpeg").unwrap()
@@ -28,22 +41,194 @@ impl MediaType {
     ///     "application/json",
     ///     MediaType::from_file_extension("json").unwrap()
     /// );
+    /// assert_eq!(
+    ///     "application/typescript",
+    ///     MediaType::from_file_extension("d.ts").unwrap()
+    /// );
     /// ```
     pub fn from_file_extension(ext: &str) -> Option<String> {
-        for (file_ext, media_ty) in MIME_MAPPING.iter() {
-            if *file_ext == ext {
-                return Some((*media_ty).to_string());
+        let ext = ext.strip_prefix('.').unwrap_or(ext);
+        Self::lookup(ext).or_else(|| Self::lookup(&ext.to_ascii_lowercase()))
+    }
+
+    /// Get the media type from the name of a file
+    ///
+    /// Compound extensions like `d.ts` are tried before the last one alone
+    ///
+    /// # Examples
+    /// ```rust
+    /// use fmedia::MediaType;
+    ///
+    /// assert_eq!("image/png", MediaType::from_path("static/Logo.PNG").unwrap());
+    /// assert_eq!("application/gzip", MediaType::from_path("archive.tar.gz").unwrap());
+    /// assert_eq!("application/typescript", MediaType::from_path("lib/index.d.ts").unwrap());
+    /// assert_eq!(None, MediaType::from_path(".bashrc"));
+    /// ```
+    pub fn from_path(path: impl AsRef<Path>) -> Option<String> {
+        let exts = extensions_of(path.as_ref());
+        let compound = Self::from_compound_extensions(&exts).next();
+        compound.or_else(|| exts.last().and_then(|ext| Self::lookup(ext)))
+    }
+
+    /// Get every media type the name of a file hints at, the most likely first
+    ///
+    /// After those of compound and last extensions come those of the inner ones, which tell
+    /// what is in a compressed or encoded file
+    ///
+    /// # Examples
+    /// ```rust
+    /// use fmedia::MediaType;
+    ///
+    /// assert_eq!(
+    ///     vec!["application/gzip", "application/x-tar"],
+    ///     MediaType::candidates_from_path("archive.tar.gz")
+    /// );
+    /// assert_eq!(vec!["application/typescript"], MediaType::candidates_from_path("index.d.ts"));
+    /// assert!(MediaType::candidates_from_path("Makefile").is_empty());
+    /// ```
+    pub fn candidates_from_path(path: impl AsRef<Path>) -> Vec<String> {
+        let exts = extensions_of(path.as_ref());
+        let mut candidates = Vec::<String>::new();
+        for media_ty in Self::from_compound_extensions(&exts)
+            .chain(exts.iter().rev().filter_map(|ext| Self::lookup(ext)))
+        {
+            if !candidates.contains(&media_ty) {
+                candidates.push(media_ty);
             }
         }
-        None
+        candidates
+    }
+
+    /// Look a normalised extension up, compound ones included
+    fn lookup(ext: &str) -> Option<String> {
+        builtin(ext).map(str::to_string)
+    }
+
+    /// Media types of the compound extensions at the end of `exts`, the longest first
+    fn from_compound_extensions(exts: &[String]) -> impl Iterator<Item = String> + '_ {
+        (0..exts.len().saturating_sub(1)).filter_map(move |start| {
+            let compound = exts[start..].join(".");
+            COMPOUND_MAPPING
+                .iter()
+                .find(|(file_ext, _)| *file_ext == compound)
+                .map(|(_, media_ty)| (*media_ty).to_string())
+        })
+    }
+
+    /// How many leading bytes of a file are enough to sniff its media type
+    pub const SNIFF_LEN: usize = 4096;
+
+    /// Get the media type from the leading bytes of a file, by their magic number
+    ///
+    /// # Examples
+    /// ```rust
+    /// use fmedia::MediaType;
+    ///
+    /// assert_eq!("image/png", MediaType::from_bytes(b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR").unwrap());
+    /// assert_eq!("application/pdf", MediaType::from_bytes(b"%PDF-1.7\n").unwrap());
+    /// assert_eq!("application/wasm", MediaType::from_bytes(b"\0asm\x01\0\0\0").unwrap());
+    /// assert_eq!("text/html", MediaType::from_bytes(b"\n<!DOCTYPE html><html>").unwrap());
+    /// assert_eq!(None, MediaType::from_bytes(b"plain words"));
+    /// ```
+    pub fn from_bytes(bytes: &[u8]) -> Option<String> {
+        magic::sniff(bytes).map(str::to_string)
     }
+
+    /// Get the media type from the first [`SNIFF_LEN`](Self::SNIFF_LEN) bytes of a reader
+    ///
+    /// # Examples
+    /// ```rust
+    /// use fmedia::MediaType;
+    ///
+    /// let gzip: &[u8] = b"\x1f\x8b\x08\0\0\0\0\0";
+    /// assert_eq!("application/gzip", MediaType::from_reader(gzip).unwrap().unwrap());
+    /// ```
+    pub fn from_reader<R: Read>(reader: R) -> std::io::Result<Option<String>> {
+        let mut head = Vec::with_capacity(Self::SNIFF_LEN);
+        reader.take(Self::SNIFF_LEN as u64).read_to_end(&mut head)?;
+        Ok(Self::from_bytes(&head))
+    }
+
+    /// Get the media type of a file from both its name and its leading bytes
+    ///
+    /// The content wins over an extension that contradicts it, while an extension that says
+    /// more than the content, like `docx` for a ZIP archive, is kept, and so is a text extension
+    /// of text that merely starts like a binary format, as `MZ` does executables. Files nothing is known
+    /// about are `text/plain` if they look like text and `application/octet-stream` otherwise.
+    ///
+    /// # Examples
+    /// ```rust
+    /// use fmedia::MediaType;
+    ///
+    /// assert_eq!("image/png", MediaType::detect("photo.jpg", b"\x89PNG\r\n\x1a\n"));
+    /// assert_eq!(
+    ///     "application/vnd.openxmlformats-officedocument.wordprocessingml.document",
+    ///     MediaType::detect("report.DOCX", b"PK\x03\x04\x14\0\x06\0")
+    /// );
+    /// assert_eq!("application/json", MediaType::detect("data.json", b"{\"a\": 1}"));
+    /// assert_eq!("application/octet-stream", MediaType::detect("notes.txt", b"\0\x01\x02"));
+    /// assert_eq!("text/plain", MediaType::detect("README", b"Hello"));
+    /// assert_eq!("text/plain", MediaType::detect("notes.txt", b"MZ and more notes"));
+    /// assert_eq!("application/x-msdownload", MediaType::detect("app.txt", b"MZ\x90\0\x03\0"));
+    /// assert_eq!("audio/mpeg", MediaType::detect("song.txt", b"ID3\x04\0\0\0\0\x01"));
+    /// ```
+    pub fn detect(path: impl AsRef<Path>, head_bytes: &[u8]) -> String {
+        reconcile(magic::sniff(head_bytes), Self::from_path(path), head_bytes)
+    }
+}
+
+/// The built-in media type of a normalised extension, compound ones included
+fn builtin(ext: &str) -> Option<&'static str> {
+    COMPOUND_MAPPING
+        .iter()
+        .find(|(file_ext, _)| *file_ext == ext)
+        .map(|(_, media_ty)| *media_ty)
+        .or_else(|| maps::EXTENSIONS.get(ext).copied())
+}
+
+/// Settle on a media type from what content and name say
+fn reconcile(sniffed: Option<&str>, by_extension: Option<String>, head_bytes: &[u8]) -> String {
+    match (sniffed, by_extension) {
+        (Some(sniffed), Some(by_extension))
+            if sniffed == by_extension || magic::is_refined_by(sniffed, &by_extension) =>
+        {
+            by_extension
+        }
+        (Some(sniffed), Some(by_extension))
+            if magic::is_weak(sniffed, head_bytes)
+                && meta::is_text(&by_extension)
+                && magic::looks_like_text(head_bytes) =>
+        {
+            by_extension
+        }
+        (Some(sniffed), _) => sniffed.to_string(),
+        (None, Some(by_extension))
+            if !meta::is_text(&by_extension) || magic::looks_like_text(head_bytes) =>
+        {
+            by_extension
+        }
+        (None, None) if magic::looks_like_text(head_bytes) => "text/plain".to_string(),
+        (None, _) => "application/octet-stream".to_string(),
+    }
+}
+
+/// Lowercase extensions of a file name, the last one last; `.bashrc` has none
+fn extensions_of(path: &Path) -> Vec<String> {
+    let name = match path.file_name().and_then(|name| name.to_str()) {
+        Some(name) => name.to_ascii_lowercase(),
+        None => return Vec::new(),
+    };
+    name.trim_start_matches('.')
+        .split('.')
+        .skip(1)
+        .filter(|ext| !ext.is_empty())
+        .map(str::to_string)
+        .collect()
 }
 
 pub struct FileExtension;
 
 impl FileExtension {
-    const ONCE_INIT: Once = Once::new();
-
     /// Get its suffix by file media type
     ///
     /// Return one or more results
@@ -66,21 +251,69 @@ impl FileExtension {
     /// );
     /// assert_eq!(
     ///     vec!["json"],
-    ///     FileExtension::from_media_type("application/json").unwrap()
+    ///     FileExtension::from_media_type("Application/JSON; charset=utf-8").unwrap()
     /// );
     /// ```
-    pub fn from_media_type(media_type: &str) -> Option<Vec<String>> {
-        let mut found = false;
-        let mut ret = Vec::<String>::new();
-        for (file_ext, media_ty) in MIME_MAPPING.iter() {
-            if *media_ty == media_type {
-                Self::ONCE_INIT.call_once(|| found = true);
-                ret.push((*file_ext).to_string());
+    pub fn from_media_type(media_type: &str) -> Option<&'static [&'static str]> {
+        MediaTypeInfo::lookup(media_type).map(|info| info.extensions)
+    }
+}
+
+/// What is known of a media type
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MediaTypeInfo {
+    /// Its file extensions, the most common first
+    pub extensions: &'static [&'static str],
+    /// Whether its values are text
+    pub text: bool,
+    /// The charset to declare when serving it, if it takes one
+    pub charset: Option<&'static str>,
+    /// Whether compressing its values is worth it
+    pub compressible: bool,
+}
+
+impl MediaTypeInfo {
+    /// Get what is known of a media type, parameters aside
+    ///
+    /// Types without file extensions are judged by their names alone
+    ///
+    /// # Examples
+    /// ```rust
+    /// use fmedia::MediaTypeInfo;
+    ///
+    /// let png = MediaTypeInfo::of("image/png");
+    /// assert_eq!((false, None, false), (png.text, png.charset, png.compressible));
+    ///
+    /// let html = MediaTypeInfo::of("text/html; charset=iso-8859-1");
+    /// assert_eq!((true, Some("utf-8"), true), (html.text, html.charset, html.compressible));
+    ///
+    /// let json = MediaTypeInfo::of("application/json");
+    /// assert_eq!((true, None, true), (json.text, json.charset, json.compressible));
+    ///
+    /// let wasm = MediaTypeInfo::of("application/wasm");
+    /// assert_eq!((false, None, true), (wasm.text, wasm.charset, wasm.compressible));
+    /// ```
+    pub fn of(media_type: &str) -> MediaTypeInfo {
+        Self::lookup(media_type).unwrap_or_else(|| {
+            let essence = essence_of(media_type);
+            MediaTypeInfo {
+                extensions: &[],
+                text: meta::is_text(&essence),
+                charset: meta::charset(&essence),
+                compressible: meta::is_compressible(&essence),
             }
-        }
-        match found {
-            true => Some(ret),
-            _ => None,
-        }
+        })
+    }
+
+    fn lookup(media_type: &str) -> Option<MediaTypeInfo> {
+        maps::MEDIA_TYPES
+            .get(media_type)
+            .or_else(|| maps::MEDIA_TYPES.get(essence_of(media_type).as_str()))
+            .copied()
     }
 }
+
+/// The media type without parameters, lowercased
+fn essence_of(media_type: &str) -> String {
+    media_type.split(';').next().unwrap_or_default().trim().to_ascii_lowercase()
+}
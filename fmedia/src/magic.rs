@@ -0,0 +1,212 @@
+//! Content sniffing by magic numbers
+
+/// Leading bytes of a file and the media type they announce, tried in order
+const SIGNATURES: &[(usize, &[u8], &str)] = &[
+    (0, b"\x89PNG\r\n\x1a\n", "image/png"),
+    (0, b"\xff\xd8\xff", "image/jpeg"),
+    (0, b"GIF87a", "image/gif"),
+    (0, b"GIF89a", "image/gif"),
+    (0, b"II*\x00", "image/tiff"),
+    (0, b"MM\x00*", "image/tiff"),
+    (0, b"\x00\x00\x01\x00", "image/x-icon"),
+    (0, b"%PDF-", "application/pdf"),
+    (0, b"{\\rtf", "application/rtf"),
+    (0, b"\x1f\x8b", "application/gzip"),
+    (0, b"\xfd7zXZ\x00", "application/x-xz"),
+    (0, b"\x28\xb5\x2f\xfd", "application/zstd"),
+    (0, b"7z\xbc\xaf\x27\x1c", "application/x-7z-compressed"),
+    (0, b"Rar!\x1a\x07", "application/x-rar-compressed"),
+    (257, b"ustar", "application/x-tar"),
+    (0, b"\x7fELF", "application/x-elf"),
+    (0, b"\xfe\xed\xfa\xce", "application/x-mach-binary"),
+    (0, b"\xfe\xed\xfa\xcf", "application/x-mach-binary"),
+    (0, b"\xce\xfa\xed\xfe", "application/x-mach-binary"),
+    (0, b"\xcf\xfa\xed\xfe", "application/x-mach-binary"),
+    (0, b"\xca\xfe\xba\xbe", "application/java"),
+    (0, b"\x00asm", "application/wasm"),
+    (0, b"SQLite format 3\x00", "application/vnd.sqlite3"),
+    (0, b"OggS", "audio/ogg"),
+    (0, b"fLaC", "audio/flac"),
+    (0, b"wOFF", "font/woff"),
+    (0, b"wOF2", "font/woff2"),
+    (0, b"\x00\x01\x00\x00\x00", "font/ttf"),
+];
+
+/// Magic numbers so short that text may start with them too, tried after all others and giving
+/// way to a text extension when the bytes look like text
+const WEAK_SIGNATURES: &[(&[u8], &str)] = &[
+    (b"%!PS", "application/postscript"),
+    (b"BZh", "application/x-bzip2"),
+    (b"MZ", "application/x-msdownload"),
+    (b"ID3", "audio/mpeg"),
+    (b"\xff\xfb", "audio/mpeg"),
+    (b"\xff\xf3", "audio/mpeg"),
+    (b"\xff\xf2", "audio/mpeg"),
+    (b"MThd", "audio/midi"),
+    (b"OTTO", "font/otf"),
+];
+
+/// The second half of `RIFF` headers
+const RIFF_FORMATS: &[(&[u8], &str)] =
+    &[(b"WEBP", "image/webp"), (b"WAVE", "audio/x-wav"), (b"AVI ", "video/x-msvideo")];
+
+/// ISO base media file brands, every other one is MP4
+const FTYP_BRANDS: &[(&[u8], &str)] = &[
+    (b"qt  ", "video/quicktime"),
+    (b"M4A ", "audio/mp4"),
+    (b"M4B ", "audio/mp4"),
+    (b"3gp", "video/3gpp"),
+    (b"3g2", "video/3gpp2"),
+    (b"heic", "image/heic"),
+    (b"heix", "image/heic"),
+    (b"mif1", "image/heif"),
+    (b"avif", "image/avif"),
+];
+
+/// Files in ZIP archives that tell what kind of archive it is
+const ZIP_ENTRIES: &[(&[u8], &str)] = &[
+    (b"word/", "application/vnd.openxmlformats-officedocument.wordprocessingml.document"),
+    (b"xl/", "application/vnd.openxmlformats-officedocument.spreadsheetml.sheet"),
+    (b"ppt/", "application/vnd.openxmlformats-officedocument.presentationml.presentation"),
+    (b"AndroidManifest.xml", "application/vnd.android.package-archive"),
+    (b"META-INF/MANIFEST.MF", "application/java-archive"),
+];
+
+pub(crate) fn sniff(bytes: &[u8]) -> Option<&'static str> {
+    sniff_strong(bytes).or_else(|| sniff_weak(bytes))
+}
+
+/// Whether `sniffed` is only what a weak magic number at the start of the bytes says
+pub(crate) fn is_weak(sniffed: &str, bytes: &[u8]) -> bool {
+    sniff_strong(bytes).is_none() && sniff_weak(bytes) == Some(sniffed)
+}
+
+fn sniff_weak(bytes: &[u8]) -> Option<&'static str> {
+    WEAK_SIGNATURES
+        .iter()
+        .find(|(magic, _)| bytes.starts_with(magic))
+        .map(|(_, media_type)| *media_type)
+}
+
+fn sniff_strong(bytes: &[u8]) -> Option<&'static str> {
+    for (offset, magic, media_type) in SIGNATURES {
+        if bytes.get(*offset..offset + magic.len()) == Some(*magic) {
+            return Some(media_type);
+        }
+    }
+    // Two letters are not much, but the four bytes reserved after the file size are zero
+    if bytes.starts_with(b"BM") && bytes.get(6..10) == Some(&[0; 4]) {
+        return Some("image/bmp");
+    }
+    if bytes.starts_with(b"RIFF") {
+        return RIFF_FORMATS
+            .iter()
+            .find(|(format, _)| bytes.get(8..12) == Some(*format))
+            .map(|(_, media_type)| *media_type);
+    }
+    if bytes.get(4..8) == Some(b"ftyp") {
+        let brand = bytes.get(8..12).unwrap_or_default();
+        return Some(
+            FTYP_BRANDS
+                .iter()
+                .find(|(prefix, _)| brand.starts_with(prefix))
+                .map_or("video/mp4", |(_, media_type)| *media_type),
+        );
+    }
+    if bytes.starts_with(b"\x1a\x45\xdf\xa3") {
+        return Some(if contains(bytes, b"webm") { "video/webm" } else { "video/x-matroska" });
+    }
+    if bytes.starts_with(b"PK\x03\x04") || bytes.starts_with(b"PK\x05\x06") {
+        return Some(sniff_zip(bytes));
+    }
+    if bytes.starts_with(b"\xef\xbb\xbf") {
+        return Some(sniff_markup(&bytes[3..]).unwrap_or("text/plain"));
+    }
+    if bytes.starts_with(b"\xfe\xff") || bytes.starts_with(b"\xff\xfe") {
+        return Some("text/plain");
+    }
+    sniff_markup(bytes)
+}
+
+/// OpenDocument and EPUB files start with a stored `mimetype` file holding their media type,
+/// the others are told apart by the names of the files they hold
+fn sniff_zip(bytes: &[u8]) -> &'static str {
+    if bytes.get(30..38) == Some(b"mimetype") {
+        let le_u16 = |at: usize| u16::from_le_bytes([bytes[at], bytes[at + 1]]) as usize;
+        let size = le_u16(18);
+        let start = 30 + le_u16(26) + le_u16(28);
+        if let Some(declared) = bytes.get(start..start + size) {
+            if let Some(media_type) = DECLARED_TYPES.iter().find(|t| t.as_bytes() == declared) {
+                return media_type;
+            }
+        }
+    }
+    ZIP_ENTRIES
+        .iter()
+        .find(|(entry, _)| contains(bytes, entry))
+        .map_or("application/zip", |(_, media_type)| *media_type)
+}
+
+/// What the `mimetype` file of archives may say
+const DECLARED_TYPES: &[&str] = &[
+    "application/epub+zip",
+    "application/vnd.oasis.opendocument.text",
+    "application/vnd.oasis.opendocument.spreadsheet",
+    "application/vnd.oasis.opendocument.presentation",
+    "application/vnd.oasis.opendocument.graphics",
+];
+
+/// HTML, SVG and XML, which may start with whitespace and are not case-sensitive everywhere
+fn sniff_markup(bytes: &[u8]) -> Option<&'static str> {
+    let start = bytes.iter().position(|b| !b.is_ascii_whitespace())?;
+    let bytes = &bytes[start..];
+    let starts_with_ignore_case =
+        |prefix: &[u8]| bytes.get(..prefix.len()).is_some_and(|b| b.eq_ignore_ascii_case(prefix));
+    if starts_with_ignore_case(b"<!doctype html") || starts_with_ignore_case(b"<html") {
+        return Some("text/html");
+    }
+    if starts_with_ignore_case(b"<svg") {
+        return Some("image/svg+xml");
+    }
+    if bytes.starts_with(b"<?xml") {
+        return Some(if contains(bytes, b"<svg") { "image/svg+xml" } else { "application/xml" });
+    }
+    None
+}
+
+/// Whether the bytes read so far could be the start of a text file
+pub(crate) fn looks_like_text(bytes: &[u8]) -> bool {
+    let text = match std::str::from_utf8(bytes) {
+        Ok(text) => text,
+        // Only the last character may be cut
+        Err(e) if e.error_len().is_none() => {
+            std::str::from_utf8(&bytes[..e.valid_up_to()]).unwrap_or_default()
+        }
+        Err(_) => return false,
+    };
+    !text.chars().any(|c| c.is_control() && !matches!(c, '\t' | '\n' | '\r' | '\x0c' | '\x1b'))
+}
+
+/// Whether a file sniffed as `sniffed` can be what its extension says it is, which is then the
+/// more precise of the two, like a document that is a ZIP archive or a text file that is JSON
+pub(crate) fn is_refined_by(sniffed: &str, by_extension: &str) -> bool {
+    match sniffed {
+        "application/zip" => {
+            by_extension.ends_with("+zip")
+                || by_extension.starts_with("application/vnd.openxmlformats-officedocument.")
+                || by_extension.starts_with("application/vnd.oasis.opendocument.")
+                || by_extension == "application/java-archive"
+                || by_extension == "application/vnd.android.package-archive"
+        }
+        "text/plain" => crate::meta::is_text(by_extension),
+        "application/xml" => by_extension.ends_with("+xml") || by_extension == "text/xml",
+        "video/mp4" | "audio/ogg" => {
+            by_extension.starts_with("video/") || by_extension.starts_with("audio/")
+        }
+        _ => false,
+    }
+}
+
+fn contains(bytes: &[u8], needle: &[u8]) -> bool {
+    bytes.windows(needle.len()).any(|window| window == needle)
+}
@@ -0,0 +1,66 @@
+//! What is known of media types by their names alone
+//!
+//! Also read by the build script, which records the answers next to the extensions
+
+/// Types outside `text/` whose values are text nonetheless
+const TEXT_TYPES: &[&str] = &[
+    "application/javascript",
+    "application/ecmascript",
+    "application/json",
+    "application/xml",
+    "application/typescript",
+    "application/x-sh",
+    "application/x-csh",
+    "application/x-latex",
+    "application/x-tex",
+    "application/rtf",
+    "application/postscript",
+];
+
+/// Binary types that are not already compressed
+const COMPRESSIBLE_TYPES: &[&str] = &[
+    "application/wasm",
+    "application/x-tar",
+    "application/x-elf",
+    "application/x-msdownload",
+    "application/x-mach-binary",
+    "application/vnd.sqlite3",
+    "application/vnd.ms-fontobject",
+    "application/vnd.ms-excel",
+    "application/msword",
+    "application/vnd.ms-powerpoint",
+    "application/pdf",
+    "application/java",
+    "application/octet-stream",
+    "image/bmp",
+    "image/tiff",
+    "image/x-icon",
+    "audio/x-wav",
+    "font/ttf",
+    "font/otf",
+];
+
+/// Whether values of the media type are text, which can be read as such whatever they hold
+pub(crate) fn is_text(media_type: &str) -> bool {
+    media_type.starts_with("text/")
+        || media_type.ends_with("+xml")
+        || media_type.ends_with("+json")
+        || TEXT_TYPES.contains(&media_type)
+}
+
+/// The charset to declare when serving the media type, if it takes one
+///
+/// JSON is UTF-8 by definition and takes none.
+pub(crate) fn charset(media_type: &str) -> Option<&'static str> {
+    let is_json = media_type == "application/json" || media_type.ends_with("+json");
+    if is_text(media_type) && !is_json {
+        Some("utf-8")
+    } else {
+        None
+    }
+}
+
+/// Whether compressing values of the media type saves enough to be worth it
+pub(crate) fn is_compressible(media_type: &str) -> bool {
+    is_text(media_type) || COMPRESSIBLE_TYPES.contains(&media_type)
+}
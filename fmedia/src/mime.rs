@@ -0,0 +1,258 @@
+//! Media types with their parameters, as found in `Content-Type` and `Accept` headers
+
+use std::fmt::{Display, Formatter};
+use std::io::{Error, ErrorKind, Result};
+use std::str::FromStr;
+
+/// A parsed media type like `image/svg+xml` or `text/html; charset=utf-8`
+///
+/// Type, subtype, suffix and parameter names are lowercased, parameter values are kept as is.
+///
+/// # Examples
+/// ```rust
+/// use fmedia::Mime;
+///
+/// let mime: Mime = "Image/SVG+XML; charset=\"UTF-8\"".parse().unwrap();
+/// assert_eq!("image", mime.ty());
+/// assert_eq!("svg", mime.subtype());
+/// assert_eq!(Some("xml"), mime.suffix());
+/// assert_eq!(Some("UTF-8"), mime.param("Charset"));
+/// assert_eq!("image/svg+xml", mime.essence());
+/// assert_eq!("image/svg+xml; charset=UTF-8", mime.to_string());
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Mime {
+    ty: String,
+    subtype: String,
+    suffix: Option<String>,
+    params: Vec<(String, String)>,
+}
+
+impl Mime {
+    pub fn ty(&self) -> &str {
+        &self.ty
+    }
+
+    /// The subtype without its suffix
+    pub fn subtype(&self) -> &str {
+        &self.subtype
+    }
+
+    /// What follows the `+` of structured syntaxes, like `xml` or `json`
+    pub fn suffix(&self) -> Option<&str> {
+        self.suffix.as_deref()
+    }
+
+    pub fn params(&self) -> &[(String, String)] {
+        &self.params
+    }
+
+    pub fn param(&self, name: &str) -> Option<&str> {
+        self.params
+            .iter()
+            .find(|(param_name, _)| param_name.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// The same media type with the parameter set, replacing one of the same name
+    pub fn with_param(mut self, name: &str, value: &str) -> Self {
+        let name = name.to_ascii_lowercase();
+        self.params.retain(|(param_name, _)| *param_name != name);
+        self.params.push((name, value.to_string()));
+        self
+    }
+
+    /// Type, subtype and suffix, without parameters
+    pub fn essence(&self) -> String {
+        match &self.suffix {
+            Some(suffix) => format!("{}/{}+{}", self.ty, self.subtype, suffix),
+            None => format!("{}/{}", self.ty, self.subtype),
+        }
+    }
+
+    /// Whether this is a range like `image/*` or `*/*` rather than a media type
+    pub fn is_wildcard(&self) -> bool {
+        self.ty == "*" || self.subtype == "*"
+    }
+
+    /// Whether `mime` is within this range, whose parameters, if any, it must all have
+    ///
+    /// # Examples
+    /// ```rust
+    /// use fmedia::Mime;
+    ///
+    /// let png: Mime = "image/png".parse().unwrap();
+    /// assert!("image/*".parse::<Mime>().unwrap().matches(&png));
+    /// assert!("*/*".parse::<Mime>().unwrap().matches(&png));
+    /// assert!(!"text/*".parse::<Mime>().unwrap().matches(&png));
+    ///
+    /// let html: Mime = "text/html; charset=utf-8".parse().unwrap();
+    /// assert!("text/html".parse::<Mime>().unwrap().matches(&html));
+    /// assert!(!"text/html; level=1".parse::<Mime>().unwrap().matches(&html));
+    /// ```
+    pub fn matches(&self, mime: &Mime) -> bool {
+        let essence_matches = match (self.ty.as_str(), self.subtype.as_str()) {
+            ("*", _) => true,
+            (ty, "*") => ty == mime.ty,
+            _ => self.ty == mime.ty && self.subtype == mime.subtype && self.suffix == mime.suffix,
+        };
+        essence_matches
+            && self.params.iter().all(|(name, value)| {
+                mime.param(name).is_some_and(|other| other.eq_ignore_ascii_case(value))
+            })
+    }
+
+    /// How closely this range matches, exact media types with parameters the most
+    fn specificity(&self) -> usize {
+        match (self.ty.as_str(), self.subtype.as_str()) {
+            ("*", _) => 0,
+            (_, "*") => 1,
+            _ => 2 + self.params.len(),
+        }
+    }
+}
+
+impl FromStr for Mime {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let invalid = |why: &str| Error::new(ErrorKind::InvalidInput, format!("{}: {:?}", why, s));
+
+        let (essence, mut rest) = match s.find(';') {
+            Some(index) => (&s[..index], &s[index + 1..]),
+            None => (s, ""),
+        };
+        let (ty, subtype) = essence.trim().split_once('/').ok_or_else(|| invalid("No subtype"))?;
+        if !is_token(ty) || !is_token(subtype) {
+            return Err(invalid("Invalid media type"));
+        }
+        if ty == "*" && subtype != "*" {
+            return Err(invalid("Wildcard type with a subtype"));
+        }
+        let (subtype, suffix) = match subtype.rsplit_once('+') {
+            Some((subtype, suffix)) if !subtype.is_empty() && !suffix.is_empty() => {
+                (subtype, Some(suffix.to_ascii_lowercase()))
+            }
+            _ => (subtype, None),
+        };
+
+        let mut params = Vec::<(String, String)>::new();
+        loop {
+            rest = rest.trim_start_matches(|c: char| c == ';' || c.is_ascii_whitespace());
+            if rest.is_empty() {
+                break;
+            }
+            let (name, after_name) =
+                rest.split_once('=').ok_or_else(|| invalid("Parameter without value"))?;
+            let name = name.trim();
+            if !is_token(name) {
+                return Err(invalid("Invalid parameter name"));
+            }
+            let (value, after_value) = if let Some(quoted) = after_name.strip_prefix('"') {
+                parse_quoted(quoted).ok_or_else(|| invalid("Unterminated quoted string"))?
+            } else {
+                let end = after_name.find(';').unwrap_or(after_name.len());
+                let value = after_name[..end].trim();
+                if !is_token(value) {
+                    return Err(invalid("Invalid parameter value"));
+                }
+                (value.to_string(), &after_name[end..])
+            };
+            params.push((name.to_ascii_lowercase(), value));
+            rest = after_value;
+        }
+
+        Ok(Mime {
+            ty: ty.to_ascii_lowercase(),
+            subtype: subtype.to_ascii_lowercase(),
+            suffix,
+            params,
+        })
+    }
+}
+
+impl Display for Mime {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.essence())?;
+        for (name, value) in &self.params {
+            if is_token(value) {
+                write!(f, "; {}={}", name, value)?;
+            } else {
+                write!(f, "; {}=\"{}\"", name, value.replace('\\', "\\\\").replace('"', "\\\""))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Pick what to send for an `Accept` header out of the `available` media types
+///
+/// Every available media type gets the quality of the most specific range matching it, and the
+/// best one wins, the first of equals. Nothing is acceptable when all have a quality of zero;
+/// an empty header accepts anything. Ranges that do not parse are ignored.
+///
+/// # Examples
+/// ```rust
+/// use fmedia::{negotiate, Mime};
+///
+/// let available: Vec<Mime> =
+///     vec!["text/html".parse().unwrap(), "application/json".parse().unwrap()];
+///
+/// let accept = "application/json, text/html;q=0.9, */*;q=0.1";
+/// assert_eq!("application/json", negotiate(accept, &available).unwrap().essence());
+///
+/// let accept = "text/*;q=0.5, application/json;q=0.2";
+/// assert_eq!("text/html", negotiate(accept, &available).unwrap().essence());
+///
+/// assert_eq!("text/html", negotiate("", &available).unwrap().essence());
+/// assert_eq!(None, negotiate("image/*", &available));
+/// assert_eq!(None, negotiate("*/*, text/html;q=0, application/*;q=0", &available));
+/// ```
+pub fn negotiate<'a>(accept: &str, available: &'a [Mime]) -> Option<&'a Mime> {
+    if accept.trim().is_empty() {
+        return available.first();
+    }
+    let ranges: Vec<(Mime, f32)> = accept
+        .split(',')
+        .filter_map(|range| range.parse::<Mime>().ok())
+        .map(|mut range| {
+            let quality = range.param("q").and_then(|q| q.parse::<f32>().ok()).unwrap_or(1.0);
+            range.params.retain(|(name, _)| name != "q");
+            (range, quality.clamp(0.0, 1.0))
+        })
+        .collect();
+
+    let mut best: Option<(&Mime, f32)> = None;
+    for mime in available {
+        let quality = ranges
+            .iter()
+            .filter(|(range, _)| range.matches(mime))
+            .max_by_key(|(range, _)| range.specificity())
+            .map(|(_, quality)| *quality);
+        if let Some(quality) = quality {
+            if quality > 0.0 && best.is_none_or(|(_, best_quality)| quality > best_quality) {
+                best = Some((mime, quality));
+            }
+        }
+    }
+    best.map(|(mime, _)| mime)
+}
+
+/// RFC 7230 tokens, the characters names and unquoted values are made of
+fn is_token(s: &str) -> bool {
+    !s.is_empty() && s.bytes().all(|b| b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&b))
+}
+
+/// The value of a quoted string whose opening quote is already consumed, and what follows it
+fn parse_quoted(s: &str) -> Option<(String, &str)> {
+    let mut value = String::new();
+    let mut chars = s.char_indices();
+    while let Some((index, c)) = chars.next() {
+        match c {
+            '"' => return Some((value, &s[index + 1..])),
+            '\\' => value.push(chars.next()?.1),
+            _ => value.push(c),
+        }
+    }
+    None
+}
@@ -0,0 +1,757 @@
+//! Media types beyond the built-in table, from `mime.types` and shared-mime-info files
+
+use crate::{builtin, extensions_of, magic, maps, reconcile};
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::{Error, ErrorKind, Result};
+use std::path::Path;
+
+/// The built-in table and whatever was merged into it
+///
+/// Merged mappings take precedence over the built-in table, and the later merged over the
+/// earlier. Within a shared-mime-info file, globs and magic rules of higher weight or priority
+/// win. Globs that are more than an extension, like `Makefile` or `*.tar.*`, are tried before
+/// extensions, and magic rules before the built-in ones.
+///
+/// # Examples
+/// ```rust
+/// use fmedia::MimeRegistry;
+///
+/// let mut registry = MimeRegistry::new();
+/// let mime_types = "# Site types\napplication/x-site-report  srep\nimage/x-png  png\n";
+/// registry.merge_mime_types(mime_types).unwrap();
+/// registry
+///     .merge_shared_mime_info(
+///         r#"<?xml version="1.0"?>
+///         <mime-info xmlns="http://www.freedesktop.org/standards/shared-mime-info">
+///           <mime-type type="text/x-justfile">
+///             <glob pattern="justfile"/>
+///           </mime-type>
+///           <mime-type type="application/x-site-archive">
+///             <glob pattern="*.sar"/>
+///             <magic priority="60"><match type="string" offset="0:4" value="SAR\x01"/></magic>
+///           </mime-type>
+///         </mime-info>"#,
+///     )
+///     .unwrap();
+///
+/// assert_eq!(Some("application/x-site-report"), registry.from_path("q3.SREP"));
+/// assert_eq!(Some("image/x-png"), registry.from_file_extension("png"));
+/// assert_eq!(Some("image/jpeg"), registry.from_file_extension("jpg"));
+/// assert_eq!(Some("text/x-justfile"), registry.from_path("project/Justfile"));
+/// assert_eq!(Some("application/x-site-archive"), registry.sniff(b"..SAR\x01 data"));
+/// assert_eq!("application/x-site-archive", registry.detect("backup.bin", b"SAR\x01"));
+/// assert_eq!(vec!["png"], registry.from_media_type("image/x-png"));
+/// assert!(registry.from_media_type("image/png").is_empty());
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct MimeRegistry {
+    /// Merged extensions, compound ones included, and their media types
+    extensions: BTreeMap<String, String>,
+    /// Merged globs that are more than an extension, the first matching winning
+    globs: Vec<Glob>,
+    /// Merged magic rules, the first matching winning
+    magic: Vec<Magic>,
+}
+
+#[derive(Debug, Clone)]
+struct Glob {
+    pattern: String,
+    media_type: String,
+    case_sensitive: bool,
+}
+
+#[derive(Debug, Clone)]
+struct Magic {
+    priority: u32,
+    media_type: String,
+    matches: Vec<Match>,
+}
+
+/// A shared-mime-info `match`, true when its value is found and, if it has any, one of its
+/// children is true
+#[derive(Debug, Clone, Default)]
+struct Match {
+    start: usize,
+    end: usize,
+    /// `None` for the kinds of match that are not supported, which are never true
+    value: Option<Vec<u8>>,
+    mask: Option<Vec<u8>>,
+    children: Vec<Match>,
+}
+
+impl MimeRegistry {
+    /// A registry with the built-in table only
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Merge a file, in shared-mime-info format if it is XML, in `mime.types` format otherwise
+    pub fn load(&mut self, path: impl AsRef<Path>) -> Result<()> {
+        let path = path.as_ref();
+        let merged = fs::read_to_string(path).and_then(|text| {
+            if text.trim_start().starts_with('<') {
+                self.merge_shared_mime_info(&text)
+            } else {
+                self.merge_mime_types(&text)
+            }
+        });
+        merged.map_err(|e| Error::new(e.kind(), format!("{}: {}", path.display(), e)))
+    }
+
+    /// Merge lines of a media type followed by its extensions, as in Apache's `mime.types`
+    pub fn merge_mime_types(&mut self, text: &str) -> Result<()> {
+        // Nothing is merged unless every line is valid
+        let mut extensions = BTreeMap::<String, String>::new();
+        for (number, line) in text.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or_default();
+            let mut words = line.split_whitespace();
+            let media_type = match words.next() {
+                Some(media_type) => media_type,
+                None => continue,
+            };
+            if !is_media_type(media_type) {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    format!("Line {}: {:?} is not a media type", number + 1, media_type),
+                ));
+            }
+            for ext in words {
+                let ext = ext.trim_start_matches('.').to_ascii_lowercase();
+                extensions.insert(ext, media_type.to_ascii_lowercase());
+            }
+        }
+        self.extensions.append(&mut extensions);
+        Ok(())
+    }
+
+    /// Merge the globs and magic rules of a freedesktop shared-mime-info XML file
+    pub fn merge_shared_mime_info(&mut self, xml: &str) -> Result<()> {
+        let invalid = |msg: String| Error::new(ErrorKind::InvalidData, msg);
+
+        let mut media_type: Option<String> = None;
+        let mut globs = Vec::<(u32, Glob)>::new();
+        let mut magic = Vec::<Magic>::new();
+        // Matches being read, the innermost last
+        let mut open_matches = Vec::<Match>::new();
+
+        for tag in tags(xml)? {
+            match (tag.name, tag.closing) {
+                ("mime-type", false) => {
+                    let ty =
+                        tag.attr("type").ok_or_else(|| invalid("mime-type without type".into()))?;
+                    if !is_media_type(ty) {
+                        return Err(invalid(format!("{:?} is not a media type", ty)));
+                    }
+                    media_type = (!tag.empty).then(|| ty.to_ascii_lowercase());
+                }
+                ("mime-type", true) => media_type = None,
+                ("glob", false) => {
+                    let media_type = media_type
+                        .clone()
+                        .ok_or_else(|| invalid("glob outside mime-type".into()))?;
+                    let pattern = tag
+                        .attr("pattern")
+                        .ok_or_else(|| invalid("glob without pattern".into()))?;
+                    let weight = tag.attr("weight").and_then(parse_number).unwrap_or(50);
+                    let case_sensitive = tag.attr("case-sensitive") == Some("true");
+                    let pattern =
+                        if case_sensitive { pattern.to_string() } else { pattern.to_lowercase() };
+                    globs.push((weight, Glob { pattern, media_type, case_sensitive }));
+                }
+                ("magic", false) => {
+                    let media_type = media_type
+                        .clone()
+                        .ok_or_else(|| invalid("magic outside mime-type".into()))?;
+                    let priority = tag.attr("priority").and_then(parse_number).unwrap_or(50);
+                    magic.push(Magic { priority, media_type, matches: Vec::new() });
+                }
+                ("match", false) => {
+                    if magic.is_empty() {
+                        return Err(invalid("match outside magic".into()));
+                    }
+                    let parsed =
+                        parse_match(&tag).map_err(|msg| invalid(format!("match: {}", msg)))?;
+                    open_matches.push(parsed);
+                    if tag.empty {
+                        close_match(&mut open_matches, &mut magic);
+                    }
+                }
+                ("match", true) => close_match(&mut open_matches, &mut magic),
+                _ => {}
+            }
+        }
+
+        // Lower weights first, for higher ones to override them
+        globs.sort_by_key(|(weight, _)| *weight);
+        let mut complex_globs = Vec::<Glob>::new();
+        for (_, glob) in globs {
+            match glob.pattern.strip_prefix("*.") {
+                Some(ext) if !glob.case_sensitive && !ext.contains(['*', '?', '[']) => {
+                    self.extensions.insert(ext.to_string(), glob.media_type);
+                }
+                _ => complex_globs.insert(0, glob),
+            }
+        }
+        complex_globs.append(&mut self.globs);
+        self.globs = complex_globs;
+
+        // Stable, so the newly merged come first among rules of the same priority
+        magic.append(&mut self.magic);
+        magic.sort_by_key(|rule| std::cmp::Reverse(rule.priority));
+        self.magic = magic;
+        Ok(())
+    }
+
+    /// Get the media type from the file suffix, merged mappings first
+    pub fn from_file_extension(&self, ext: &str) -> Option<&str> {
+        let ext = ext.strip_prefix('.').unwrap_or(ext).to_ascii_lowercase();
+        self.extensions.get(&ext).map(String::as_str).or_else(|| builtin(&ext))
+    }
+
+    /// Get the media type from the name of a file, globs first, then compound extensions
+    /// before the last one alone
+    pub fn from_path(&self, path: impl AsRef<Path>) -> Option<&str> {
+        let path = path.as_ref();
+        if let Some(name) = path.file_name().and_then(|name| name.to_str()) {
+            let lowercase = name.to_lowercase();
+            let glob = self.globs.iter().find(|glob| {
+                glob_matches(&glob.pattern, if glob.case_sensitive { name } else { &lowercase })
+            });
+            if let Some(glob) = glob {
+                return Some(&glob.media_type);
+            }
+        }
+
+        let exts = extensions_of(path);
+        let suffixes: Vec<String> = (0..exts.len()).map(|start| exts[start..].join(".")).collect();
+        suffixes
+            .iter()
+            .find_map(|suffix| self.extensions.get(suffix).map(String::as_str))
+            .or_else(|| suffixes.iter().find_map(|suffix| builtin(suffix)))
+    }
+
+    /// Get the file extensions of a media type, merged ones first
+    pub fn from_media_type(&self, media_type: &str) -> Vec<&str> {
+        let media_type = media_type.to_ascii_lowercase();
+        let merged = self
+            .extensions
+            .iter()
+            .filter(|(_, ty)| **ty == media_type)
+            .map(|(ext, _)| ext.as_str());
+        let builtin = maps::MEDIA_TYPES
+            .get(media_type.as_str())
+            .map_or(&[][..], |info| info.extensions)
+            .iter()
+            .copied()
+            .filter(|ext| !self.extensions.contains_key(*ext));
+        merged.chain(builtin).collect()
+    }
+
+    /// Get the media type from the leading bytes of a file, merged magic rules first
+    pub fn sniff(&self, bytes: &[u8]) -> Option<&str> {
+        self.magic
+            .iter()
+            .find(|rule| rule.matches.iter().any(|m| m.is_found_in(bytes)))
+            .map(|rule| rule.media_type.as_str())
+            .or_else(|| magic::sniff(bytes))
+    }
+
+    /// Get the media type of a file from both its name and its leading bytes, like
+    /// [`MediaType::detect`](crate::MediaType::detect) does
+    pub fn detect(&self, path: impl AsRef<Path>, head_bytes: &[u8]) -> String {
+        reconcile(self.sniff(head_bytes), self.from_path(path).map(str::to_string), head_bytes)
+    }
+}
+
+impl Match {
+    fn is_found_in(&self, bytes: &[u8]) -> bool {
+        let value = match &self.value {
+            Some(value) => value,
+            None => return false,
+        };
+        // Offsets past the bytes sniffed can never match, however far the range goes
+        let end = self.end.min(bytes.len().saturating_sub(value.len()));
+        let found = (self.start..=end).any(|offset| {
+            let window = match offset.checked_add(value.len()).and_then(|to| bytes.get(offset..to))
+            {
+                Some(window) => window,
+                None => return false,
+            };
+            match &self.mask {
+                Some(mask) => window.iter().zip(value).zip(mask).all(|((b, v), m)| b & m == v & m),
+                None => window == value.as_slice(),
+            }
+        });
+        found && (self.children.is_empty() || self.children.iter().any(|m| m.is_found_in(bytes)))
+    }
+}
+
+/// Attach the innermost open match to its parent, or to the rule when it has none
+fn close_match(open_matches: &mut Vec<Match>, magic: &mut [Magic]) {
+    if let Some(closed) = open_matches.pop() {
+        match open_matches.last_mut() {
+            Some(parent) => parent.children.push(closed),
+            None => {
+                if let Some(rule) = magic.last_mut() {
+                    rule.matches.push(closed);
+                }
+            }
+        }
+    }
+}
+
+fn parse_match(tag: &Tag) -> std::result::Result<Match, String> {
+    let offset = tag.attr("offset").ok_or("no offset")?;
+    let (start, end) = match offset.split_once(':') {
+        Some((start, end)) => (start, end),
+        None => (offset, offset),
+    };
+    let start = start.trim().parse::<usize>().map_err(|_| format!("bad offset {:?}", offset))?;
+    let end = end.trim().parse::<usize>().map_err(|_| format!("bad offset {:?}", offset))?;
+    if end < start {
+        return Err(format!("bad offset {:?}", offset));
+    }
+    let raw = tag.attr("value").ok_or("no value")?;
+
+    let number = |width: usize, big_endian: bool| -> std::result::Result<Vec<u8>, String> {
+        let n = parse_number(raw).ok_or_else(|| format!("bad number {:?}", raw))?;
+        let bytes = if big_endian { n.to_be_bytes() } else { n.to_le_bytes() };
+        Ok(if big_endian { bytes[4 - width..].to_vec() } else { bytes[..width].to_vec() })
+    };
+    let host = cfg!(target_endian = "big");
+    let kind = tag.attr("type").unwrap_or("string");
+    let (value, width) = match kind {
+        "string" => (Some(unescape(raw)), None),
+        "byte" => (Some(number(1, true)?), Some((1, true))),
+        "big16" => (Some(number(2, true)?), Some((2, true))),
+        "little16" => (Some(number(2, false)?), Some((2, false))),
+        "host16" => (Some(number(2, host)?), Some((2, host))),
+        "big32" => (Some(number(4, true)?), Some((4, true))),
+        "little32" => (Some(number(4, false)?), Some((4, false))),
+        "host32" => (Some(number(4, host)?), Some((4, host))),
+        _ => (None, None),
+    };
+    let mask = match (tag.attr("mask"), width) {
+        (None, _) => None,
+        (Some(mask), Some((width, big_endian))) => {
+            let n = parse_number(mask).ok_or_else(|| format!("bad mask {:?}", mask))?;
+            let bytes = if big_endian { n.to_be_bytes() } else { n.to_le_bytes() };
+            Some(if big_endian { bytes[4 - width..].to_vec() } else { bytes[..width].to_vec() })
+        }
+        (Some(mask), None) => Some(parse_hex(mask).ok_or_else(|| format!("bad mask {:?}", mask))?),
+    };
+    Ok(Match { start, end, value, mask, children: Vec::new() })
+}
+
+/// Decimal, `0x` hexadecimal and `0` octal numbers, as magic values are written
+fn parse_number(s: &str) -> Option<u32> {
+    let s = s.trim();
+    if let Some(hex) = s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+        u32::from_str_radix(hex, 16).ok()
+    } else if s.len() > 1 && s.starts_with('0') {
+        u32::from_str_radix(&s[1..], 8).ok()
+    } else {
+        s.parse().ok()
+    }
+}
+
+fn parse_hex(s: &str) -> Option<Vec<u8>> {
+    let hex = s.trim().strip_prefix("0x").or_else(|| s.trim().strip_prefix("0X"))?;
+    if hex.len() % 2 != 0 {
+        return None;
+    }
+    (0..hex.len()).step_by(2).map(|i| u8::from_str_radix(&hex[i..i + 2], 16).ok()).collect()
+}
+
+/// The bytes of a string value, with C escapes like `\x89`, `\177` and `\n`
+fn unescape(s: &str) -> Vec<u8> {
+    let bytes = s.as_bytes();
+    let mut value = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] != b'\\' || i + 1 == bytes.len() {
+            value.push(bytes[i]);
+            i += 1;
+            continue;
+        }
+        let digits_of = |from: usize, max: usize, radix: u32| {
+            bytes[from..].iter().take(max).take_while(|b| (**b as char).is_digit(radix)).count()
+        };
+        match bytes[i + 1] {
+            b'x' if digits_of(i + 2, 2, 16) > 0 => {
+                let len = digits_of(i + 2, 2, 16);
+                let digits = std::str::from_utf8(&bytes[i + 2..i + 2 + len]).unwrap_or("0");
+                value.push(u8::from_str_radix(digits, 16).unwrap_or_default());
+                i += 2 + len;
+            }
+            b'0'..=b'7' => {
+                let len = digits_of(i + 1, 3, 8);
+                let digits = std::str::from_utf8(&bytes[i + 1..i + 1 + len]).unwrap_or("0");
+                value.push(u32::from_str_radix(digits, 8).unwrap_or_default() as u8);
+                i += 1 + len;
+            }
+            escaped => {
+                value.push(match escaped {
+                    b'n' => b'\n',
+                    b'r' => b'\r',
+                    b't' => b'\t',
+                    other => other,
+                });
+                i += 2;
+            }
+        }
+    }
+    value
+}
+
+/// Shell-like patterns with `*`, `?` and `[...]`, over the whole name
+fn glob_matches(pattern: &str, name: &str) -> bool {
+    fn matches(pattern: &[char], name: &[char]) -> bool {
+        match pattern.first() {
+            None => name.is_empty(),
+            Some('*') => (0..=name.len()).any(|skip| matches(&pattern[1..], &name[skip..])),
+            Some('?') => !name.is_empty() && matches(&pattern[1..], &name[1..]),
+            Some('[') => match pattern.iter().position(|c| *c == ']') {
+                Some(close) if close > 1 && !name.is_empty() => {
+                    let set = &pattern[1..close];
+                    let (negated, set) = match set.first() {
+                        Some('!') | Some('^') => (true, &set[1..]),
+                        _ => (false, set),
+                    };
+                    let mut found = false;
+                    let mut i = 0;
+                    while i < set.len() {
+                        if i + 2 < set.len() && set[i + 1] == '-' {
+                            found |= (set[i]..=set[i + 2]).contains(&name[0]);
+                            i += 3;
+                        } else {
+                            found |= set[i] == name[0];
+                            i += 1;
+                        }
+                    }
+                    found != negated && matches(&pattern[close + 1..], &name[1..])
+                }
+                _ => name.first() == Some(&'[') && matches(&pattern[1..], &name[1..]),
+            },
+            Some(c) => name.first() == Some(c) && matches(&pattern[1..], &name[1..]),
+        }
+    }
+    let pattern: Vec<char> = pattern.chars().collect();
+    let name: Vec<char> = name.chars().collect();
+    matches(&pattern, &name)
+}
+
+fn is_media_type(s: &str) -> bool {
+    s.split_once('/').is_some_and(|(ty, subtype)| {
+        !ty.is_empty() && !subtype.is_empty() && !s.contains(char::is_whitespace)
+    })
+}
+
+/// An XML element tag, the only part of shared-mime-info files that matters here
+struct Tag<'a> {
+    name: &'a str,
+    attrs: Vec<(&'a str, String)>,
+    closing: bool,
+    /// Whether it closes itself, like `<glob pattern="*.txt"/>`
+    empty: bool,
+}
+
+impl Tag<'_> {
+    fn attr(&self, name: &str) -> Option<&str> {
+        self.attrs.iter().find(|(attr, _)| *attr == name).map(|(_, value)| value.as_str())
+    }
+}
+
+/// The tags of an XML document, skipping text, comments, declarations and CDATA
+fn tags(xml: &str) -> Result<Vec<Tag<'_>>> {
+    let invalid = |msg: &str| Error::new(ErrorKind::InvalidData, msg.to_string());
+    let mut tags = Vec::new();
+    let mut rest = xml;
+    while let Some(start) = rest.find('<') {
+        rest = &rest[start..];
+        let skipped = [("<!--", "-->"), ("<![CDATA[", "]]>"), ("<?", "?>"), ("<!", ">")]
+            .iter()
+            .find(|(open, _)| rest.starts_with(open));
+        if let Some((_, close)) = skipped {
+            let end = rest.find(close).ok_or_else(|| invalid("Unterminated markup"))?;
+            rest = &rest[end + close.len()..];
+            continue;
+        }
+
+        let end = tag_end(rest).ok_or_else(|| invalid("Unterminated tag"))?;
+        let mut inner = &rest[1..end];
+        rest = &rest[end + 1..];
+        let closing = inner.starts_with('/');
+        if closing {
+            inner = &inner[1..];
+        }
+        let empty = inner.ends_with('/');
+        if empty {
+            inner = &inner[..inner.len() - 1];
+        }
+        let name_end = inner.find(|c: char| c.is_whitespace()).unwrap_or(inner.len());
+        let name = &inner[..name_end];
+        if name.is_empty() {
+            return Err(invalid("Tag without name"));
+        }
+
+        let mut attrs = Vec::new();
+        let mut attr_rest = inner[name_end..].trim_start();
+        while !attr_rest.is_empty() {
+            let eq = attr_rest.find('=').ok_or_else(|| invalid("Attribute without value"))?;
+            let attr = attr_rest[..eq].trim();
+            let after = attr_rest[eq + 1..].trim_start();
+            let quote = after.chars().next().filter(|c| *c == '"' || *c == '\'');
+            let quote = quote.ok_or_else(|| invalid("Unquoted attribute value"))?;
+            let close = after[1..].find(quote).ok_or_else(|| invalid("Unterminated attribute"))?;
+            attrs.push((attr, decode_entities(&after[1..1 + close])));
+            attr_rest = after[close + 2..].trim_start();
+        }
+        tags.push(Tag { name, attrs, closing, empty });
+    }
+    Ok(tags)
+}
+
+/// Where the `>` ending the tag at the start of `s` is, skipping those in quoted values
+fn tag_end(s: &str) -> Option<usize> {
+    let mut quote = None;
+    for (index, c) in s.char_indices() {
+        match (quote, c) {
+            (None, '"') | (None, '\'') => quote = Some(c),
+            (Some(q), c) if q == c => quote = None,
+            (None, '>') => return Some(index),
+            _ => {}
+        }
+    }
+    None
+}
+
+fn decode_entities(s: &str) -> String {
+    let mut decoded = String::with_capacity(s.len());
+    let mut rest = s;
+    while let Some(start) = rest.find('&') {
+        decoded.push_str(&rest[..start]);
+        rest = &rest[start..];
+        let end = match rest.find(';') {
+            Some(end) => end,
+            None => break,
+        };
+        let entity = &rest[1..end];
+        let c = match entity {
+            "lt" => Some('<'),
+            "gt" => Some('>'),
+            "amp" => Some('&'),
+            "quot" => Some('"'),
+            "apos" => Some('\''),
+            _ => entity
+                .strip_prefix("#x")
+                .map(|hex| u32::from_str_radix(hex, 16))
+                .or_else(|| entity.strip_prefix('#').map(str::parse))
+                .and_then(|code| code.ok())
+                .and_then(char::from_u32),
+        };
+        match c {
+            Some(c) => {
+                decoded.push(c);
+                rest = &rest[end + 1..];
+            }
+            None => {
+                decoded.push('&');
+                rest = &rest[1..];
+            }
+        }
+    }
+    decoded.push_str(rest);
+    decoded
+}
+
+#[test]
+fn test_magic_offsets_are_bounded_by_the_bytes() {
+    let mut registry = MimeRegistry::new();
+    registry
+        .merge_shared_mime_info(
+            r#"<mime-info>
+              <mime-type type="application/x-far">
+                <magic><match type="string" offset="0:4294967295" value="FAR"/></magic>
+              </mime-type>
+              <mime-type type="application/x-huge">
+                <magic><match type="string" offset="18446744073709551615" value="X"/></magic>
+              </mime-type>
+            </mime-info>"#,
+        )
+        .unwrap();
+    assert_eq!(Some("application/x-far"), registry.sniff(b"....FAR"));
+    assert_eq!(None, registry.sniff(b"....FA"));
+    assert_eq!(None, registry.sniff(b""));
+}
+
+#[test]
+fn test_later_merges_override_earlier_ones() {
+    let mut registry = MimeRegistry::new();
+    registry.merge_mime_types("application/x-first  dat .log\nimage/x-png  PNG\n").unwrap();
+    registry.merge_mime_types("application/x-second  dat\n").unwrap();
+    assert_eq!(Some("application/x-second"), registry.from_file_extension("dat"));
+    assert_eq!(Some("application/x-first"), registry.from_file_extension(".log"));
+    assert_eq!(Some("image/x-png"), registry.from_file_extension("png"));
+    assert_eq!(Some("image/jpeg"), registry.from_file_extension("jpg"));
+
+    registry
+        .merge_shared_mime_info(
+            r#"<mime-info>
+              <mime-type type="application/x-third"><glob pattern="*.dat"/></mime-type>
+            </mime-info>"#,
+        )
+        .unwrap();
+    assert_eq!(Some("application/x-third"), registry.from_path("data/Q3.DAT"));
+    assert_eq!(vec!["log"], registry.from_media_type("application/x-first"));
+}
+
+#[test]
+fn test_higher_glob_weights_win() {
+    for (first, second) in [("40", "80"), ("80", "40")] {
+        let mut registry = MimeRegistry::new();
+        registry
+            .merge_shared_mime_info(&format!(
+                r#"<mime-info>
+                  <mime-type type="application/x-{first}">
+                    <glob pattern="*.dat" weight="{first}"/>
+                    <glob pattern="make*" weight="{first}"/>
+                  </mime-type>
+                  <mime-type type="application/x-{second}">
+                    <glob pattern="*.dat" weight="{second}"/>
+                    <glob pattern="makefile" weight="{second}"/>
+                  </mime-type>
+                </mime-info>"#,
+            ))
+            .unwrap();
+        assert_eq!(Some("application/x-80"), registry.from_path("q3.dat"));
+        assert_eq!(Some("application/x-80"), registry.from_path("Makefile"));
+        let make = format!("application/x-{}", first);
+        assert_eq!(Some(make.as_str()), registry.from_path("make.inc"));
+    }
+}
+
+#[test]
+fn test_case_sensitive_globs() {
+    let mut registry = MimeRegistry::new();
+    registry
+        .merge_shared_mime_info(
+            r#"<mime-info>
+              <mime-type type="text/x-c++src"><glob pattern="*.C" case-sensitive="true"/></mime-type>
+              <mime-type type="text/x-readme"><glob pattern="README*"/></mime-type>
+            </mime-info>"#,
+        )
+        .unwrap();
+    assert_eq!(Some("text/x-c++src"), registry.from_path("main.C"));
+    assert_ne!(Some("text/x-c++src"), registry.from_path("main.c"));
+    assert_eq!(Some("text/x-readme"), registry.from_path("ReadMe.txt"));
+}
+
+#[test]
+fn test_higher_magic_priorities_win() {
+    let rule = |media_type: &str, priority: u32| {
+        format!(
+            r#"<mime-type type="{}">
+              <magic priority="{}"><match type="string" offset="0" value="SAR"/></magic>
+            </mime-type>"#,
+            media_type, priority
+        )
+    };
+    for rules in [
+        [rule("application/x-low", 40), rule("application/x-high", 80)],
+        [rule("application/x-high", 80), rule("application/x-low", 40)],
+    ] {
+        let mut registry = MimeRegistry::new();
+        registry
+            .merge_shared_mime_info(&format!("<mime-info>{}</mime-info>", rules.concat()))
+            .unwrap();
+        assert_eq!(Some("application/x-high"), registry.sniff(b"SAR\x01"));
+    }
+
+    // Among rules of the same priority, the later merged are tried first
+    let mut registry = MimeRegistry::new();
+    let merged = format!("<mime-info>{}</mime-info>", rule("application/x-first", 50));
+    registry.merge_shared_mime_info(&merged).unwrap();
+    let merged = format!("<mime-info>{}</mime-info>", rule("application/x-second", 50));
+    registry.merge_shared_mime_info(&merged).unwrap();
+    assert_eq!(Some("application/x-second"), registry.sniff(b"SAR\x01"));
+}
+
+#[test]
+fn test_nested_and_masked_matches() {
+    let mut registry = MimeRegistry::new();
+    registry
+        .merge_shared_mime_info(
+            r#"<mime-info>
+              <mime-type type="application/x-nested">
+                <magic>
+                  <match type="string" offset="0" value="BOX">
+                    <match type="string" offset="4:8" value="LID"/>
+                    <match type="big16" offset="4" value="0x0102"/>
+                  </match>
+                </magic>
+              </mime-type>
+              <mime-type type="application/x-masked">
+                <magic>
+                  <match type="byte" offset="0" value="0x10" mask="0xf0"/>
+                  <match type="string" offset="1" value="\x01\x80" mask="0x0fff"/>
+                </magic>
+              </mime-type>
+            </mime-info>"#,
+        )
+        .unwrap();
+    assert_eq!(Some("application/x-nested"), registry.sniff(b"BOX ...LID"));
+    assert_eq!(Some("application/x-nested"), registry.sniff(b"BOX \x01\x02"));
+    assert_ne!(Some("application/x-nested"), registry.sniff(b"BOX .....LID"));
+    assert_ne!(Some("application/x-nested"), registry.sniff(b"LID BOX"));
+
+    assert_eq!(Some("application/x-masked"), registry.sniff(b"\x1f"));
+    assert_eq!(Some("application/x-masked"), registry.sniff(b"\x00\xf1\x80"));
+    assert_ne!(Some("application/x-masked"), registry.sniff(b"\x20\xf1\x81"));
+}
+
+#[test]
+fn test_malformed_shared_mime_info() {
+    let with_magic = |matches: &str| {
+        format!(
+            r#"<mime-info><mime-type type="application/x-bad"><magic>{}</magic></mime-type></mime-info>"#,
+            matches
+        )
+    };
+    let malformed = [
+        String::from("<mime-info><mime-type type=\"application/x-bad\""),
+        String::from("<mime-info><!-- unterminated </mime-info>"),
+        String::from("<mime-info><mime-type type=application/x-bad/></mime-info>"),
+        String::from("<mime-info><mime-type/></mime-info>"),
+        String::from("<mime-info><mime-type type=\"not a type\"/></mime-info>"),
+        String::from("<mime-info><glob pattern=\"*.bad\"/></mime-info>"),
+        String::from("<mime-info><magic/></mime-info>"),
+        String::from("<mime-info><mime-type type=\"application/x-bad\"><match offset=\"0\" value=\"B\"/></mime-type></mime-info>"),
+        with_magic(r#"<match offset="4:2" value="B"/>"#),
+        with_magic(r#"<match offset="-1" value="B"/>"#),
+        with_magic(r#"<match offset="0"/>"#),
+        with_magic(r#"<match type="big32" offset="0" value="0xfffffffff"/>"#),
+        with_magic(r#"<match type="string" offset="0" value="B" mask="0xf"/>"#),
+    ];
+    for xml in &malformed {
+        let mut registry = MimeRegistry::new();
+        let e = registry.merge_shared_mime_info(xml).unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::InvalidData, "{}", xml);
+        assert!(registry.magic.is_empty() && registry.globs.is_empty(), "{}", xml);
+    }
+}
+
+#[test]
+fn test_malformed_mime_types_merge_nothing() {
+    let mut registry = MimeRegistry::new();
+    registry.merge_mime_types("application/x-kept  kept\n").unwrap();
+    let e = registry
+        .merge_mime_types("# Site types\napplication/x-lost  lost kept\n\nnot-a-type  bad\n")
+        .unwrap_err();
+    assert_eq!(e.kind(), ErrorKind::InvalidData);
+    assert!(e.to_string().starts_with("Line 4:"), "{}", e);
+    assert_eq!(None, registry.from_file_extension("lost"));
+    assert_eq!(Some("application/x-kept"), registry.from_file_extension("kept"));
+}
@@ -0,0 +1,87 @@
+use std::io::{Error, ErrorKind, Result, Write};
+use std::path::Path;
+
+use qrcode::render::{svg, unicode};
+use qrcode::types::QrError;
+use qrcode::{EcLevel, QrCode, Version};
+
+/// Encode `data` as a version 10 QR code,
+/// falling back to the smallest version that fits when it is too long
+pub fn encode(data: &[u8]) -> std::result::Result<QrCode, QrError> {
+    match QrCode::with_version(data, Version::Normal(10), EcLevel::L) {
+        Err(QrError::DataTooLong) => QrCode::with_error_correction_level(data, EcLevel::L),
+        ret => ret,
+    }
+}
+
+/// Render as SVG, dark red on light yellow, at least 400x400
+pub fn render_svg(code: &QrCode) -> String {
+    code.render()
+        .min_dimensions(400, 400)
+        .dark_color(svg::Color("#800000"))
+        .light_color(svg::Color("#ffff80"))
+        .build()
+}
+
+/// Render as Unicode half blocks, inverted so that it scans on dark terminals
+pub fn render_unicode(code: &QrCode) -> String {
+    code.render::<unicode::Dense1x2>()
+        .dark_color(unicode::Dense1x2::Light)
+        .light_color(unicode::Dense1x2::Dark)
+        .build()
+}
+
+/// Write a rendered SVG to `out_path`, creating its parent folders if needed
+pub fn write_svg(image: &str, out_path: &Path) -> Result<()> {
+    if out_path.file_name().is_none() {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            format!("{} does not name a file", out_path.display()),
+        ));
+    }
+    if let Some(parent) = out_path.parent() {
+        if !parent.as_os_str().is_empty() && !parent.exists() {
+            std::fs::create_dir_all(parent)?;
+        }
+    }
+    let mut svg_file = std::fs::OpenOptions::new()
+        .create(true)
+        .read(true)
+        .write(true)
+        .truncate(true)
+        .open(out_path)?;
+    svg_file.write_all(image.as_bytes())?;
+    svg_file.flush()?;
+    Ok(())
+}
+
+#[test]
+fn test_encode_falls_back_to_a_larger_version() {
+    assert_eq!(encode(b"otpauth://totp/alice").unwrap().version(), Version::Normal(10));
+    let long = vec![b'a'; 400];
+    let code = encode(&long).unwrap();
+    assert!(matches!(code.version(), Version::Normal(v) if v > 10));
+    assert!(matches!(encode(&vec![b'a'; 4000]), Err(QrError::DataTooLong)));
+}
+
+#[test]
+fn test_render() {
+    let code = encode(b"hello").unwrap();
+    let svg = render_svg(&code);
+    assert!(svg.contains("<svg") && svg.contains("#800000") && svg.contains("#ffff80"));
+    let text = render_unicode(&code);
+    // Two rows of modules per line, with the quiet zone around them
+    let width = code.width() + 8;
+    assert_eq!(text.lines().count(), width.div_ceil(2));
+    assert!(text.lines().all(|line| line.chars().count() == width));
+}
+
+#[test]
+fn test_write_svg() {
+    let dir = std::env::temp_dir().join(format!("genqrsvg-test-{}", std::process::id()));
+    let out_path = dir.join("nested").join("code.svg");
+    write_svg("<svg/>", &out_path).unwrap();
+    assert_eq!(std::fs::read_to_string(&out_path).unwrap(), "<svg/>");
+    std::fs::remove_dir_all(&dir).unwrap();
+    assert_eq!(write_svg("<svg/>", Path::new("/")).unwrap_err().kind(), ErrorKind::InvalidInput);
+}
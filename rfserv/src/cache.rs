@@ -0,0 +1,204 @@
+//! Validators and caching policies of the files served, for conditional requests
+
+use crate::cli::ETagSource;
+use crate::header::Header;
+use crate::util::{fmt_http_date, parse_http_date, DEFAULT_BUF_SIZE};
+
+use std::collections::HashMap;
+use std::fs::{File, Metadata};
+use std::io::{Error, ErrorKind, Read, Result};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use lazy_static::lazy_static;
+
+lazy_static! {
+    /// Content hashes by path, kept while the size and modification time stay the same
+    static ref HASHES: Mutex<HashMap<PathBuf, (u64, SystemTime, u64)>> = Mutex::new(HashMap::new());
+}
+
+/// What tells one version of a file from another
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Validators {
+    /// A strong entity tag, quotes included
+    pub(crate) etag: String,
+    /// Seconds since the epoch
+    pub(crate) last_modified: Option<u64>,
+}
+
+impl Validators {
+    pub(crate) fn of(path: &Path, metadata: &Metadata, source: ETagSource) -> Result<Self> {
+        let modified = metadata.modified()?;
+        let since_epoch = modified.duration_since(UNIX_EPOCH).unwrap_or_default();
+        let etag = match source {
+            ETagSource::Metadata => {
+                format!("\"{:x}-{:x}\"", metadata.len(), since_epoch.as_nanos())
+            }
+            ETagSource::Hash => {
+                format!("\"{:016x}\"", content_hash(path, metadata.len(), modified)?)
+            }
+        };
+        // A time yet to come is no promise the file will not change again within that second
+        let last_modified = Some(since_epoch.as_secs()).filter(|_| modified < SystemTime::now());
+        Ok(Validators { etag, last_modified })
+    }
+
+    /// `ETag` and `Last-Modified`
+    pub(crate) fn add_to(&self, header: &mut Header) {
+        header.insert("ETag", &self.etag);
+        if let Some(last_modified) = self.last_modified {
+            header.insert("Last-Modified", &fmt_http_date(last_modified));
+        }
+    }
+
+    /// Whether a GET or HEAD with that header can be answered with `304 Not Modified`, by
+    /// `If-None-Match` or else `If-Modified-Since`
+    pub(crate) fn not_modified(&self, header: &Header) -> bool {
+        if header.contains("If-None-Match") {
+            let tag = opaque(&self.etag);
+            return header.tokens("If-None-Match").any(|t| t == "*" || opaque(t) == tag);
+        }
+        match (header.get("If-Modified-Since").and_then(parse_http_date), self.last_modified) {
+            (Some(since), Some(last_modified)) => last_modified <= since,
+            _ => false,
+        }
+    }
+
+    /// Whether the `If-Range` validator, if any, still describes the file, for its ranges to be
+    /// served rather than all of it
+    pub(crate) fn if_range_holds(&self, if_range: Option<&str>) -> bool {
+        match if_range.map(str::trim) {
+            None => true,
+            // Only strong tags compare, and ours are all strong
+            Some(tag) if tag.starts_with('"') => tag == self.etag,
+            Some(tag) if tag.starts_with("W/") => false,
+            Some(date) => {
+                self.last_modified.is_some() && parse_http_date(date) == self.last_modified
+            }
+        }
+    }
+}
+
+/// The entity tag without its weakness, for weak comparison
+fn opaque(tag: &str) -> &str {
+    tag.strip_prefix("W/").unwrap_or(tag)
+}
+
+/// FNV-1a of the bytes of a file, which are read again only once it changes
+fn content_hash(path: &Path, len: u64, modified: SystemTime) -> Result<u64> {
+    if let Some(&(l, m, hash)) = HASHES.lock().unwrap().get(path) {
+        if (l, m) == (len, modified) {
+            return Ok(hash);
+        }
+    }
+
+    let mut file = File::open(path)?;
+    let mut buf = [0u8; DEFAULT_BUF_SIZE];
+    let mut hash = 0xcbf29ce484222325u64;
+    loop {
+        let n = match file.read(&mut buf) {
+            Ok(0) => break,
+            Ok(n) => n,
+            Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+        for b in &buf[..n] {
+            hash = (hash ^ *b as u64).wrapping_mul(0x100000001b3);
+        }
+    }
+    HASHES.lock().unwrap().insert(path.to_path_buf(), (len, modified, hash));
+    Ok(hash)
+}
+
+/// A `Cache-Control` value for the request paths matching a pattern, as in
+/// `/assets/*=max-age=86400`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct CacheRule {
+    pattern: String,
+    value: String,
+}
+
+impl FromStr for CacheRule {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.split_once('=') {
+            Some((pattern, value)) if !pattern.is_empty() && !value.trim().is_empty() => {
+                Ok(CacheRule { pattern: pattern.to_string(), value: value.trim().to_string() })
+            }
+            _ => Err(Error::new(ErrorKind::InvalidInput, "expected PATTERN=VALUE")),
+        }
+    }
+}
+
+/// The `Cache-Control` of the first rule matching the request path
+pub(crate) fn cache_control<'a>(rules: &'a [CacheRule], path: &str) -> Option<&'a str> {
+    rules
+        .iter()
+        .find(|rule| glob_matches(rule.pattern.as_bytes(), path.as_bytes()))
+        .map(|rule| rule.value.as_str())
+}
+
+/// Whether the text matches the pattern, where `*` stands for any run of characters and `?`
+/// for any one
+fn glob_matches(pattern: &[u8], text: &[u8]) -> bool {
+    match pattern.split_first() {
+        None => text.is_empty(),
+        Some((b'*', rest)) => (0..=text.len()).any(|i| glob_matches(rest, &text[i..])),
+        Some((&p, rest)) => match text.split_first() {
+            Some((&t, text)) => (p == b'?' || p == t) && glob_matches(rest, text),
+            None => false,
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn header(fields: &[(&str, &str)]) -> Header {
+        let mut header = Header::new();
+        for (name, value) in fields {
+            header.add(name, value).unwrap();
+        }
+        header
+    }
+
+    #[test]
+    fn evaluates_conditions() {
+        // Sun, 06 Nov 1994 08:49:37 GMT
+        let v = Validators { etag: "\"abc\"".to_string(), last_modified: Some(784111777) };
+        assert!(!v.not_modified(&header(&[])));
+        assert!(v.not_modified(&header(&[("If-None-Match", "\"x\", W/\"abc\"")])));
+        assert!(v.not_modified(&header(&[("If-None-Match", "*")])));
+        assert!(!v.not_modified(&header(&[
+            ("If-None-Match", "\"x\""),
+            ("If-Modified-Since", "Sun, 06 Nov 1994 08:49:37 GMT"),
+        ])));
+        assert!(v.not_modified(&header(&[("If-Modified-Since", "Sun, 06 Nov 1994 08:49:37 GMT")])));
+        assert!(!v.not_modified(&header(&[("If-Modified-Since", "Sun, 06 Nov 1994 08:49:36 GMT")])));
+        assert!(!v.not_modified(&header(&[("If-Modified-Since", "yesterday")])));
+
+        assert!(v.if_range_holds(None));
+        assert!(v.if_range_holds(Some("\"abc\"")));
+        assert!(!v.if_range_holds(Some("W/\"abc\"")));
+        assert!(v.if_range_holds(Some("Sun, 06 Nov 1994 08:49:37 GMT")));
+        assert!(!v.if_range_holds(Some("Sun, 06 Nov 1994 08:49:38 GMT")));
+    }
+
+    #[test]
+    fn matches_cache_rules() {
+        let rule: CacheRule = "/assets/*.js=max-age=3600".parse().unwrap();
+        assert_eq!(("/assets/*.js", "max-age=3600"), (rule.pattern.as_str(), rule.value.as_str()));
+        assert!("no-store".parse::<CacheRule>().is_err());
+        assert!("*=".parse::<CacheRule>().is_err());
+
+        assert!(glob_matches(b"/assets/*.js", b"/assets/lib/app.js"));
+        assert!(glob_matches(b"*", b"/"));
+        assert!(glob_matches(b"/v?/*", b"/v1/index.html"));
+        assert!(!glob_matches(b"/assets/*.js", b"/assets/app.css"));
+        assert!(!glob_matches(b"/v?/*", b"/v10"));
+    }
+}
@@ -0,0 +1,66 @@
+use std::io::{Error, Result};
+use std::path::PathBuf;
+
+use crate::cache::CacheRule;
+
+use clap::{ArgEnum, Parser};
+use fmedia::MimeRegistry;
+
+/// A static file server for the current directory
+#[derive(Parser, Debug)]
+#[clap(author, version, about, long_about = None)]
+pub(crate) struct Args {
+    /// Media types to add to the built-in ones, from an Apache `mime.types` file or a
+    /// shared-mime-info XML file, the later overriding the earlier
+    #[clap(long, value_name = "FILE")]
+    pub(crate) mime_types: Vec<PathBuf>,
+
+    /// What entity tags are derived from
+    #[clap(long, arg_enum, value_name = "SOURCE", default_value = "metadata")]
+    pub(crate) etag: ETagSource,
+
+    /// The `Cache-Control` of files whose request path matches the pattern, where `*` stands
+    /// for any characters and `?` for one, the first matching rule applying
+    #[clap(long, value_name = "PATTERN=VALUE")]
+    pub(crate) cache_control: Vec<CacheRule>,
+}
+
+#[derive(ArgEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum ETagSource {
+    /// The size and modification time
+    Metadata,
+    /// A hash of the content, read again whenever the metadata changes
+    Hash,
+}
+
+/// What the server runs with, made once from the arguments
+pub(crate) struct Config {
+    /// The built-in media types and those of the `--mime-types` files
+    pub(crate) mime_registry: MimeRegistry,
+    pub(crate) etag: ETagSource,
+    pub(crate) cache_control: Vec<CacheRule>,
+}
+
+impl Config {
+    /// Fails when a `--mime-types` file cannot be loaded
+    pub(crate) fn from_args(args: Args) -> Result<Self> {
+        let mut mime_registry = MimeRegistry::new();
+        for path in &args.mime_types {
+            mime_registry
+                .load(path)
+                .map_err(|e| Error::new(e.kind(), format!("Cannot load media types from {}", e)))?;
+        }
+        Ok(Config { mime_registry, etag: args.etag, cache_control: args.cache_control })
+    }
+}
+
+impl Default for Config {
+    /// The built-in media types only, entity tags from metadata and no `Cache-Control`
+    fn default() -> Self {
+        Config {
+            mime_registry: MimeRegistry::new(),
+            etag: ETagSource::Metadata,
+            cache_control: Vec::new(),
+        }
+    }
+}
@@ -5,31 +5,126 @@
  *
  * @author Liangcheng Juves
  */
-use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
 
+/// Header fields in the order they came, looked up by case-insensitive names and limited in
+/// number and total size
+#[derive(Debug, Clone)]
 pub struct Header {
-    inner: HashMap<String, String>,
+    fields: Vec<(String, String)>,
+    size: usize,
+    max_count: usize,
+    max_size: usize,
 }
 
+/// What [`Header::add`] refuses when it would go over the limits
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HeaderTooLarge;
+
 impl Header {
-    pub fn new(map: HashMap<String, String>) -> Self {
-        Header { inner: map }
+    /// At most 100 fields and 16 KiB of names and values
+    pub fn new() -> Self {
+        Self::with_limits(100, 16 * 1024)
+    }
+
+    pub fn with_limits(max_count: usize, max_size: usize) -> Self {
+        Header { fields: Vec::new(), size: 0, max_count, max_size }
+    }
+
+    pub fn add(&mut self, key: &str, value: &str) -> Result<(), HeaderTooLarge> {
+        let size = self.size + key.len() + value.len();
+        if self.fields.len() == self.max_count || size > self.max_size {
+            return Err(HeaderTooLarge);
+        }
+        self.size = size;
+        self.fields.push((key.to_string(), value.to_string()));
+        Ok(())
+    }
+
+    /// Add a field the server makes itself, which the limits meant for clients do not apply to
+    pub fn insert(&mut self, key: &str, value: &str) {
+        self.size += key.len() + value.len();
+        self.fields.push((key.to_string(), value.to_string()));
+    }
+
+    /// Remove every field of that name
+    pub fn remove(&mut self, key: &str) {
+        self.fields.retain(|(name, _)| !name.eq_ignore_ascii_case(key));
+        self.size = self.fields.iter().map(|(name, value)| name.len() + value.len()).sum();
+    }
+
+    /// The value of the first field of that name
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.fields
+            .iter()
+            .find(|(name, _)| name.eq_ignore_ascii_case(key))
+            .map(|(_, value)| value.as_str())
+    }
+
+    pub fn get_all<'a>(&'a self, key: &'a str) -> impl Iterator<Item = &'a str> + 'a {
+        self.fields
+            .iter()
+            .filter(move |(name, _)| name.eq_ignore_ascii_case(key))
+            .map(|(_, value)| value.as_str())
+    }
+
+    pub fn contains(&self, key: &str) -> bool {
+        self.get(key).is_some()
+    }
+
+    /// The comma-separated elements of every field of that name, like the codings of
+    /// `Transfer-Encoding` or the options of `Connection`
+    pub fn tokens<'a>(&'a self, key: &'a str) -> impl Iterator<Item = &'a str> + 'a {
+        self.get_all(key)
+            .flat_map(|value| value.split(','))
+            .map(str::trim)
+            .filter(|t| !t.is_empty())
+    }
+
+    /// Whether a comma-separated field has the token, which is not case-sensitive
+    pub fn has_token(&self, key: &str, token: &str) -> bool {
+        self.tokens(key).any(|t| t.eq_ignore_ascii_case(token))
+    }
+
+    pub fn len(&self) -> usize {
+        self.fields.len()
     }
 
-    pub fn add(&mut self, key: String, value: String) {
-        self.inner.insert(key, value);
+    pub fn is_empty(&self) -> bool {
+        self.fields.is_empty()
     }
 
-    pub fn remove(&mut self, key: String) {
-        self.inner.remove(&key);
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.fields.iter().map(|(name, value)| (name.as_str(), value.as_str()))
     }
+}
 
-    pub fn get(&mut self, key: String) -> String {
-        self.inner.get(&key).unwrap().to_string()
+impl Default for Header {
+    fn default() -> Self {
+        Self::new()
     }
+}
 
-    // pub fn parse(bytes: &[u8]) -> Self {
-    //     let mut map = HashMap::<String, String>::new();
-    //     Header { inner: map }
-    // }
+impl Display for Header {
+    /// As sent on the wire, every field followed by CRLF
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        for (name, value) in &self.fields {
+            write!(f, "{}: {}\r\n", name, value)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn inserts_past_the_limits() {
+        let mut header = Header::with_limits(1, 8);
+        header.add("Server", "rf").unwrap();
+        assert_eq!(Err(HeaderTooLarge), header.add("Content-Length", "0"));
+        header.insert("Content-Length", "0");
+        assert_eq!("Server: rf\r\nContent-Length: 0\r\n", header.to_string());
+    }
 }
@@ -1,65 +1,162 @@
+mod cache;
+mod cli;
 mod header;
 mod index_router;
 mod model;
+mod range;
 mod request;
 mod util;
 
+use crate::cache::{cache_control, Validators};
+use crate::cli::{Args, Config};
+use crate::header::Header;
 use crate::index_router::*;
 use crate::model::*;
-use crate::request::{Request, RequestMethod};
+use crate::range::{parse_ranges, Multipart, Ranges};
+use crate::request::{HttpError, Request, RequestMethod, RequestParser, Version};
 use crate::util::*;
 
-use std::fs::File;
+use std::fs::{File, Metadata};
 
-use std::io::{Result, Write};
-// #[cfg(any(target_os = "linux", target_os = "l4re"))]
-// use std::net::Shutdown;
-use std::net::{TcpListener, TcpStream};
+use std::io::{BufWriter, ErrorKind, Read, Result, Seek, SeekFrom, Write};
+use std::net::{Shutdown, TcpListener, TcpStream};
+use std::path::PathBuf;
+use std::process::exit;
+use std::sync::Arc;
 use std::thread::spawn;
-use std::time::UNIX_EPOCH;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use clap::Parser;
 
 fn main() -> Result<()> {
-    init_serv("0.0.0.0:9999")?;
+    let config = Config::from_args(Args::parse()).unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        exit(1);
+    });
+    init_serv("0.0.0.0:9999", Arc::new(config))?;
     Ok(())
 }
 
-fn init_serv(addr: &str) -> Result<()> {
+fn init_serv(addr: &str, config: Arc<Config>) -> Result<()> {
     let listener = TcpListener::bind(addr)?;
     println!("Listening on http://{}", addr);
     println!();
 
     for stream in listener.incoming() {
-        spawn(move || handle_conn(stream?));
+        let config = Arc::clone(&config);
+        spawn(move || handle_conn(stream?, &config));
     }
 
     Ok(())
 }
 
-fn handle_conn(mut stream: TcpStream) -> Result<()> {
-    let request_lines = read_request_lines(&stream)?;
-    let request_lines_string = String::from_utf8(request_lines).unwrap();
-    println!("{}", request_lines_string);
+/// How long a connection is kept waiting for its next request
+const IDLE_TIMEOUT: Duration = Duration::from_secs(5);
+/// How many requests a connection serves before it is closed
+const MAX_REQUESTS_PER_CONN: usize = 100;
+/// How long a client that stopped reading its response keeps the connection
+const WRITE_TIMEOUT: Duration = Duration::from_secs(30);
+
+fn handle_conn(stream: TcpStream, config: &Config) -> Result<()> {
+    stream.set_read_timeout(Some(IDLE_TIMEOUT))?;
+    stream.set_write_timeout(Some(WRITE_TIMEOUT))?;
+    let mut parser = RequestParser::new();
 
-    let first_line = request_lines_string.split(&LF.to_string()).collect::<Vec<_>>()[0];
+    for served in 1..=MAX_REQUESTS_PER_CONN {
+        let request = match parser.read_request(&mut &stream) {
+            Ok(Some(request)) => request,
+            Ok(None) => break,
+            Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {
+                if !parser.is_idle() {
+                    write_error_response(&mut &stream, 408, "Request Timeout", closing_header())?;
+                }
+                break;
+            }
+            Err(e) => {
+                if let Some(error) = HttpError::of(&e) {
+                    let (status, reason) = (error.status(), error.reason());
+                    write_error_response(&mut &stream, status, reason, closing_header())?;
+                    break;
+                }
+                return Err(e);
+            }
+        };
+        println!("{} {} {}", request.method, request.target, request.version);
+        println!("{}", request.headers);
+
+        let keep_alive = served < MAX_REQUESTS_PER_CONN && wants_keep_alive(&request);
+        let connection = if keep_alive {
+            let mut header = Header::new();
+            header.insert("Connection", "keep-alive");
+            let keep_alive = format!(
+                "timeout={}, max={}",
+                IDLE_TIMEOUT.as_secs(),
+                MAX_REQUESTS_PER_CONN - served
+            );
+            header.insert("Keep-Alive", &keep_alive);
+            header
+        } else {
+            closing_header()
+        };
+        let mut out = Tracked::new(BufWriter::new(&stream));
+        if let Err(e) = respond(&mut out, &request, &connection, config) {
+            // Half a response cannot be taken back, only the connection can be dropped
+            if out.has_written() {
+                return Err(e);
+            }
+            eprintln!("{} {}: {}", request.method, request.target, e);
+            let (status, reason) = match e.kind() {
+                ErrorKind::PermissionDenied => (403, "Forbidden"),
+                _ => (500, "Internal Server Error"),
+            };
+            write_error_response(&mut out, status, reason, connection.clone())?;
+        }
+        out.flush()?;
+        if !keep_alive {
+            break;
+        }
+    }
+
+    // Let the client read the last response to its end before the connection goes
+    let _ = stream.shutdown(Shutdown::Write);
+    Ok(())
+}
+
+/// HTTP/1.1 connections persist unless closed, HTTP/1.0 ones only when asked to
+fn wants_keep_alive(request: &Request) -> bool {
+    match request.version {
+        Version::Http11 => !request.headers.has_token("Connection", "close"),
+        Version::Http10 => request.headers.has_token("Connection", "keep-alive"),
+    }
+}
 
-    let first_line_infos: Vec<_> = first_line.split(&SP.to_string()).collect();
-    let request = Request::new(
-        first_line_infos[1].to_string(),
-        match first_line_infos[0] {
-            _ => RequestMethod::GET("GET"),
-        },
-        1.1,
-    );
+fn closing_header() -> Header {
+    let mut header = Header::new();
+    header.insert("Connection", "close");
+    header
+}
 
-    // #[cfg(any(target_os = "linux", target_os = "l4re"))]
-    // stream.shutdown(Shutdown::Read)?;
+/// Write the whole response to a request, with the `connection` fields among its header
+fn respond<W: Write>(
+    stream: &mut W,
+    request: &Request,
+    connection: &Header,
+    config: &Config,
+) -> Result<()> {
+    if !matches!(request.method, RequestMethod::Get | RequestMethod::Head) {
+        let mut header = connection.clone();
+        header.insert("Allow", "GET, HEAD");
+        return write_error_response(stream, 405, "Method Not Allowed", header);
+    }
+    let with_body = request.method != RequestMethod::Head;
 
     let mut path_buf = std::env::current_dir()?;
     let mut is_index_page = false;
-    let simple_request_uri = if request.uri.ends_with("/") {
-        &request.uri[..(request.uri.rfind("/").unwrap_or(request.uri.len()))]
+    let request_path = request.path();
+    let simple_request_uri = if request_path.ends_with("/") {
+        &request_path[..(request_path.rfind("/").unwrap_or(request_path.len()))]
     } else {
-        &request.uri
+        request_path
     };
 
     let paths: Vec<_> = simple_request_uri.split(*ROOT_ROUTER).collect();
@@ -85,13 +182,6 @@ fn handle_conn(mut stream: TcpStream) -> Result<()> {
         let path_buf_metadata = path_buf.metadata()?;
 
         if path_buf_metadata.is_dir() {
-            stream.write(
-                b"\
-HTTP/1.1 200 OK\r\n\
-Content-Type: text/html;charset=utf-8\r\n",
-            )?;
-            write_server_line(&mut stream)?;
-
             let mut file_infos = Vec::<FileInfo>::new();
 
             for entry in (&path_buf).read_dir()? {
@@ -115,55 +205,234 @@ Content-Type: text/html;charset=utf-8\r\n",
             let dir_view_html = gen_dir_view_html(&SERVER_NAME, dir_name, &file_infos);
             let dir_view_html_bytes = dir_view_html.as_bytes();
 
-            stream.write(b"Content-Length: ")?;
-            stream.write(dir_view_html_bytes.len().to_string().as_bytes())?;
-            stream.write(b"\r\n\r\n")?;
-            stream.write_all(dir_view_html_bytes)?;
-            write_crlf(&mut stream)?;
-        } else {
-            stream.write(
+            // Only once the folder was read, for its errors to be answered
+            stream.write_all(
                 b"\
 HTTP/1.1 200 OK\r\n\
-Content-Type: ",
+Content-Type: text/html;charset=utf-8\r\n",
             )?;
-            stream.write(&mime_bytes_from(&path_buf))?;
-            stream.write(b"; charset=utf-8\r\n")?;
-            write_server_line(&mut stream)?;
-            stream.write(b"Content-Length: ")?;
-            stream.write(path_buf_metadata.len().to_string().as_bytes())?;
-            stream.write(b"\r\n\r\n")?;
-
-            let mut file = File::open(&path_buf)?;
-            transform_stream(&mut file, &mut stream)?;
-            write_crlf(&mut stream)?;
+            write_server_line(stream)?;
+            write!(stream, "{}", connection)?;
+
+            stream.write_all(b"Content-Length: ")?;
+            stream.write_all(dir_view_html_bytes.len().to_string().as_bytes())?;
+            stream.write_all(b"\r\n\r\n")?;
+            if with_body {
+                stream.write_all(dir_view_html_bytes)?;
+            }
+        } else {
+            serve_file(stream, request, &path_buf, &path_buf_metadata, connection, config)?;
         }
     } else {
-        stream.write(
+        stream.write_all(
             b"\
 HTTP/1.1 404 Not Found\r\n\
 Content-Type: text/html;charset=utf-8\r\n",
         )?;
-        write_server_line(&mut stream)?;
+        write_server_line(stream)?;
+        write!(stream, "{}", connection)?;
 
         let not_found_html =
-            gen_not_found_html(&format!("{} [ 404 ]", &SERVER_NAME.to_owned()), &request.uri);
+            gen_not_found_html(&format!("{} [ 404 ]", &SERVER_NAME.to_owned()), request_path);
         let not_found_html_bytes = not_found_html.as_bytes();
 
-        stream.write(b"Content-Length: ")?;
-        stream.write(not_found_html_bytes.len().to_string().as_bytes())?;
-        stream.write(b"\r\n\r\n")?;
-        stream.write_all(not_found_html_bytes)?;
-        write_crlf(&mut stream)?;
+        stream.write_all(b"Content-Length: ")?;
+        stream.write_all(not_found_html_bytes.len().to_string().as_bytes())?;
+        stream.write_all(b"\r\n\r\n")?;
+        if with_body {
+            stream.write_all(not_found_html_bytes)?;
+        }
     }
 
     println!(">>>>>> Written");
     println!();
     println!();
 
-    stream.flush()?;
+    Ok(())
+}
+
+/// Answer with a file, or with the ranges of it that a GET asks for, unless the client
+/// already has it
+fn serve_file<W: Write>(
+    stream: &mut W,
+    request: &Request,
+    path_buf: &PathBuf,
+    metadata: &Metadata,
+    connection: &Header,
+    config: &Config,
+) -> Result<()> {
+    let total = metadata.len();
+    let content_type = content_type_from(path_buf, &config.mime_registry);
+    let validators = Validators::of(path_buf, metadata, config.etag)?;
+    let mut header = connection.clone();
+    validators.add_to(&mut header);
+    if let Some(cache_control) = cache_control(&config.cache_control, request.path()) {
+        header.insert("Cache-Control", cache_control);
+    }
+
+    if validators.not_modified(&request.headers) {
+        return write_head(stream, 304, "Not Modified", &header);
+    }
+    header.insert("Accept-Ranges", "bytes");
 
-    // #[cfg(any(target_os = "linux", target_os = "l4re"))]
-    // stream.shutdown(Shutdown::Write)?;
+    let if_range = request.headers.get("If-Range");
+    let ranges = match request.headers.get("Range") {
+        Some(range)
+            if request.method == RequestMethod::Get && validators.if_range_holds(if_range) =>
+        {
+            parse_ranges(range, total)
+        }
+        _ => None,
+    };
 
+    let mut file = File::open(path_buf)?;
+    match ranges {
+        None => {
+            header.insert("Content-Type", &content_type);
+            header.insert("Content-Length", &total.to_string());
+            write_head(stream, 200, "OK", &header)?;
+            if request.method != RequestMethod::Head {
+                transform_stream(&mut file, stream)?;
+            }
+        }
+        Some(Ranges::Unsatisfiable) => {
+            header.insert("Content-Range", &format!("bytes */{}", total));
+            write_error_response(stream, 416, "Range Not Satisfiable", header)?;
+        }
+        Some(Ranges::Satisfiable(ranges)) if ranges.len() == 1 => {
+            let range = ranges[0];
+            header.insert("Content-Type", &content_type);
+            header.insert("Content-Range", &range.content_range(total));
+            header.insert("Content-Length", &range.len().to_string());
+            write_head(stream, 206, "Partial Content", &header)?;
+            file.seek(SeekFrom::Start(range.start))?;
+            transform_stream(&mut file.take(range.len()), stream)?;
+        }
+        Some(Ranges::Satisfiable(ranges)) => {
+            let nanos = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+            let multipart = Multipart {
+                boundary: format!("{:016x}", nanos.as_nanos() as u64),
+                content_type: &content_type,
+                total,
+            };
+            header.insert("Content-Type", &multipart.content_type());
+            header.insert("Content-Length", &multipart.content_length(&ranges).to_string());
+            write_head(stream, 206, "Partial Content", &header)?;
+            for range in &ranges {
+                stream.write_all(multipart.part_head(range).as_bytes())?;
+                file.seek(SeekFrom::Start(range.start))?;
+                transform_stream(&mut (&mut file).take(range.len()), stream)?;
+                stream.write_all(CRLF.as_bytes())?;
+            }
+            stream.write_all(multipart.end().as_bytes())?;
+        }
+    }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io::{BufRead, BufReader, Read};
+
+    /// Read a response off the connection, its status, header fields and body
+    fn read_response<R: BufRead>(r: &mut R, head_only: bool) -> (String, Vec<String>, Vec<u8>) {
+        let mut lines = Vec::new();
+        loop {
+            let mut line = String::new();
+            r.read_line(&mut line).unwrap();
+            if line == "\r\n" {
+                break;
+            }
+            lines.push(line.trim_end().to_string());
+        }
+        let status = lines.remove(0);
+        let length = lines
+            .iter()
+            .find_map(|line| line.strip_prefix("Content-Length: "))
+            .map_or(0, |length| length.parse().unwrap());
+        let mut body = vec![0u8; if head_only { 0 } else { length }];
+        r.read_exact(&mut body).unwrap();
+        (status, lines, body)
+    }
+
+    #[test]
+    fn serves_pipelined_requests_on_one_connection() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        spawn(move || handle_conn(listener.accept().unwrap().0, &Config::default()));
+
+        let mut client = TcpStream::connect(addr).unwrap();
+        client
+            .write_all(
+                b"GET /Cargo.toml HTTP/1.1\r\nHost: t\r\n\r\n\
+                HEAD /Cargo.toml HTTP/1.1\r\nHost: t\r\n\r\n\
+                DELETE /Cargo.toml HTTP/1.1\r\nHost: t\r\nContent-Length: 2\r\n\r\nno\
+                GET /nowhere HTTP/1.1\r\nHost: t\r\nConnection: close\r\n\r\n",
+            )
+            .unwrap();
+        let mut responses = BufReader::new(client);
+
+        let (status, lines, body) = read_response(&mut responses, false);
+        assert_eq!("HTTP/1.1 200 OK", status);
+        assert!(lines.contains(&"Connection: keep-alive".to_string()));
+        assert_eq!(std::fs::read("Cargo.toml").unwrap(), body);
+
+        let (status, _, body) = read_response(&mut responses, true);
+        assert_eq!("HTTP/1.1 200 OK", status);
+        assert!(body.is_empty());
+
+        let (status, lines, _) = read_response(&mut responses, false);
+        assert_eq!("HTTP/1.1 405 Method Not Allowed", status);
+        assert!(lines.contains(&"Allow: GET, HEAD".to_string()));
+
+        let (status, lines, _) = read_response(&mut responses, false);
+        assert_eq!("HTTP/1.1 404 Not Found", status);
+        assert!(lines.contains(&"Connection: close".to_string()));
+
+        let mut rest = Vec::new();
+        responses.read_to_end(&mut rest).unwrap();
+        assert!(rest.is_empty());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn answers_when_a_file_cannot_be_read() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let name = format!("forbidden-{}.txt", std::process::id());
+        std::fs::write(&name, "secret").unwrap();
+        std::fs::set_permissions(&name, std::fs::Permissions::from_mode(0o000)).unwrap();
+        if File::open(&name).is_ok() {
+            // Permissions do not hold for root
+            std::fs::remove_file(&name).unwrap();
+            return;
+        }
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        spawn(move || handle_conn(listener.accept().unwrap().0, &Config::default()));
+
+        let mut client = TcpStream::connect(addr).unwrap();
+        write!(
+            client,
+            "GET /{} HTTP/1.1\r\nHost: t\r\n\r\n\
+            GET /Cargo.toml HTTP/1.1\r\nHost: t\r\nConnection: close\r\n\r\n",
+            name
+        )
+        .unwrap();
+        let mut responses = BufReader::new(client);
+        let forbidden = read_response(&mut responses, false);
+        let next = read_response(&mut responses, false);
+        std::fs::remove_file(&name).unwrap();
+
+        let (status, lines, body) = forbidden;
+        assert_eq!("HTTP/1.1 403 Forbidden", status);
+        assert!(lines.contains(&"Connection: keep-alive".to_string()));
+        assert_eq!(b"403 Forbidden\n", &body[..]);
+        let (status, _, body) = next;
+        assert_eq!("HTTP/1.1 200 OK", status);
+        assert_eq!(std::fs::read("Cargo.toml").unwrap(), body);
+    }
+}
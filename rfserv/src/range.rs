@@ -0,0 +1,145 @@
+/// More ranges than this in one request are served as the whole file instead
+const MAX_RANGES: usize = 64;
+
+/// Bytes `start` to `end` of a file, both included as in `Range: bytes=0-499`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct ByteRange {
+    pub(crate) start: u64,
+    pub(crate) end: u64,
+}
+
+impl ByteRange {
+    pub(crate) fn len(&self) -> u64 {
+        self.end - self.start + 1
+    }
+
+    /// The value of `Content-Range` for this range of a file of `total` bytes
+    pub(crate) fn content_range(&self, total: u64) -> String {
+        format!("bytes {}-{}/{}", self.start, self.end, total)
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum Ranges {
+    /// In increasing order, without overlaps
+    Satisfiable(Vec<ByteRange>),
+    Unsatisfiable,
+}
+
+/// The ranges of a file of `total` bytes a `Range` header asks for, `None` when it is to be
+/// ignored and the whole file served, as for other units or invalid values
+pub(crate) fn parse_ranges(value: &str, total: u64) -> Option<Ranges> {
+    let (unit, specs) = value.split_once('=')?;
+    if !unit.trim().eq_ignore_ascii_case("bytes") {
+        return None;
+    }
+
+    let mut ranges = Vec::new();
+    for spec in specs.split(',').map(str::trim).filter(|spec| !spec.is_empty()) {
+        let (first, last) = spec.split_once('-')?;
+        let (first, last) = (first.trim(), last.trim());
+        let number = |s: &str| {
+            s.bytes().all(|b| b.is_ascii_digit()).then(|| s.parse::<u64>().ok()).flatten()
+        };
+        let range = if first.is_empty() {
+            // The last bytes, whose count is given
+            let count = number(last)?;
+            (count > 0 && total > 0)
+                .then(|| ByteRange { start: total.saturating_sub(count), end: total - 1 })
+        } else {
+            let start = number(first)?;
+            let end = if last.is_empty() { u64::MAX } else { number(last)? };
+            if end < start {
+                return None;
+            }
+            (start < total).then(|| ByteRange { start, end: end.min(total - 1) })
+        };
+        ranges.extend(range);
+    }
+    if ranges.len() > MAX_RANGES {
+        return None;
+    }
+    if ranges.is_empty() {
+        return Some(Ranges::Unsatisfiable);
+    }
+
+    // Overlapping and adjacent ranges are served as one
+    ranges.sort_by_key(|range| range.start);
+    let mut coalesced: Vec<ByteRange> = Vec::with_capacity(ranges.len());
+    for range in ranges {
+        match coalesced.last_mut() {
+            Some(last) if range.start <= last.end.saturating_add(1) => {
+                last.end = last.end.max(range.end)
+            }
+            _ => coalesced.push(range),
+        }
+    }
+    Some(Ranges::Satisfiable(coalesced))
+}
+
+/// The framing of the parts of a `multipart/byteranges` body
+pub(crate) struct Multipart<'a> {
+    pub(crate) boundary: String,
+    pub(crate) content_type: &'a str,
+    pub(crate) total: u64,
+}
+
+impl Multipart<'_> {
+    pub(crate) fn content_type(&self) -> String {
+        format!("multipart/byteranges; boundary={}", self.boundary)
+    }
+
+    /// What comes before the bytes of a part
+    pub(crate) fn part_head(&self, range: &ByteRange) -> String {
+        format!(
+            "--{}\r\nContent-Type: {}\r\nContent-Range: {}\r\n\r\n",
+            self.boundary,
+            self.content_type,
+            range.content_range(self.total)
+        )
+    }
+
+    /// What follows the bytes of the last part
+    pub(crate) fn end(&self) -> String {
+        format!("--{}--\r\n", self.boundary)
+    }
+
+    /// The length of the whole body, every part followed by CRLF
+    pub(crate) fn content_length(&self, ranges: &[ByteRange]) -> u64 {
+        let parts: u64 =
+            ranges.iter().map(|range| self.part_head(range).len() as u64 + range.len() + 2).sum();
+        parts + self.end().len() as u64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ranges(value: &str, total: u64) -> Option<Vec<(u64, u64)>> {
+        match parse_ranges(value, total)? {
+            Ranges::Satisfiable(ranges) => Some(ranges.iter().map(|r| (r.start, r.end)).collect()),
+            Ranges::Unsatisfiable => Some(Vec::new()),
+        }
+    }
+
+    #[test]
+    fn parses_ranges() {
+        assert_eq!(Some(vec![(0, 499)]), ranges("bytes=0-499", 10000));
+        assert_eq!(Some(vec![(9500, 9999)]), ranges("bytes=9500-", 10000));
+        assert_eq!(Some(vec![(9500, 9999)]), ranges("bytes=-500", 10000));
+        assert_eq!(Some(vec![(0, 9)]), ranges("bytes=-500", 10));
+        assert_eq!(Some(vec![(5, 9)]), ranges("bytes=5-100", 10));
+        assert_eq!(Some(vec![(0, 1), (4, 9)]), ranges("Bytes=4-6, 0-1 ,6-, 20-30", 10));
+        assert_eq!(Some(vec![(0, 5)]), ranges("bytes=0-2,3-5", 10));
+        // Nothing satisfiable, for a 416
+        assert_eq!(Some(vec![]), ranges("bytes=10-20", 10));
+        assert_eq!(Some(vec![]), ranges("bytes=-0", 10));
+        assert_eq!(Some(vec![]), ranges("bytes=0-", 0));
+        // Ignored, for a 200
+        assert_eq!(None, ranges("items=0-1", 10));
+        assert_eq!(None, ranges("bytes=5-1", 10));
+        assert_eq!(None, ranges("bytes=a-b", 10));
+        assert_eq!(None, ranges("bytes=+1-2", 10));
+    }
+}
@@ -5,22 +5,586 @@
  *
  * @author Liangcheng Juves
  */
+use crate::header::{Header, HeaderTooLarge};
+use crate::util::DEFAULT_BUF_SIZE;
 
-#[derive(Debug)]
+use std::fmt::{Display, Formatter};
+use std::io::{Error, ErrorKind, Read, Result};
+
+/// Longest request target, longer ones get a 414
+pub(crate) const MAX_TARGET_LEN: usize = 8 * 1024;
+/// Largest request line and header fields together, larger ones get a 431
+pub(crate) const MAX_HEAD_LEN: usize = 24 * 1024;
+/// Largest body, larger ones get a 413
+pub(crate) const MAX_BODY_LEN: usize = 16 * 1024 * 1024;
+/// Longest line giving the size of a chunk, extensions included
+const MAX_CHUNK_LINE_LEN: usize = 1024;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum RequestMethod {
-    GET(&'static str),
-    POST(&'static str),
+    Get,
+    Head,
+    Post,
+    Put,
+    Delete,
+    Connect,
+    Options,
+    Trace,
+    Patch,
+    Other(String),
+}
+
+impl RequestMethod {
+    fn from_token(token: &str) -> Self {
+        match token {
+            "GET" => RequestMethod::Get,
+            "HEAD" => RequestMethod::Head,
+            "POST" => RequestMethod::Post,
+            "PUT" => RequestMethod::Put,
+            "DELETE" => RequestMethod::Delete,
+            "CONNECT" => RequestMethod::Connect,
+            "OPTIONS" => RequestMethod::Options,
+            "TRACE" => RequestMethod::Trace,
+            "PATCH" => RequestMethod::Patch,
+            other => RequestMethod::Other(other.to_string()),
+        }
+    }
+
+    pub fn as_str(&self) -> &str {
+        match self {
+            RequestMethod::Get => "GET",
+            RequestMethod::Head => "HEAD",
+            RequestMethod::Post => "POST",
+            RequestMethod::Put => "PUT",
+            RequestMethod::Delete => "DELETE",
+            RequestMethod::Connect => "CONNECT",
+            RequestMethod::Options => "OPTIONS",
+            RequestMethod::Trace => "TRACE",
+            RequestMethod::Patch => "PATCH",
+            RequestMethod::Other(other) => other,
+        }
+    }
+}
+
+impl Display for RequestMethod {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Version {
+    Http10,
+    Http11,
 }
 
-#[derive(Debug)]
+impl Display for Version {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Version::Http10 => "HTTP/1.0",
+            Version::Http11 => "HTTP/1.1",
+        })
+    }
+}
+
+#[derive(Debug, Clone)]
 pub struct Request {
-    pub uri: String,
     pub method: RequestMethod,
-    pub version: f32,
+    pub target: String,
+    pub version: Version,
+    pub headers: Header,
+    pub body: Vec<u8>,
 }
 
 impl Request {
-    pub fn new(uri: String, method: RequestMethod, version: f32) -> Self {
-        Request { uri, method, version }
+    /// The target without its query
+    pub fn path(&self) -> &str {
+        self.target.split('?').next().unwrap_or_default()
+    }
+}
+
+/// Why a request could not be read, and the status to answer it with
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HttpError {
+    BadRequest,
+    PayloadTooLarge,
+    UriTooLong,
+    HeaderFieldsTooLarge,
+    NotImplemented,
+    VersionNotSupported,
+}
+
+impl HttpError {
+    pub fn status(&self) -> u16 {
+        match self {
+            HttpError::BadRequest => 400,
+            HttpError::PayloadTooLarge => 413,
+            HttpError::UriTooLong => 414,
+            HttpError::HeaderFieldsTooLarge => 431,
+            HttpError::NotImplemented => 501,
+            HttpError::VersionNotSupported => 505,
+        }
+    }
+
+    pub fn reason(&self) -> &'static str {
+        match self {
+            HttpError::BadRequest => "Bad Request",
+            HttpError::PayloadTooLarge => "Payload Too Large",
+            HttpError::UriTooLong => "URI Too Long",
+            HttpError::HeaderFieldsTooLarge => "Request Header Fields Too Large",
+            HttpError::NotImplemented => "Not Implemented",
+            HttpError::VersionNotSupported => "HTTP Version Not Supported",
+        }
+    }
+
+    /// The HTTP error an I/O error of [`RequestParser::read_request`] stands for, if any
+    pub fn of(e: &Error) -> Option<HttpError> {
+        e.get_ref().and_then(|inner| inner.downcast_ref::<HttpError>()).copied()
+    }
+}
+
+impl Display for HttpError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} {}", self.status(), self.reason())
+    }
+}
+
+impl std::error::Error for HttpError {}
+
+impl From<HttpError> for Error {
+    fn from(e: HttpError) -> Self {
+        Error::new(ErrorKind::InvalidData, e)
+    }
+}
+
+impl From<HeaderTooLarge> for HttpError {
+    fn from(_: HeaderTooLarge) -> Self {
+        HttpError::HeaderFieldsTooLarge
+    }
+}
+
+enum State {
+    Head,
+    Body(Request, Framing),
+}
+
+enum Framing {
+    Length(usize),
+    Chunked(Chunk),
+}
+
+/// Where a chunked body is at
+enum Chunk {
+    Size,
+    Data(usize),
+    DataEnd,
+    /// Trailer fields, which are skipped, and how many bytes of them were
+    Trailers(usize),
+}
+
+/// Reads requests out of the bytes of a connection as they come, keeping what follows one
+/// for the next
+pub struct RequestParser {
+    buf: Vec<u8>,
+    /// How much of the head was looked through for its end already
+    scanned: usize,
+    state: State,
+}
+
+impl RequestParser {
+    pub fn new() -> Self {
+        RequestParser { buf: Vec::new(), scanned: 0, state: State::Head }
+    }
+
+    pub fn push(&mut self, bytes: &[u8]) {
+        self.buf.extend_from_slice(bytes);
+    }
+
+    /// Whether nothing of a next request came yet
+    pub fn is_idle(&self) -> bool {
+        self.buf.is_empty() && matches!(self.state, State::Head)
+    }
+
+    /// Parse a request out of the bytes pushed so far, `None` until they make a whole one
+    pub fn parse(&mut self) -> std::result::Result<Option<Request>, HttpError> {
+        if let State::Head = self.state {
+            // Empty lines before a request line are to be ignored
+            let blank = self.buf.iter().take_while(|b| **b == b'\r' || **b == b'\n').count();
+            self.buf.drain(..blank);
+
+            let end = match head_end(&self.buf, self.scanned) {
+                Some(end) => end,
+                None => {
+                    // The line ending before the empty line may be seen again
+                    self.scanned = self.buf.len().saturating_sub(2);
+                    check_partial_head(&self.buf)?;
+                    return Ok(None);
+                }
+            };
+            let head: Vec<u8> = self.buf.drain(..end).collect();
+            self.scanned = 0;
+            let (request, framing) = parse_head(&head)?;
+            match framing {
+                Some(framing) => self.state = State::Body(request, framing),
+                None => return Ok(Some(request)),
+            }
+        }
+
+        let complete = match &mut self.state {
+            State::Head => false,
+            State::Body(request, framing) => read_body(&mut self.buf, &mut request.body, framing)?,
+        };
+        if !complete {
+            return Ok(None);
+        }
+        match std::mem::replace(&mut self.state, State::Head) {
+            State::Body(request, _) => Ok(Some(request)),
+            State::Head => Ok(None),
+        }
+    }
+
+    /// Read from `reader` until a whole request is there, `None` when it is closed first
+    pub fn read_request<R: Read>(&mut self, reader: &mut R) -> Result<Option<Request>> {
+        let mut chunk = [0u8; DEFAULT_BUF_SIZE];
+        loop {
+            if let Some(request) = self.parse()? {
+                return Ok(Some(request));
+            }
+            let len = match reader.read(&mut chunk) {
+                Ok(len) => len,
+                Err(ref e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            };
+            if len == 0 {
+                if self.buf.is_empty() && matches!(self.state, State::Head) {
+                    return Ok(None);
+                }
+                return Err(Error::new(
+                    ErrorKind::UnexpectedEof,
+                    "Closed in the middle of a request",
+                ));
+            }
+            self.push(&chunk[..len]);
+        }
+    }
+}
+
+impl Default for RequestParser {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Where the empty line ending the head is over, if it came after `from`
+fn head_end(buf: &[u8], from: usize) -> Option<usize> {
+    buf.iter().enumerate().skip(from).filter(|(_, b)| **b == b'\n').find_map(|(i, _)| {
+        match buf.get(i + 1..) {
+            Some([b'\n', ..]) => Some(i + 2),
+            Some([b'\r', b'\n', ..]) => Some(i + 3),
+            _ => None,
+        }
+    })
+}
+
+/// Give up on a head that is not over yet but already too long
+fn check_partial_head(buf: &[u8]) -> std::result::Result<(), HttpError> {
+    match buf.iter().position(|b| *b == b'\n') {
+        // Room for the longest method and version around the target
+        None if buf.len() > MAX_TARGET_LEN + 32 => Err(HttpError::UriTooLong),
+        Some(end)
+            if buf[..end]
+                .split(|b| *b == b' ')
+                .nth(1)
+                .is_some_and(|t| t.len() > MAX_TARGET_LEN) =>
+        {
+            Err(HttpError::UriTooLong)
+        }
+        _ if buf.len() > MAX_HEAD_LEN => Err(HttpError::HeaderFieldsTooLarge),
+        _ => Ok(()),
+    }
+}
+
+fn parse_head(head: &[u8]) -> std::result::Result<(Request, Option<Framing>), HttpError> {
+    let mut lines =
+        head.split(|b| *b == b'\n').map(|line| line.strip_suffix(b"\r").unwrap_or(line));
+
+    let request_line = lines.next().unwrap_or_default();
+    let request_line = std::str::from_utf8(request_line).map_err(|_| HttpError::BadRequest)?;
+    let mut parts = request_line.split(' ');
+    let (method, target, version) = match (parts.next(), parts.next(), parts.next(), parts.next()) {
+        (Some(method), Some(target), Some(version), None) => (method, target, version),
+        _ => return Err(HttpError::BadRequest),
+    };
+    if !is_token(method) || target.is_empty() || target.bytes().any(|b| b.is_ascii_control()) {
+        return Err(HttpError::BadRequest);
+    }
+    if target.len() > MAX_TARGET_LEN {
+        return Err(HttpError::UriTooLong);
+    }
+    let version = match version {
+        "HTTP/1.1" => Version::Http11,
+        "HTTP/1.0" => Version::Http10,
+        other => {
+            let digits = other.strip_prefix("HTTP/").map(str::as_bytes);
+            return Err(match digits {
+                Some([major, b'.', minor]) if major.is_ascii_digit() && minor.is_ascii_digit() => {
+                    HttpError::VersionNotSupported
+                }
+                _ => HttpError::BadRequest,
+            });
+        }
+    };
+    if head.len() > MAX_HEAD_LEN {
+        return Err(HttpError::HeaderFieldsTooLarge);
+    }
+
+    let mut headers = Header::new();
+    for line in lines.filter(|line| !line.is_empty()) {
+        // Folded lines are obsolete and tell of smuggling more than anything else
+        if line[0] == b' ' || line[0] == b'\t' {
+            return Err(HttpError::BadRequest);
+        }
+        let colon = line.iter().position(|b| *b == b':').ok_or(HttpError::BadRequest)?;
+        let name = std::str::from_utf8(&line[..colon]).map_err(|_| HttpError::BadRequest)?;
+        if !is_token(name) {
+            return Err(HttpError::BadRequest);
+        }
+        let value = String::from_utf8_lossy(&line[colon + 1..]);
+        headers.add(name, value.trim_matches(|c| c == ' ' || c == '\t'))?;
+    }
+
+    let hosts = headers.get_all("Host").count();
+    if hosts > 1 || (version == Version::Http11 && hosts == 0) {
+        return Err(HttpError::BadRequest);
+    }
+
+    let framing = framing_of(&headers, version)?;
+    let request = Request {
+        method: RequestMethod::from_token(method),
+        target: target.to_string(),
+        version,
+        headers,
+        body: Vec::new(),
+    };
+    Ok((request, framing))
+}
+
+/// How the body is delimited, `None` when there is none
+fn framing_of(
+    headers: &Header,
+    version: Version,
+) -> std::result::Result<Option<Framing>, HttpError> {
+    if headers.contains("Transfer-Encoding") {
+        // Both, or chunking in HTTP/1.0, are how requests get smuggled past proxies
+        if headers.contains("Content-Length") || version == Version::Http10 {
+            return Err(HttpError::BadRequest);
+        }
+        let codings: Vec<&str> = headers.tokens("Transfer-Encoding").collect();
+        return match codings.last() {
+            Some(last) if last.eq_ignore_ascii_case("chunked") => {
+                if codings.len() == 1 {
+                    Ok(Some(Framing::Chunked(Chunk::Size)))
+                } else {
+                    Err(HttpError::NotImplemented)
+                }
+            }
+            _ => Err(HttpError::BadRequest),
+        };
+    }
+
+    let mut lengths = headers.tokens("Content-Length");
+    let length = match lengths.next() {
+        Some(length) => length,
+        None => return Ok(None),
+    };
+    if lengths.any(|other| other != length) || !length.bytes().all(|b| b.is_ascii_digit()) {
+        return Err(HttpError::BadRequest);
+    }
+    match length.parse::<usize>() {
+        Ok(0) => Ok(None),
+        Ok(length) if length <= MAX_BODY_LEN => Ok(Some(Framing::Length(length))),
+        _ => Err(HttpError::PayloadTooLarge),
+    }
+}
+
+/// Move what is there of the body from `buf` to `body`, and tell whether it is all there
+fn read_body(
+    buf: &mut Vec<u8>,
+    body: &mut Vec<u8>,
+    framing: &mut Framing,
+) -> std::result::Result<bool, HttpError> {
+    let chunk = match framing {
+        Framing::Length(length) => {
+            let taken = (*length - body.len()).min(buf.len());
+            body.extend(buf.drain(..taken));
+            return Ok(body.len() == *length);
+        }
+        Framing::Chunked(chunk) => chunk,
+    };
+    loop {
+        match chunk {
+            Chunk::Size => {
+                let line = match take_line(buf, MAX_CHUNK_LINE_LEN, HttpError::BadRequest)? {
+                    Some(line) => line,
+                    None => return Ok(false),
+                };
+                let size = line.split(|b| *b == b';').next().unwrap_or_default();
+                let size = std::str::from_utf8(size).map_err(|_| HttpError::BadRequest)?.trim();
+                if size.is_empty() || !size.bytes().all(|b| b.is_ascii_hexdigit()) {
+                    return Err(HttpError::BadRequest);
+                }
+                let size =
+                    usize::from_str_radix(size, 16).map_err(|_| HttpError::PayloadTooLarge)?;
+                *chunk = match size {
+                    0 => Chunk::Trailers(0),
+                    size if size > MAX_BODY_LEN - body.len() => {
+                        return Err(HttpError::PayloadTooLarge)
+                    }
+                    size => Chunk::Data(size),
+                };
+            }
+            Chunk::Data(left) => {
+                let taken = (*left).min(buf.len());
+                body.extend(buf.drain(..taken));
+                *left -= taken;
+                if *left > 0 {
+                    return Ok(false);
+                }
+                *chunk = Chunk::DataEnd;
+            }
+            Chunk::DataEnd => match take_line(buf, 2, HttpError::BadRequest)? {
+                Some(line) if line.is_empty() => *chunk = Chunk::Size,
+                Some(_) => return Err(HttpError::BadRequest),
+                None => return Ok(false),
+            },
+            Chunk::Trailers(size) => {
+                let line =
+                    match take_line(buf, MAX_HEAD_LEN - *size, HttpError::HeaderFieldsTooLarge)? {
+                        Some(line) => line,
+                        None => return Ok(false),
+                    };
+                if line.is_empty() {
+                    return Ok(true);
+                }
+                *size += line.len();
+            }
+        }
+    }
+}
+
+/// Take a line out of `buf` without its line ending, or fail with `too_long` when there is no
+/// line ending within `max_len` bytes
+fn take_line(
+    buf: &mut Vec<u8>,
+    max_len: usize,
+    too_long: HttpError,
+) -> std::result::Result<Option<Vec<u8>>, HttpError> {
+    match buf.iter().position(|b| *b == b'\n') {
+        Some(end) if end <= max_len => {
+            let mut line: Vec<u8> = buf.drain(..=end).collect();
+            line.pop();
+            if line.last() == Some(&b'\r') {
+                line.pop();
+            }
+            Ok(Some(line))
+        }
+        Some(_) => Err(too_long),
+        None if buf.len() > max_len => Err(too_long),
+        None => Ok(None),
+    }
+}
+
+/// RFC 7230 tokens, which methods and field names are
+fn is_token(s: &str) -> bool {
+    !s.is_empty() && s.bytes().all(|b| b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&b))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse_all(bytes: &[u8]) -> std::result::Result<Vec<Request>, HttpError> {
+        let mut parser = RequestParser::new();
+        let mut requests = Vec::new();
+        // A byte at a time, for every state to be resumed
+        for byte in bytes {
+            parser.push(&[*byte]);
+            while let Some(request) = parser.parse()? {
+                requests.push(request);
+            }
+        }
+        Ok(requests)
+    }
+
+    #[test]
+    fn parses_heads_and_bodies() {
+        let requests = parse_all(
+            b"\r\nGET /a%20b?x=1 HTTP/1.1\r\nHost: h\r\nX-Long:  spaced value \r\n\r\n\
+            POST /up HTTP/1.1\r\nhost: h\r\nContent-Length: 5\r\n\r\nhello\
+            PUT /c HTTP/1.1\nHost: h\nTransfer-Encoding: chunked\n\n4;ext=1\r\nWiki\r\n6\r\npedia!\r\n0\r\nX-Trailer: t\r\n\r\n\
+            GET / HTTP/1.0\r\n\r\n",
+        )
+        .unwrap();
+        assert_eq!(4, requests.len());
+        assert_eq!(RequestMethod::Get, requests[0].method);
+        assert_eq!("/a%20b", requests[0].path());
+        assert_eq!(Some("spaced value"), requests[0].headers.get("x-long"));
+        assert_eq!(b"hello", &requests[1].body[..]);
+        assert_eq!(b"Wikipedia!", &requests[2].body[..]);
+        assert_eq!(Version::Http10, requests[3].version);
+    }
+
+    #[test]
+    fn rejects_malformed_requests() {
+        let status = |bytes: &[u8]| parse_all(bytes).err().map(|e| e.status());
+        assert_eq!(Some(400), status(b"GET /\r\n\r\n"));
+        assert_eq!(Some(400), status(b"GET / HTTP/1.1\r\n\r\n"));
+        assert_eq!(Some(400), status(b"GET / HTTP/1.1\r\nHost: h\r\n folded\r\n\r\n"));
+        assert_eq!(Some(400), status(b"GET / HTTP/1.1\r\nHost : h\r\n\r\n"));
+        assert_eq!(
+            Some(400),
+            status(b"POST / HTTP/1.1\r\nHost: h\r\nContent-Length: 1, 2\r\n\r\n")
+        );
+        assert_eq!(
+            Some(400),
+            status(b"POST / HTTP/1.1\r\nHost: h\r\nContent-Length: 3\r\nTransfer-Encoding: chunked\r\n\r\n")
+        );
+        assert_eq!(
+            Some(400),
+            status(b"POST / HTTP/1.1\r\nHost: h\r\nTransfer-Encoding: chunked\r\n\r\nzz\r\n")
+        );
+        assert_eq!(
+            Some(501),
+            status(b"POST / HTTP/1.1\r\nHost: h\r\nTransfer-Encoding: gzip, chunked\r\n\r\n")
+        );
+        assert_eq!(Some(505), status(b"GET / HTTP/2.0\r\n\r\n"));
+        assert_eq!(
+            Some(414),
+            status(format!("GET /{} HTTP/1.1\r\n", "a".repeat(MAX_TARGET_LEN)).as_bytes())
+        );
+        assert_eq!(
+            Some(431),
+            status(
+                format!("GET / HTTP/1.1\r\nHost: h\r\nX: {}\r\n", "a".repeat(MAX_HEAD_LEN))
+                    .as_bytes()
+            )
+        );
+        assert_eq!(
+            Some(413),
+            status(
+                format!(
+                    "POST / HTTP/1.1\r\nHost: h\r\nContent-Length: {}\r\n\r\n",
+                    MAX_BODY_LEN + 1
+                )
+                .as_bytes()
+            )
+        );
+        assert_eq!(
+            Some(413),
+            status(
+                b"POST / HTTP/1.1\r\nHost: h\r\nTransfer-Encoding: chunked\r\n\r\n\
+                  1\r\na\r\nffffffffffffffff\r\n"
+            )
+        );
     }
 }
@@ -1,14 +1,14 @@
+use crate::header::Header;
 use crate::model::*;
 
+use std::fs::File;
 use std::io::{ErrorKind, Read, Result, Write};
-use std::net::TcpStream;
 use std::path::PathBuf;
 
-use fmedia::MediaType;
+use fmedia::{MediaType, MediaTypeInfo, MimeRegistry};
 
 // Bare metal platforms usually have very small amounts of RAM
 // (in the order of hundreds of KB)
-#[allow(dead_code)]
 pub const DEFAULT_BUF_SIZE: usize = if cfg!(target_os = "espidf") { 512 } else { 8 * 1024 };
 
 pub(crate) fn gen_dir_view_html(title: &str, dir_name: &str, file_infos: &Vec<FileInfo>) -> String {
@@ -52,46 +52,16 @@ pub(crate) fn gen_not_found_html(title: &str, not_found_path: &str) -> String {
     html
 }
 
-pub(crate) fn read_request_lines(mut stream: &TcpStream) -> Result<Vec<u8>> {
-    let mut request_lines = Vec::<u8>::new();
-
-    let mut buf = [0u8];
-
-    fn write_and_flush<W>(w: &mut W, bytes: &[u8]) -> Result<()>
-    where
-        W: Write,
-    {
-        w.write(bytes)?;
-        w.flush()?;
-        Ok(())
-    }
-
-    loop {
-        stream.read(&mut buf)?;
-        write_and_flush(&mut request_lines, &buf)?;
-
-        if b'\n' == buf[0] {
-            let mut buf_tmp = [0u8];
-            stream.read(&mut buf_tmp)?;
-
-            if b'\r' == buf_tmp[0] || b'\n' == buf_tmp[0] {
-                return Ok(request_lines);
-            } else {
-                write_and_flush(&mut request_lines, &buf_tmp)?;
-            }
-        }
+/// The `Content-Type` of a file, with a charset for text
+pub(crate) fn content_type_from(path_buf: &PathBuf, registry: &MimeRegistry) -> String {
+    let mut head = Vec::with_capacity(MediaType::SNIFF_LEN);
+    if let Ok(file) = File::open(path_buf) {
+        let _ = file.take(MediaType::SNIFF_LEN as u64).read_to_end(&mut head);
     }
-}
-
-pub(crate) fn mime_bytes_from(path_buf: &PathBuf) -> Vec<u8> {
-    let media_ty = match path_buf.extension() {
-        Some(extension) => MediaType::from_file_extension(extension.to_str().unwrap()),
-        _ => None,
-    };
-    if let Some(mty) = media_ty {
-        mty.as_bytes().to_vec()
-    } else {
-        b"text/plain".to_vec()
+    let media_ty = registry.detect(path_buf, &head);
+    match MediaTypeInfo::of(&media_ty).charset {
+        Some(charset) => format!("{}; charset={}", media_ty, charset),
+        None => media_ty,
     }
 }
 
@@ -104,16 +74,112 @@ where
     Ok(())
 }
 
+/// A short plain text response for a request that cannot be served
+pub(crate) fn write_error_response<W>(
+    w: &mut W,
+    status: u16,
+    reason: &str,
+    mut header: Header,
+) -> Result<()>
+where
+    W: Write,
+{
+    let body = format!("{} {}\n", status, reason);
+    header.insert("Content-Type", "text/plain; charset=utf-8");
+    header.insert("Content-Length", &body.len().to_string());
+    write_head(w, status, reason, &header)?;
+    w.write_all(body.as_bytes())?;
+    w.flush()?;
+    Ok(())
+}
+
+/// The status line, `Server` and the header, up to the body
+pub(crate) fn write_head<W>(w: &mut W, status: u16, reason: &str, header: &Header) -> Result<()>
+where
+    W: Write,
+{
+    write!(w, "HTTP/1.1 {} {}\r\n", status, reason)?;
+    write_server_line(w)?;
+    write!(w, "{}\r\n", header)?;
+    Ok(())
+}
+
 pub(crate) fn write_server_line<W>(w: &mut W) -> Result<()>
 where
     W: Write,
 {
-    w.write(b"Server: ")?;
-    w.write(&SERVER_NAME.as_bytes())?;
+    w.write_all(b"Server: ")?;
+    w.write_all(SERVER_NAME.as_bytes())?;
     write_crlf(w)?;
     Ok(())
 }
 
+const MONTHS: [&str; 12] =
+    ["Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec"];
+
+const WEEKDAYS: [&str; 7] = ["Sun", "Mon", "Tue", "Wed", "Thu", "Fri", "Sat"];
+
+/// An HTTP date in the IMF-fixdate form, like `Sun, 06 Nov 1994 08:49:37 GMT`
+pub(crate) fn fmt_http_date(secs: u64) -> String {
+    let (days, secs) = (secs / 86400, secs % 86400);
+    // The epoch was a Thursday
+    let weekday = WEEKDAYS[((days + 4) % 7) as usize];
+
+    // The civil date of the days since the epoch, counting years from March for the leap days
+    let days = days + 719468;
+    let (era, day_of_era) = (days / 146097, days % 146097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_from_march = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_from_march + 2) / 5 + 1;
+    let month = (month_from_march + 2) % 12;
+    let year = era * 400 + year_of_era + if month < 2 { 1 } else { 0 };
+
+    format!(
+        "{}, {:02} {} {} {:02}:{:02}:{:02} GMT",
+        weekday,
+        day,
+        MONTHS[month as usize],
+        year,
+        secs / 3600,
+        secs % 3600 / 60,
+        secs % 60
+    )
+}
+
+/// Seconds since the epoch of an HTTP date, in the preferred IMF-fixdate form
+/// (`Sun, 06 Nov 1994 08:49:37 GMT`) or the obsolete RFC 850 and asctime ones
+pub(crate) fn parse_http_date(date: &str) -> Option<u64> {
+    let parts: Vec<_> = date.split_whitespace().collect();
+    let (day, month, year, time) = match parts[..] {
+        [_, day, month, year, time, "GMT"] => (day, month, year.parse().ok()?, time),
+        [_, date, time, "GMT"] => {
+            let mut date = date.splitn(3, '-');
+            let (day, month, year) = (date.next()?, date.next()?, date.next()?);
+            let year: u64 = year.parse().ok().filter(|_| year.len() == 2)?;
+            (day, month, if year < 70 { 2000 + year } else { 1900 + year }, time)
+        }
+        [_, month, day, time, year] => (day, month, year.parse().ok()?, time),
+        _ => return None,
+    };
+    let day: u64 = day.parse().ok().filter(|day| (1..=31).contains(day))?;
+    let month = MONTHS.iter().position(|m| *m == month)? as u64 + 1;
+    let mut time = time.splitn(3, ':').map(|n| n.parse::<u64>().ok().filter(|_| n.len() == 2));
+    let (hour, minute, second) = (time.next()??, time.next()??, time.next()??);
+    if year < 1970 || hour > 23 || minute > 59 || second > 60 {
+        return None;
+    }
+
+    // Days from the epoch to the civil date, counting years from March for the leap days
+    let year = if month <= 2 { year - 1 } else { year };
+    let (era, year_of_era) = (year / 400, year % 400);
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    let days = (era * 146097 + day_of_era).checked_sub(719468)?;
+    Some(days * 86400 + hour * 3600 + minute * 60 + second)
+}
+
 pub(crate) fn transform_stream<R: Read + ?Sized, W: Write + ?Sized>(
     r: &mut R,
     w: &mut W,
@@ -130,3 +196,62 @@ pub(crate) fn transform_stream<R: Read + ?Sized, W: Write + ?Sized>(
         w.flush()?;
     }
 }
+
+/// A writer that tells whether a response was started through it
+pub(crate) struct Tracked<W> {
+    inner: W,
+    written: bool,
+}
+
+impl<W: Write> Tracked<W> {
+    pub(crate) fn new(inner: W) -> Self {
+        Tracked { inner, written: false }
+    }
+
+    pub(crate) fn has_written(&self) -> bool {
+        self.written
+    }
+}
+
+impl<W: Write> Write for Tracked<W> {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        self.written |= !buf.is_empty();
+        self.inner.write(buf)
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn formats_http_dates() {
+        assert_eq!("Thu, 01 Jan 1970 00:00:00 GMT", fmt_http_date(0));
+        assert_eq!("Sun, 06 Nov 1994 08:49:37 GMT", fmt_http_date(784111777));
+        assert_eq!("Tue, 29 Feb 2000 23:59:59 GMT", fmt_http_date(951868799));
+        assert_eq!("Fri, 31 Dec 2100 12:00:00 GMT", fmt_http_date(4133937600));
+        for secs in (0..5_000_000_000).step_by(86_399_999) {
+            assert_eq!(Some(secs), parse_http_date(&fmt_http_date(secs)));
+        }
+    }
+
+    #[test]
+    fn parses_http_dates() {
+        for date in [
+            "Sun, 06 Nov 1994 08:49:37 GMT",
+            "Sunday, 06-Nov-94 08:49:37 GMT",
+            "Sun Nov  6 08:49:37 1994",
+        ] {
+            assert_eq!(Some(784111777), parse_http_date(date), "{}", date);
+        }
+        assert_eq!(Some(0), parse_http_date("Thu, 01 Jan 1970 00:00:00 GMT"));
+        assert_eq!(Some(951782400), parse_http_date("Tue, 29 Feb 2000 00:00:00 GMT"));
+        assert_eq!(None, parse_http_date("Sun, 06 Nov 1994 08:49:37 CET"));
+        assert_eq!(None, parse_http_date("Sun, 06 Nov 1994 8:49:37 GMT"));
+        assert_eq!(None, parse_http_date("\"abc\""));
+    }
+}
@@ -0,0 +1,271 @@
+use crate::generator::{self, HmacShaAlgorithm};
+
+use std::io::{Error, ErrorKind, Result};
+
+use serde::{Deserialize, Serialize};
+use zeroize::{Zeroize, Zeroizing};
+
+const OTPAUTH_SCHEME: &str = "otpauth://";
+
+pub(crate) const DEFAULT_DIGITS: usize = 6;
+pub(crate) const DEFAULT_PERIOD: u64 = 30;
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub enum OtpKind {
+    Totp,
+    Hotp,
+}
+
+impl OtpKind {
+    fn name(&self) -> &'static str {
+        match self {
+            OtpKind::Totp => "totp",
+            OtpKind::Hotp => "hotp",
+        }
+    }
+}
+
+/// One OTP account, as described by an `otpauth://` URI
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct Account {
+    pub name: String,
+    pub issuer: Option<String>,
+    /// Base32 encoded, upper case and padded, wiped from memory when the account is dropped
+    pub secret: String,
+    pub kind: OtpKind,
+    pub algorithm: HmacShaAlgorithm,
+    pub digits: usize,
+    pub period: u64,
+    pub counter: u64,
+}
+
+impl Drop for Account {
+    fn drop(&mut self) {
+        self.secret.zeroize();
+    }
+}
+
+fn invalid_input(msg: String) -> Error {
+    Error::new(ErrorKind::InvalidInput, msg)
+}
+
+impl Account {
+    /// Parse `otpauth://TYPE/LABEL?PARAMETERS`
+    pub fn from_otpauth_uri(uri: &str) -> Result<Self> {
+        let uri = uri.trim();
+        if !uri.get(..OTPAUTH_SCHEME.len()).is_some_and(|s| s.eq_ignore_ascii_case(OTPAUTH_SCHEME))
+        {
+            return Err(invalid_input(format!("Not an otpauth URI: {}", uri)));
+        }
+        let rest = &uri[OTPAUTH_SCHEME.len()..];
+        let (kind, rest) =
+            rest.split_once('/').ok_or_else(|| invalid_input("Missing label".to_string()))?;
+        let kind = match kind.to_ascii_lowercase().as_str() {
+            "totp" => OtpKind::Totp,
+            "hotp" => OtpKind::Hotp,
+            _ => return Err(invalid_input(format!("Unknown OTP type: {}", kind))),
+        };
+        let (label, query) = rest.split_once('?').unwrap_or((rest, ""));
+        let name = percent_decode(label)?;
+        if name.is_empty() {
+            return Err(invalid_input("Missing label".to_string()));
+        }
+
+        let mut account = Account {
+            name,
+            issuer: None,
+            secret: String::new(),
+            kind,
+            algorithm: HmacShaAlgorithm::SHA1,
+            digits: DEFAULT_DIGITS,
+            period: DEFAULT_PERIOD,
+            counter: 0,
+        };
+        let mut has_counter = false;
+        for pair in query.split('&').filter(|pair| !pair.is_empty()) {
+            let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+            let key = key.to_ascii_lowercase();
+            if key == "secret" {
+                account.secret = normalize_secret(&Zeroizing::new(percent_decode(value)?))?;
+                continue;
+            }
+            let value = percent_decode(value)?;
+            match key.as_str() {
+                "issuer" => account.issuer = Some(value),
+                "algorithm" => account.algorithm = value.parse()?,
+                "digits" => {
+                    account.digits = match value.parse() {
+                        Ok(digits @ 6..=8) => digits,
+                        _ => return Err(invalid_input(format!("Invalid digits: {}", value))),
+                    }
+                }
+                "period" => {
+                    account.period = match value.parse() {
+                        Ok(period) if period > 0 => period,
+                        _ => return Err(invalid_input(format!("Invalid period: {}", value))),
+                    }
+                }
+                "counter" => {
+                    account.counter = value
+                        .parse()
+                        .map_err(|_| invalid_input(format!("Invalid counter: {}", value)))?;
+                    has_counter = true;
+                }
+                // Unknown parameters (e.g. `image`) are ignored
+                _ => {}
+            }
+        }
+        if account.secret.is_empty() {
+            return Err(invalid_input("Missing secret".to_string()));
+        }
+        if account.kind == OtpKind::Hotp && !has_counter {
+            return Err(invalid_input("HOTP URI requires a counter".to_string()));
+        }
+        Ok(account)
+    }
+
+    /// The URI holds the secret, so it is wiped from memory when dropped too
+    pub fn to_otpauth_uri(&self) -> Zeroizing<String> {
+        let name = percent_encode(&self.name);
+        let issuer = self.issuer.as_deref().map(percent_encode);
+        // Room for every parameter, so that no reallocation leaves the secret behind
+        let mut uri = Zeroizing::new(String::with_capacity(
+            OTPAUTH_SCHEME.len()
+                + name.len()
+                + self.secret.len()
+                + issuer.as_ref().map_or(0, String::len)
+                + 128,
+        ));
+        uri.push_str(OTPAUTH_SCHEME);
+        uri.push_str(self.kind.name());
+        uri.push('/');
+        uri.push_str(&name);
+        uri.push_str("?secret=");
+        uri.push_str(self.secret.trim_end_matches('='));
+        if let Some(issuer) = issuer {
+            uri.push_str(&format!("&issuer={}", issuer));
+        }
+        uri.push_str(&format!("&algorithm={}&digits={}", self.algorithm.name(), self.digits));
+        match self.kind {
+            OtpKind::Totp => uri.push_str(&format!("&period={}", self.period)),
+            OtpKind::Hotp => uri.push_str(&format!("&counter={}", self.counter)),
+        }
+        uri
+    }
+
+    pub fn kind_name(&self) -> &'static str {
+        self.kind.name()
+    }
+
+    /// The code for the given unix time, or for the current counter of a HOTP account
+    pub fn code_at(&self, time_secs: u64) -> String {
+        let secret = Zeroizing::new(rb32::decode(self.secret.as_bytes()));
+        let moving_factor = match self.kind {
+            OtpKind::Totp => time_secs / self.period,
+            OtpKind::Hotp => self.counter,
+        };
+        generator::gen_otp(&secret, moving_factor, self.digits, self.algorithm)
+    }
+
+    /// Seconds until the TOTP code at `time_secs` expires
+    pub fn remaining_secs(&self, time_secs: u64) -> u64 {
+        self.period - time_secs % self.period
+    }
+}
+
+/// Upper-case, strip spaces and pad to a whole base32 group, so that `rb32::decode` accepts it
+fn normalize_secret(secret: &str) -> Result<String> {
+    // Padding included, so that pushing it does not leave a copy behind
+    let mut normalized = Zeroizing::new(String::with_capacity(secret.len() + 7));
+    normalized.extend(
+        secret
+            .chars()
+            .filter(|c| !c.is_whitespace() && *c != '-' && *c != '=')
+            .map(|c| c.to_ascii_uppercase()),
+    );
+    if normalized.is_empty() {
+        return Ok(String::new());
+    }
+    if let Some(c) = normalized.chars().find(|c| !matches!(c, 'A'..='Z' | '2'..='7')) {
+        return Err(invalid_input(format!("Invalid base32 character in secret: {}", c)));
+    }
+    while !normalized.len().is_multiple_of(8) {
+        normalized.push('=');
+    }
+    Ok(std::mem::take(&mut *normalized))
+}
+
+fn percent_decode(s: &str) -> Result<String> {
+    let bytes = s.as_bytes();
+    let mut decoded = Vec::<u8>::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'%' => {
+                let hex = s
+                    .get(i + 1..i + 3)
+                    .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+                    .ok_or_else(|| invalid_input(format!("Invalid percent-encoding: {}", s)))?;
+                decoded.push(hex);
+                i += 3;
+            }
+            b'+' => {
+                decoded.push(b' ');
+                i += 1;
+            }
+            b => {
+                decoded.push(b);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8(decoded).map_err(|_| invalid_input(format!("Invalid UTF-8 in: {}", s)))
+}
+
+fn percent_encode(s: &str) -> String {
+    let mut encoded = String::with_capacity(s.len());
+    for b in s.bytes() {
+        match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' | b'@' | b':' => {
+                encoded.push(b as char)
+            }
+            _ => encoded.push_str(&format!("%{:02X}", b)),
+        }
+    }
+    encoded
+}
+
+#[test]
+fn test_otpauth_uri_round_trip() {
+    let uri = "otpauth://totp/ACME%20Co:john@example.com?secret=HXDMVJECJJWSRB3HWIZR4IFUGFTMXBOZ&issuer=ACME%20Co&algorithm=SHA1&digits=6&period=30";
+    let account = Account::from_otpauth_uri(uri).unwrap();
+    assert_eq!(account.name, "ACME Co:john@example.com");
+    assert_eq!(account.issuer.as_deref(), Some("ACME Co"));
+    assert_eq!(account.kind, OtpKind::Totp);
+    assert_eq!(*account.to_otpauth_uri(), uri);
+    assert_eq!(Account::from_otpauth_uri(&account.to_otpauth_uri()).unwrap(), account);
+}
+
+#[test]
+fn test_otpauth_uri_rejects_invalid() {
+    assert!(Account::from_otpauth_uri("https://example.com").is_err());
+    assert!(Account::from_otpauth_uri("otpauth://totp/alice?issuer=x").is_err());
+    assert!(Account::from_otpauth_uri("otpauth://hotp/alice?secret=JBSWY3DPEHPK3PXP").is_err());
+    assert!(Account::from_otpauth_uri("otpauth://totp/alice?secret=JBSW1").is_err());
+    assert!(Account::from_otpauth_uri("otpauth://totp/alice?secret=JBSW&digits=9").is_err());
+    // The end of the scheme falling inside a character
+    assert!(Account::from_otpauth_uri("otpauth:/é/totp/alice?secret=JBSWY3DPEHPK3PXP").is_err());
+    assert!(Account::from_otpauth_uri("aééééé").is_err());
+}
+
+#[test]
+fn test_account_code() {
+    let account = Account::from_otpauth_uri(
+        "otpauth://totp/test?secret=fhcidhyw3n46ejbiqwosp4vurtyij3w7&period=30",
+    )
+    .unwrap();
+    assert_eq!(account.code_at(1637501965), "767557");
+    assert_eq!(account.remaining_secs(1637501965), 5);
+}
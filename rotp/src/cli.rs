@@ -0,0 +1,68 @@
+use clap::{Parser, Subcommand};
+
+/// Manage an encrypted vault of OTP accounts
+#[derive(Parser, Debug)]
+#[clap(propagate_version = true)]
+#[clap(author, version, about, long_about = None)]
+pub(crate) struct Args {
+    /// Path of the vault file, defaults to `rotp/vault` in the user's config directory
+    #[clap(long, global = true)]
+    pub(crate) vault: Option<String>,
+
+    #[clap(subcommand)]
+    pub(crate) command: Commands,
+}
+
+#[derive(Subcommand, Debug)]
+#[derive(Clone)]
+pub(crate) enum Commands {
+    /// Add an account from an `otpauth://` URI
+    Add {
+        /// For example `otpauth://totp/ACME:alice?secret=JBSWY3DPEHPK3PXP&issuer=ACME`
+        uri: String,
+    },
+    /// Generate a new TOTP secret, add it and show its enrolment QR code
+    New {
+        /// Account name, usually `Issuer:user`
+        name: String,
+
+        /// Name of the service the account belongs to
+        #[clap(long)]
+        issuer: Option<String>,
+
+        /// HMAC algorithm: SHA1, SHA256 or SHA512
+        #[clap(long, default_value = "SHA1")]
+        algorithm: String,
+
+        /// Secret length in bytes, defaults to the length recommended for the algorithm
+        #[clap(long)]
+        length: Option<usize>,
+
+        /// Write an SVG here instead of drawing the code in the terminal
+        #[clap(short, long)]
+        out_path: Option<String>,
+    },
+    /// List the names of all accounts
+    List,
+    /// Print the current code of an account
+    Code {
+        /// Account name, or a prefix matching exactly one account
+        name: String,
+    },
+    /// Remove an account
+    Remove {
+        /// Account name, or a prefix matching exactly one account
+        name: String,
+    },
+    /// Show the enrolment QR code of an account
+    Qr {
+        /// Account name, or a prefix matching exactly one account
+        name: String,
+
+        /// Write an SVG here instead of drawing the code in the terminal
+        #[clap(short, long)]
+        out_path: Option<String>,
+    },
+    /// Print every account as an `otpauth://` URI, secrets included
+    Export,
+}
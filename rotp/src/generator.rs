@@ -1,19 +1,57 @@
 use hmac::{Hmac, Mac, NewMac};
+use serde::{Deserialize, Serialize};
 use sha1::Sha1;
 use sha2::{Sha256, Sha512};
 
+use std::io::{Error, ErrorKind, Result};
+use std::str::FromStr;
+
 #[allow(dead_code)]
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
 pub enum HmacShaAlgorithm {
     SHA1,
     SHA256,
     SHA512,
 }
 
+impl HmacShaAlgorithm {
+    /// The name used for this algorithm in `otpauth://` URIs
+    pub fn name(&self) -> &'static str {
+        match self {
+            HmacShaAlgorithm::SHA1 => "SHA1",
+            HmacShaAlgorithm::SHA256 => "SHA256",
+            HmacShaAlgorithm::SHA512 => "SHA512",
+        }
+    }
+
+    /// Key length in bytes recommended by RFC 6238, the output size of the hash function
+    pub fn recommended_key_len(&self) -> usize {
+        match self {
+            HmacShaAlgorithm::SHA1 => 20,
+            HmacShaAlgorithm::SHA256 => 32,
+            HmacShaAlgorithm::SHA512 => 64,
+        }
+    }
+}
+
+impl FromStr for HmacShaAlgorithm {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_ascii_uppercase().replace('-', "").as_str() {
+            "SHA1" => Ok(HmacShaAlgorithm::SHA1),
+            "SHA256" => Ok(HmacShaAlgorithm::SHA256),
+            "SHA512" => Ok(HmacShaAlgorithm::SHA512),
+            _ => Err(Error::new(ErrorKind::InvalidInput, format!("Unknown algorithm: {}", s))),
+        }
+    }
+}
+
 macro_rules! compute_digest_by_sha_type {
     ($sha_ty:ty, $key:ident, $input:ident) => {
         if let Ok(mut mac) = Hmac::<$sha_ty>::new_from_slice(&$key) {
             mac.update(&$input);
-            return mac.finalize().into_bytes().as_slice().to_vec();
+            return mac.finalize().into_bytes().to_vec();
         }
     };
 }
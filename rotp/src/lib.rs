@@ -0,0 +1,10 @@
+//! HOTP/TOTP codes, `otpauth://` accounts and secret generation
+
+pub mod account;
+pub mod generator;
+pub mod secret;
+
+pub use account::{Account, OtpKind};
+pub use generator::HmacShaAlgorithm;
+pub use secret::Secret;
+pub use zeroize::Zeroizing;
@@ -1,14 +1,139 @@
-mod generator;
-use generator::*;
-
-fn main() {
-    let secret = rb32::decode("FHCIDHYW3N46EJBIQWOSP4VURTYIJ3W7".as_bytes());
-    use std::time::{SystemTime, UNIX_EPOCH};
-    let time_millis = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis();
-    println!("time_millis >>> {}", time_millis);
-    let time = (time_millis / 1000 / 30) as u64;
-    println!("time >>> {}", time);
-    let digits = 6;
-    let otp = generator::gen_otp(&secret, time, digits, HmacShaAlgorithm::SHA1);
-    println!("otp >>> {}", otp);
+mod cli;
+mod vault;
+
+use crate::cli::{Args, Commands};
+use crate::vault::Vault;
+
+use rotp::{Account, HmacShaAlgorithm, OtpKind, Secret};
+
+use std::io::{Error, ErrorKind, Result};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use clap::Parser;
+
+/// Read by scripts instead of prompting for the passphrase
+const PASSPHRASE_ENV: &str = "ROTP_PASSPHRASE";
+
+fn read_passphrase(confirm: bool) -> Result<String> {
+    if let Ok(passphrase) = std::env::var(PASSPHRASE_ENV) {
+        return Ok(passphrase);
+    }
+    let passphrase = rpassword::prompt_password("Vault passphrase: ")?;
+    if confirm && passphrase != rpassword::prompt_password("Confirm passphrase: ")? {
+        return Err(Error::new(ErrorKind::InvalidInput, "Passphrases do not match"));
+    }
+    Ok(passphrase)
+}
+
+fn open_vault(path: &Path) -> Result<Vault> {
+    if !path.exists() {
+        return Err(Error::new(
+            ErrorKind::NotFound,
+            format!("No vault at {}, add an account first", path.display()),
+        ));
+    }
+    Vault::open(path, &read_passphrase(false)?)
+}
+
+fn add_account(vault_path: &Path, account: Account) -> Result<()> {
+    let mut vault = if vault_path.exists() {
+        open_vault(vault_path)?
+    } else {
+        Vault::create(vault_path, &read_passphrase(true)?)?
+    };
+    if vault.accounts.iter().any(|a| a.name == account.name) {
+        return Err(Error::new(
+            ErrorKind::AlreadyExists,
+            format!("Account {} already exists", account.name),
+        ));
+    }
+    println!("Added {}", account.name);
+    vault.accounts.push(account);
+    vault.save()
+}
+
+/// Render an `otpauth://` URI with genqrsvg, to an SVG file or to the terminal
+fn show_qr(uri: &str, out_path: Option<&str>) -> Result<()> {
+    let code = genqrsvg::encode(uri.as_bytes())
+        .map_err(|e| Error::new(ErrorKind::InvalidInput, e.to_string()))?;
+    match out_path {
+        Some(out_path) => {
+            genqrsvg::write_svg(&genqrsvg::render_svg(&code), Path::new(out_path))?;
+            println!("Wrote {}", out_path);
+        }
+        None => println!("{}", genqrsvg::render_unicode(&code)),
+    }
+    Ok(())
+}
+
+fn main() -> Result<()> {
+    let args = Args::parse();
+    let vault_path = args.vault.map(PathBuf::from).unwrap_or_else(Vault::default_path);
+
+    match args.command {
+        Commands::Add { uri } => {
+            let account = Account::from_otpauth_uri(&uri)?;
+            add_account(&vault_path, account)?;
+        }
+        Commands::New { name, issuer, algorithm, length, out_path } => {
+            let algorithm: HmacShaAlgorithm = algorithm.parse()?;
+            let secret = match length {
+                Some(length) => Secret::generate(length)?,
+                None => Secret::generate_for(algorithm)?,
+            };
+            let account = secret.to_account(&name, issuer.as_deref(), algorithm);
+            let uri = account.to_otpauth_uri();
+            add_account(&vault_path, account)?;
+            show_qr(&uri, out_path.as_deref())?;
+        }
+        Commands::List => {
+            let vault = open_vault(&vault_path)?;
+            for account in &vault.accounts {
+                println!(
+                    "{}\t{}\t{} {} digits",
+                    account.name,
+                    account.issuer.as_deref().unwrap_or("-"),
+                    account.kind_name(),
+                    account.digits
+                );
+            }
+        }
+        Commands::Code { name } => {
+            let mut vault = open_vault(&vault_path)?;
+            let pos = vault.position(&name)?;
+            let account = &mut vault.accounts[pos];
+            let time_secs = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+            let otp = account.code_at(time_secs);
+            match account.kind {
+                OtpKind::Totp => {
+                    println!("{} ({}s left)", otp, account.remaining_secs(time_secs))
+                }
+                OtpKind::Hotp => {
+                    println!("{} (counter {})", otp, account.counter);
+                    account.counter += 1;
+                    vault.save()?;
+                }
+            }
+        }
+        Commands::Remove { name } => {
+            let mut vault = open_vault(&vault_path)?;
+            let pos = vault.position(&name)?;
+            let account = vault.accounts.remove(pos);
+            vault.save()?;
+            println!("Removed {}", account.name);
+        }
+        Commands::Qr { name, out_path } => {
+            let vault = open_vault(&vault_path)?;
+            let account = &vault.accounts[vault.position(&name)?];
+            show_qr(&account.to_otpauth_uri(), out_path.as_deref())?;
+        }
+        Commands::Export => {
+            let vault = open_vault(&vault_path)?;
+            for account in &vault.accounts {
+                println!("{}", account.to_otpauth_uri().as_str());
+            }
+        }
+    }
+    Ok(())
 }
@@ -0,0 +1,122 @@
+use crate::account::{Account, OtpKind, DEFAULT_DIGITS, DEFAULT_PERIOD};
+use crate::generator::HmacShaAlgorithm;
+
+use std::fmt;
+use std::io::{Error, ErrorKind, Result};
+
+use zeroize::{Zeroize, Zeroizing};
+
+/// RFC 4226 requires shared secrets of at least 128 bits
+pub const MIN_SECRET_LEN: usize = 16;
+
+/// A shared secret for a new OTP account, wiped from memory when dropped
+///
+/// So are its base32 and URI encodings, and the secret of the accounts made from it once they
+/// are dropped. Copies made elsewhere, like URIs printed or drawn as QR codes, are not.
+pub struct Secret {
+    bytes: Vec<u8>,
+}
+
+impl Secret {
+    /// Generate `len` random bytes with the operating system's CSPRNG
+    ///
+    /// # Examples
+    /// ```rust
+    /// use rotp::Secret;
+    ///
+    /// let secret = Secret::generate(20).unwrap();
+    /// assert_eq!(20, secret.as_bytes().len());
+    /// assert_eq!(32, secret.to_base32().len());
+    /// assert!(Secret::generate(10).is_err());
+    /// ```
+    pub fn generate(len: usize) -> Result<Self> {
+        if len < MIN_SECRET_LEN {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("Secrets must be at least {} bytes", MIN_SECRET_LEN),
+            ));
+        }
+        let mut bytes = vec![0u8; len];
+        getrandom::getrandom(&mut bytes).map_err(Error::other)?;
+        Ok(Secret { bytes })
+    }
+
+    /// Generate a secret of the length recommended for `algorithm`
+    pub fn generate_for(algorithm: HmacShaAlgorithm) -> Result<Self> {
+        Self::generate(algorithm.recommended_key_len())
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+
+    /// Base32 without padding, as expected by authenticator apps
+    pub fn to_base32(&self) -> Zeroizing<String> {
+        let encoded = Zeroizing::new(rb32::encode(&self.bytes));
+        Zeroizing::new(String::from_utf8_lossy(&encoded).trim_end_matches('=').to_string())
+    }
+
+    /// A TOTP account with the default 6 digits and 30 seconds period
+    pub fn to_account(
+        &self,
+        name: &str,
+        issuer: Option<&str>,
+        algorithm: HmacShaAlgorithm,
+    ) -> Account {
+        Account {
+            name: name.to_string(),
+            issuer: issuer.map(|issuer| issuer.to_string()),
+            secret: String::from_utf8_lossy(&Zeroizing::new(rb32::encode(&self.bytes))).to_string(),
+            kind: OtpKind::Totp,
+            algorithm,
+            digits: DEFAULT_DIGITS,
+            period: DEFAULT_PERIOD,
+            counter: 0,
+        }
+    }
+
+    /// The enrolment URI of a TOTP account using this secret
+    ///
+    /// # Examples
+    /// ```rust
+    /// use rotp::{HmacShaAlgorithm, Secret};
+    ///
+    /// let secret = Secret::generate_for(HmacShaAlgorithm::SHA256).unwrap();
+    /// let uri = secret.to_otpauth_uri("ACME:alice", Some("ACME"), HmacShaAlgorithm::SHA256);
+    /// assert!(uri.starts_with("otpauth://totp/ACME:alice?secret="));
+    /// assert!(uri.contains("&algorithm=SHA256&"));
+    /// ```
+    pub fn to_otpauth_uri(
+        &self,
+        name: &str,
+        issuer: Option<&str>,
+        algorithm: HmacShaAlgorithm,
+    ) -> Zeroizing<String> {
+        self.to_account(name, issuer, algorithm).to_otpauth_uri()
+    }
+}
+
+impl Drop for Secret {
+    fn drop(&mut self) {
+        self.bytes.zeroize();
+    }
+}
+
+impl fmt::Debug for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Secret").field("len", &self.bytes.len()).finish()
+    }
+}
+
+#[test]
+fn test_generated_secret_round_trip() {
+    for algorithm in [HmacShaAlgorithm::SHA1, HmacShaAlgorithm::SHA256, HmacShaAlgorithm::SHA512] {
+        let secret = Secret::generate_for(algorithm).unwrap();
+        assert_eq!(secret.as_bytes().len(), algorithm.recommended_key_len());
+        let account =
+            Account::from_otpauth_uri(&secret.to_otpauth_uri("a", None, algorithm)).unwrap();
+        assert_eq!(rb32::decode(account.secret.as_bytes()), secret.as_bytes());
+        assert_eq!(account.algorithm, algorithm);
+    }
+    assert_ne!(Secret::generate(16).unwrap().as_bytes(), Secret::generate(16).unwrap().as_bytes());
+}
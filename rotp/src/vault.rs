@@ -0,0 +1,211 @@
+use rotp::Account;
+
+use std::fs::{self, OpenOptions};
+use std::io::{Error, ErrorKind, Result, Write};
+use std::path::{Path, PathBuf};
+
+use chacha20poly1305::aead::{Aead, NewAead, Payload};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use hmac::Hmac;
+use sha2::Sha256;
+use zeroize::{Zeroize, Zeroizing};
+
+const VAULT_MAGIC: &[u8; 4] = b"ROTP";
+const VAULT_VERSION: u8 = 1;
+
+const SALT_LEN: usize = 16;
+const NONCE_LEN: usize = 12;
+const KEY_LEN: usize = 32;
+/// magic + version + rounds + salt + nonce
+const HEADER_LEN: usize = VAULT_MAGIC.len() + 1 + 4 + SALT_LEN + NONCE_LEN;
+
+/// PBKDF2-HMAC-SHA256 iteration count for newly created vaults
+const DEFAULT_PBKDF2_ROUNDS: u32 = 310_000;
+/// The iteration counts a vault header may ask for, so that a tampered one can neither
+/// weaken the key nor keep the key derivation running for hours
+const PBKDF2_ROUNDS: std::ops::RangeInclusive<u32> = 1_000..=10_000_000;
+
+/// A passphrase-encrypted list of accounts
+///
+/// File layout: `ROTP | version | rounds (u32 BE) | salt | nonce | ciphertext`,
+/// where the ciphertext is the JSON account list sealed with ChaCha20-Poly1305
+/// and the header is authenticated as associated data. The key kept here, the plaintext JSON
+/// and the secrets of the accounts are wiped from memory once done with, while the copy of the
+/// key inside the cipher and the passphrase are not.
+pub(crate) struct Vault {
+    path: PathBuf,
+    rounds: u32,
+    salt: [u8; SALT_LEN],
+    key: [u8; KEY_LEN],
+    pub(crate) accounts: Vec<Account>,
+}
+
+impl Vault {
+    /// `rotp/vault` under the user's config directory
+    pub(crate) fn default_path() -> PathBuf {
+        dirs::config_dir().unwrap_or_else(|| PathBuf::from(".")).join("rotp").join("vault")
+    }
+
+    /// Create an empty vault, it is not written until [`Vault::save`]
+    pub(crate) fn create(path: &Path, passphrase: &str) -> Result<Self> {
+        Self::create_with_rounds(path, passphrase, DEFAULT_PBKDF2_ROUNDS)
+    }
+
+    fn create_with_rounds(path: &Path, passphrase: &str, rounds: u32) -> Result<Self> {
+        let mut salt = [0u8; SALT_LEN];
+        fill_random(&mut salt)?;
+        Ok(Vault {
+            path: path.to_path_buf(),
+            rounds,
+            salt,
+            key: derive_key(passphrase, &salt, rounds),
+            accounts: Vec::<Account>::new(),
+        })
+    }
+
+    pub(crate) fn open(path: &Path, passphrase: &str) -> Result<Self> {
+        let bytes = fs::read(path)?;
+        if bytes.len() < HEADER_LEN || &bytes[..VAULT_MAGIC.len()] != VAULT_MAGIC {
+            return Err(Error::new(ErrorKind::InvalidData, "Not a rotp vault"));
+        }
+        let mut offset = VAULT_MAGIC.len();
+        if bytes[offset] != VAULT_VERSION {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("Unsupported vault version: {}", bytes[offset]),
+            ));
+        }
+        offset += 1;
+        let mut rounds_bytes = [0u8; 4];
+        rounds_bytes.copy_from_slice(&bytes[offset..offset + 4]);
+        let rounds = u32::from_be_bytes(rounds_bytes);
+        if !PBKDF2_ROUNDS.contains(&rounds) {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("Unsupported PBKDF2 rounds: {}", rounds),
+            ));
+        }
+        offset += 4;
+        let mut salt = [0u8; SALT_LEN];
+        salt.copy_from_slice(&bytes[offset..offset + SALT_LEN]);
+        offset += SALT_LEN;
+        let mut nonce = [0u8; NONCE_LEN];
+        nonce.copy_from_slice(&bytes[offset..HEADER_LEN]);
+
+        let key = derive_key(passphrase, &salt, rounds);
+        let cipher = ChaCha20Poly1305::new(&Key::from(key));
+        let plaintext = Zeroizing::new(
+            cipher
+                .decrypt(
+                    &Nonce::from(nonce),
+                    Payload { msg: &bytes[HEADER_LEN..], aad: &bytes[..HEADER_LEN] },
+                )
+                .map_err(|_| {
+                    Error::new(ErrorKind::InvalidData, "Wrong passphrase or corrupted vault")
+                })?,
+        );
+        let accounts = serde_json::from_slice::<Vec<Account>>(&plaintext)
+            .map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+        Ok(Vault { path: path.to_path_buf(), rounds, salt, key, accounts })
+    }
+
+    /// Encrypt with a fresh nonce and atomically replace the vault file
+    pub(crate) fn save(&self) -> Result<()> {
+        let mut header = Vec::<u8>::with_capacity(HEADER_LEN);
+        header.extend_from_slice(VAULT_MAGIC);
+        header.push(VAULT_VERSION);
+        header.extend_from_slice(&self.rounds.to_be_bytes());
+        header.extend_from_slice(&self.salt);
+        let mut nonce = [0u8; NONCE_LEN];
+        fill_random(&mut nonce)?;
+        header.extend_from_slice(&nonce);
+
+        let plaintext = Zeroizing::new(serde_json::to_vec(&self.accounts)?);
+        let cipher = ChaCha20Poly1305::new(&Key::from(self.key));
+        let ciphertext = cipher
+            .encrypt(&Nonce::from(nonce), Payload { msg: &plaintext, aad: &header })
+            .map_err(|_| Error::other("Failed to encrypt vault"))?;
+
+        if let Some(parent) = self.path.parent() {
+            if !parent.as_os_str().is_empty() && !parent.exists() {
+                fs::create_dir_all(parent)?;
+            }
+        }
+        let tmp_path = self.path.with_extension("tmp");
+        let mut options = OpenOptions::new();
+        options.create(true).truncate(true).write(true);
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+        let mut tmp_file = options.open(&tmp_path)?;
+        tmp_file.write_all(&header)?;
+        tmp_file.write_all(&ciphertext)?;
+        tmp_file.flush()?;
+        tmp_file.sync_all()?;
+        fs::rename(&tmp_path, &self.path)?;
+        Ok(())
+    }
+
+    /// Look an account up by its exact name, or by a prefix matching only one account
+    pub(crate) fn position(&self, name: &str) -> Result<usize> {
+        if let Some(pos) = self.accounts.iter().position(|account| account.name == name) {
+            return Ok(pos);
+        }
+        let matches: Vec<_> =
+            (0..self.accounts.len()).filter(|i| self.accounts[*i].name.starts_with(name)).collect();
+        match matches.len() {
+            1 => Ok(matches[0]),
+            0 => Err(Error::new(ErrorKind::NotFound, format!("No account named {}", name))),
+            _ => Err(Error::new(ErrorKind::InvalidInput, format!("Ambiguous account: {}", name))),
+        }
+    }
+}
+
+impl Drop for Vault {
+    fn drop(&mut self) {
+        self.key.zeroize();
+    }
+}
+
+fn derive_key(passphrase: &str, salt: &[u8], rounds: u32) -> [u8; KEY_LEN] {
+    let mut key = [0u8; KEY_LEN];
+    pbkdf2::pbkdf2::<Hmac<Sha256>>(passphrase.as_bytes(), salt, rounds, &mut key);
+    key
+}
+
+fn fill_random(dest: &mut [u8]) -> Result<()> {
+    getrandom::getrandom(dest).map_err(Error::other)
+}
+
+#[test]
+fn test_vault_round_trip() {
+    let path = std::env::temp_dir().join(format!("rotp-test-{}.vault", std::process::id()));
+    let mut vault =
+        Vault::create_with_rounds(&path, "correct horse", *PBKDF2_ROUNDS.start()).unwrap();
+    vault.accounts.push(
+        Account::from_otpauth_uri("otpauth://totp/alice?secret=JBSWY3DPEHPK3PXP&issuer=ACME")
+            .unwrap(),
+    );
+    vault.save().unwrap();
+
+    let reopened = Vault::open(&path, "correct horse").unwrap();
+    assert_eq!(reopened.accounts, vault.accounts);
+    assert_eq!(reopened.position("ali").unwrap(), 0);
+    assert!(reopened.position("bob").is_err());
+    assert_eq!(
+        Vault::open(&path, "battery staple").err().map(|e| e.kind()),
+        Some(ErrorKind::InvalidData)
+    );
+
+    // Rounds out of range are refused before any key is derived
+    let bytes = fs::read(&path).unwrap();
+    let rounds_at = VAULT_MAGIC.len() + 1;
+    for rounds in [0, *PBKDF2_ROUNDS.start() - 1, *PBKDF2_ROUNDS.end() + 1, u32::MAX] {
+        let mut tampered = bytes.clone();
+        tampered[rounds_at..rounds_at + 4].copy_from_slice(&rounds.to_be_bytes());
+        fs::write(&path, tampered).unwrap();
+        let e = Vault::open(&path, "correct horse").err().unwrap();
+        assert_eq!(e.kind(), ErrorKind::InvalidData);
+        assert_eq!(e.to_string(), format!("Unsupported PBKDF2 rounds: {}", rounds));
+    }
+    fs::remove_file(&path).unwrap();
+}
@@ -13,7 +13,6 @@ use biscuit::{ClaimsSet, RegisteredClaims, JWT};
 use guid_create::GUID;
 use hyper::{Body, Client, Method, Request, Response, Version};
 use lazy_static::lazy_static;
-use qrcode::render::unicode;
 use qrcode::QrCode;
 use ruimpl::rmeol;
 use serde_json::json;
@@ -611,11 +610,7 @@ pub(crate) async fn sign_in_tp_by_scan_moa_qrcode(
     // seeval!((&tdc_info, &time_limit));
 
     let code = QrCode::new(tdc_info)?;
-    let image = code
-        .render::<unicode::Dense1x2>()
-        .dark_color(unicode::Dense1x2::Light)
-        .light_color(unicode::Dense1x2::Dark)
-        .build();
+    let image = genqrsvg::render_unicode(&code);
     clearscreen::clear()?;
     println!("Please use MOA to scan the following QR code");
     println!();